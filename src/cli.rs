@@ -0,0 +1,275 @@
+use crate::emulator::DiskMode;
+
+pub const USAGE: &str = "\
+usage: udy-cream [options] <image> [guest arguments...]
+
+Options after the image are passed to the guest.
+
+machine:
+  --machine <bare|virt|user>   machine profile (default: bare)
+                               bare: run an ELF or raw binary from the memory base
+                               virt: boot a Linux kernel Image with the built-in SBI
+                               user: run a static Linux executable in U-mode
+  --memory-size <size>         RAM size, K/M/G suffixes are allowed
+                               (default: 4M, virt: 128M, user: 256M)
+  --memory-base <address>      RAM base address for bare (default: 0x80000000)
+  --format <auto|elf|bin>      image format for bare (default: auto)
+  --load <file>@<address>      also place a raw file at a physical address
+  --entry <address>            start from this address instead of the image entry
+  --tohost <address>           HTIF tohost address (default: the tohost symbol)
+  --fromhost <address>         HTIF fromhost address (default: the fromhost symbol)
+  --sbi                        use the built-in SBI and start in S-mode
+  --pk                         handle riscv-pk system calls on the host
+  --semihosting                enable semihosting with the image and guest arguments
+  --dtb                        place a device tree at the end of RAM for bare
+  --bootargs <args>            kernel command line in the device tree
+  --initrd <file>              initrd for virt
+  --dump-dtb <file>            write the device tree to a file
+
+devices:
+  --uart <file>                write UART output to a file instead of the terminal
+  --disk <file>                attach a virtio block device
+  --disk-mode <rw|ro|cow>      ro and cow never modify the disk image (default: rw)
+
+execution:
+  --max-instructions <n>       stop after n steps
+  --trace <file>               write a spike --log-commits compatible trace
+  --reference <file>           compare each instruction with a spike --log-commits trace
+  --gdb <address>              wait for GDB on a TCP address
+  --monitor                    control the guest from an interactive monitor
+  --signature <file>           write the riscv-arch-test signature after exit
+  --signature-granularity <n>  bytes per signature line (default: 4)
+
+tools:
+  --objdump                    disassemble the image and exit
+  --fuzz <seed>                run random instruction streams and exit
+  --fuzz-programs <n>          number of random programs (default: 10000)
+  -h, --help                   print this help
+
+exit status:
+  0 if the guest passed, the low 8 bits of the guest's exit code (1 if they are 0)
+  if it failed, 124 when --max-instructions is reached, 2 on emulator errors.
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Machine {
+    Bare,
+    Virt,
+    User,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    // 先頭がELFのマジックナンバーかどうかで判定する。
+    Auto,
+    Elf,
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Run(Box<Options>),
+    Objdump(String),
+    Fuzz { seed: u64, programs: Option<u64> },
+    Help,
+}
+
+// 省略された値はmachineに応じてmain.rsで決める。
+#[derive(Debug)]
+pub struct Options {
+    pub machine: Machine,
+    pub image: String,
+    pub args: Vec<String>,
+    pub format: ImageFormat,
+    pub memory_size: Option<usize>,
+    pub memory_base: Option<u64>,
+    pub loads: Vec<(String, u64)>,
+    pub entry: Option<u64>,
+    pub tohost: Option<u64>,
+    pub fromhost: Option<u64>,
+    pub sbi: bool,
+    pub pk: bool,
+    pub semihosting: bool,
+    pub dtb: bool,
+    pub bootargs: Option<String>,
+    pub initrd: Option<String>,
+    pub dump_dtb: Option<String>,
+    pub uart: Option<String>,
+    pub disk: Option<String>,
+    pub disk_mode: DiskMode,
+    pub max_instructions: Option<u64>,
+    pub trace: Option<String>,
+    pub reference: Option<String>,
+    pub gdb: Option<String>,
+    pub monitor: bool,
+    pub signature: Option<String>,
+    pub signature_granularity: Option<usize>,
+}
+
+// 0xで始まる場合は16進数、それ以外は10進数として読む。
+fn parse_number(name: &str, value: &str) -> Result<u64, String> {
+    let number = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => value.replace('_', "").parse(),
+    };
+    number.map_err(|_| format!("{}: invalid number: {}", name, value))
+}
+
+// K/M/Gの接尾辞は1024単位にする。
+fn parse_size(name: &str, value: &str) -> Result<usize, String> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    parse_number(name, number)?
+        .checked_mul(unit)
+        .and_then(|size| usize::try_from(size).ok())
+        .filter(|size| *size != 0)
+        .ok_or_else(|| format!("{}: invalid size: {}", name, value))
+}
+
+// 引数(プログラム名を除く)を読む関数
+// --name valueと--name=valueのどちらの形式でも値を渡せる。
+// 最初のオプションでない引数をイメージとし、それ以降の引数はすべてゲストに渡す。
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut options = Options {
+        machine: Machine::Bare,
+        image: String::new(),
+        args: Vec::new(),
+        format: ImageFormat::Auto,
+        memory_size: None,
+        memory_base: None,
+        loads: Vec::new(),
+        entry: None,
+        tohost: None,
+        fromhost: None,
+        sbi: false,
+        pk: false,
+        semihosting: false,
+        dtb: false,
+        bootargs: None,
+        initrd: None,
+        dump_dtb: None,
+        uart: None,
+        disk: None,
+        disk_mode: DiskMode::ReadWrite,
+        max_instructions: None,
+        trace: None,
+        reference: None,
+        gdb: None,
+        monitor: false,
+        signature: None,
+        signature_granularity: None,
+    };
+    let mut objdump = false;
+    let mut fuzz = None;
+    let mut fuzz_programs = None;
+    let mut image = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (name, mut inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline
+                .take()
+                .or_else(|| args.next().cloned())
+                .ok_or_else(|| format!("{} requires a value", name))
+        };
+
+        match name {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--machine" => {
+                options.machine = match value()?.as_str() {
+                    "bare" => Machine::Bare,
+                    "virt" => Machine::Virt,
+                    "user" => Machine::User,
+                    machine => return Err(format!("--machine: unknown machine: {}", machine)),
+                }
+            }
+            "--memory-size" => options.memory_size = Some(parse_size(name, &value()?)?),
+            "--memory-base" => options.memory_base = Some(parse_number(name, &value()?)?),
+            "--format" => {
+                options.format = match value()?.as_str() {
+                    "auto" => ImageFormat::Auto,
+                    "elf" => ImageFormat::Elf,
+                    "bin" => ImageFormat::Binary,
+                    format => return Err(format!("--format: unknown format: {}", format)),
+                }
+            }
+            "--load" => {
+                let value = value()?;
+                let (file, address) = value
+                    .rsplit_once('@')
+                    .ok_or_else(|| format!("--load: expected <file>@<address>: {}", value))?;
+                options
+                    .loads
+                    .push((file.to_string(), parse_number(name, address)?));
+            }
+            "--entry" => options.entry = Some(parse_number(name, &value()?)?),
+            "--tohost" => options.tohost = Some(parse_number(name, &value()?)?),
+            "--fromhost" => options.fromhost = Some(parse_number(name, &value()?)?),
+            "--sbi" => options.sbi = true,
+            "--pk" => options.pk = true,
+            "--semihosting" => options.semihosting = true,
+            "--dtb" => options.dtb = true,
+            "--bootargs" => options.bootargs = Some(value()?),
+            "--initrd" => options.initrd = Some(value()?),
+            "--dump-dtb" => options.dump_dtb = Some(value()?),
+            "--uart" => options.uart = Some(value()?),
+            "--disk" => options.disk = Some(value()?),
+            "--disk-mode" => {
+                options.disk_mode = match value()?.as_str() {
+                    "rw" => DiskMode::ReadWrite,
+                    "ro" => DiskMode::ReadOnly,
+                    "cow" => DiskMode::CopyOnWrite,
+                    mode => return Err(format!("--disk-mode: unknown mode: {}", mode)),
+                }
+            }
+            "--max-instructions" => options.max_instructions = Some(parse_number(name, &value()?)?),
+            "--trace" => options.trace = Some(value()?),
+            "--reference" => options.reference = Some(value()?),
+            "--gdb" => options.gdb = Some(value()?),
+            "--monitor" => options.monitor = true,
+            "--signature" => options.signature = Some(value()?),
+            "--signature-granularity" => {
+                options.signature_granularity = Some(parse_size(name, &value()?)?)
+            }
+            "--objdump" => objdump = true,
+            "--fuzz" => fuzz = Some(parse_number(name, &value()?)?),
+            "--fuzz-programs" => fuzz_programs = Some(parse_number(name, &value()?)?),
+            "--" => {
+                image = args.next().cloned();
+                break;
+            }
+            _ if name.starts_with('-') && name != "-" => {
+                return Err(format!("unknown option: {}", name));
+            }
+            _ => {
+                image = Some(arg.clone());
+                break;
+            }
+        }
+        // 値を取らないオプションに=で値を付けた場合
+        if inline.is_some() {
+            return Err(format!("{} does not take a value", name));
+        }
+    }
+    options.args = args.cloned().collect();
+
+    if let Some(seed) = fuzz {
+        return Ok(Command::Fuzz {
+            seed,
+            programs: fuzz_programs,
+        });
+    }
+    options.image = image.ok_or("an image is required")?;
+    if objdump {
+        return Ok(Command::Objdump(options.image));
+    }
+    Ok(Command::Run(Box::new(options)))
+}
@@ -0,0 +1,163 @@
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::{
+        cli::{parse_args, Command, ImageFormat, Machine, Options},
+        emulator::DiskMode,
+    };
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse_args(&args)
+    }
+
+    fn parse_options(args: &[&str]) -> Options {
+        match parse(args) {
+            Ok(Command::Run(options)) => *options,
+            command => panic!("unexpected command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn cli_defaults() {
+        let options = parse_options(&["test.elf"]);
+        assert_eq!(options.machine, Machine::Bare);
+        assert_eq!(options.image, "test.elf");
+        assert!(options.args.is_empty());
+        assert_eq!(options.format, ImageFormat::Auto);
+        assert_eq!(options.memory_size, None);
+        assert_eq!(options.memory_base, None);
+        assert_eq!(options.disk_mode, DiskMode::ReadWrite);
+        assert_eq!(options.max_instructions, None);
+        assert!(!options.sbi && !options.monitor);
+    }
+
+    #[test]
+    fn cli_options() {
+        let options = parse_options(&[
+            "--machine",
+            "bare",
+            "--memory-size=16M",
+            "--memory-base",
+            "0",
+            "--format",
+            "bin",
+            "--load",
+            "data.bin@0x8010_0000",
+            "--entry=0x100",
+            "--tohost",
+            "0x3000",
+            "--max-instructions",
+            "1000000",
+            "--trace",
+            "trace.log",
+            "--disk",
+            "rootfs.img",
+            "--disk-mode",
+            "cow",
+            "--sbi",
+            "--signature-granularity",
+            "8",
+            "rvc.bin",
+        ]);
+        assert_eq!(options.memory_size, Some(16 * 1024 * 1024));
+        assert_eq!(options.memory_base, Some(0));
+        assert_eq!(options.format, ImageFormat::Binary);
+        assert_eq!(options.loads, vec![("data.bin".to_string(), 0x8010_0000)]);
+        assert_eq!(options.entry, Some(0x100));
+        assert_eq!(options.tohost, Some(0x3000));
+        assert_eq!(options.max_instructions, Some(1000000));
+        assert_eq!(options.trace.as_deref(), Some("trace.log"));
+        assert_eq!(options.disk.as_deref(), Some("rootfs.img"));
+        assert_eq!(options.disk_mode, DiskMode::CopyOnWrite);
+        assert!(options.sbi);
+        assert_eq!(options.signature_granularity, Some(8));
+        assert_eq!(options.image, "rvc.bin");
+    }
+
+    #[test]
+    fn cli_guest_arguments() {
+        // イメージより後ろはオプションの形でもゲストに渡す。
+        let options = parse_options(&["--machine", "user", "ls", "-l", "--help"]);
+        assert_eq!(options.machine, Machine::User);
+        assert_eq!(options.image, "ls");
+        assert_eq!(options.args, ["-l", "--help"]);
+
+        let options = parse_options(&["--", "-image"]);
+        assert_eq!(options.image, "-image");
+    }
+
+    #[test]
+    fn cli_tools() {
+        assert!(matches!(parse(&["-h"]), Ok(Command::Help)));
+        assert!(matches!(
+            parse(&["--objdump", "a.out"]),
+            Ok(Command::Objdump(filename)) if filename == "a.out"
+        ));
+        assert!(matches!(
+            parse(&["--fuzz", "7", "--fuzz-programs", "100"]),
+            Ok(Command::Fuzz {
+                seed: 7,
+                programs: Some(100)
+            })
+        ));
+    }
+
+    #[test]
+    fn cli_errors() {
+        for (args, message) in [
+            (&[][..], "an image is required"),
+            (&["--unknown", "a"][..], "unknown option: --unknown"),
+            (
+                &["--machine", "spike", "a"][..],
+                "--machine: unknown machine: spike",
+            ),
+            (&["a", "--trace"][..], ""),
+            (&["--trace"][..], "--trace requires a value"),
+            (
+                &["--memory-size", "4X", "a"][..],
+                "--memory-size: invalid number: 4X",
+            ),
+            (
+                &["--memory-size", "0", "a"][..],
+                "--memory-size: invalid size: 0",
+            ),
+            (
+                &["--load", "a.bin", "a"][..],
+                "--load: expected <file>@<address>: a.bin",
+            ),
+            (&["--sbi=1", "a"][..], "--sbi does not take a value"),
+        ] {
+            match parse(args) {
+                Err(error) => assert_eq!(error, message),
+                // イメージの後ろはゲストの引数になる。
+                Ok(Command::Run(options)) => {
+                    assert!(message.is_empty());
+                    assert_eq!(options.args, ["--trace"]);
+                }
+                Ok(command) => panic!("unexpected command: {:?}", command),
+            }
+        }
+    }
+
+    #[test]
+    fn cli_memory_out_of_range() {
+        let image = std::env::temp_dir().join("udy-cream-cli.bin");
+        std::fs::write(&image, 0x0000006fu32.to_le_bytes()).unwrap();
+        let image = image.to_str().unwrap();
+
+        // RAMの末尾がアドレス空間を超える場合とデバイスツリーが収まらない場合
+        for args in [
+            &["--memory-base", "0xffffffffffff0000", image][..],
+            &["--memory-base", "0", "--memory-size", "4K", "--dtb", image][..],
+        ] {
+            let options = Options {
+                format: ImageFormat::Binary,
+                ..parse_options(args)
+            };
+            let err = crate::load_bare(&options).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
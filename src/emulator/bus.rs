@@ -0,0 +1,262 @@
+use std::any::Any;
+
+use super::{fdt::FdtWriter, plic::Plic};
+
+// デバイスが存在しないアドレスや対応していない大きさでアクセスした場合に返す。
+// 呼び出し側でアクセスの種類に応じたアクセスフォールトに変換する。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessFault;
+
+// バスに接続するデバイス
+// offsetはデバイスの先頭からのオフセット、sizeは1/2/4/8バイトのいずれか。
+pub trait Device {
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, AccessFault>;
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), AccessFault>;
+
+    // 1命令実行するごとに呼ばれる。
+    fn tick(&mut self) {}
+
+    // tickの後に呼ばれ、他のデバイス(主にRAM)を読み書きできる。
+    fn dma(&mut self, _bus: &mut BusView) {}
+
+    // デバイスが立てているmipのビット
+    fn pending_interrupts(&self) -> u64 {
+        0
+    }
+
+    // PLICにつながる割り込み線の状態
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // デバイスツリーのノード名(@以降は除く)
+    // Noneを返すデバイスはデバイスツリーに載せない。
+    fn fdt_name(&self) -> Option<&'static str> {
+        None
+    }
+
+    // reg/interrupts以外のプロパティを書き込む。
+    fn fdt_properties(&self, _fdt: &mut FdtWriter) {}
+
+    // RAMのようにバイト列として直接読み書きできる場合はその領域を返す。
+    fn as_bytes_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+pub struct Ram {
+    data: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        Ram {
+            data: vec![0; size],
+        }
+    }
+}
+
+impl Device for Ram {
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, AccessFault> {
+        let offset = offset as usize;
+        let bytes = self.data.get(offset..offset + size).ok_or(AccessFault)?;
+        let mut value = [0; 8];
+        value[..size].copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), AccessFault> {
+        let offset = offset as usize;
+        self.data
+            .get_mut(offset..offset + size)
+            .ok_or(AccessFault)?
+            .copy_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+
+    fn as_bytes_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.data)
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("memory")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("device_type", "memory");
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+struct Region {
+    base: u64,
+    size: u64,
+    // PLICの割り込み源の番号
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+impl Region {
+    fn contains(&self, address: u64, size: usize) -> bool {
+        address >= self.base
+            && address
+                .checked_add(size as u64)
+                .is_some_and(|end| end <= self.base + self.size)
+    }
+}
+
+// 物理アドレス空間
+// 領域は重ならないように登録する。
+pub struct Bus {
+    regions: Vec<Region>,
+    // tickのたびに確保しないように使い回す。
+    levels: Vec<(usize, bool)>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus {
+            regions: Vec::new(),
+            levels: Vec::new(),
+        }
+    }
+
+    pub fn add_device(&mut self, base: u64, size: u64, irq: Option<u32>, device: Box<dyn Device>) {
+        self.regions.push(Region {
+            base,
+            size,
+            irq,
+            device,
+        });
+    }
+
+    fn view(&mut self) -> BusView<'_> {
+        BusView {
+            before: &mut self.regions,
+            after: &mut [],
+        }
+    }
+
+    // address..address + sizeが1つのデバイスに収まっているか判定する関数
+    pub fn is_mapped(&self, address: u64, size: usize) -> bool {
+        self.regions
+            .iter()
+            .any(|region| region.contains(address, size))
+    }
+
+    pub fn read(&mut self, address: u64, size: usize) -> Result<u64, AccessFault> {
+        self.view().read(address, size)
+    }
+
+    pub fn write(&mut self, address: u64, size: usize, value: u64) -> Result<(), AccessFault> {
+        self.view().write(address, size, value)
+    }
+
+    // プログラムの読み込みのためにバイト列をまとめて書き込む関数
+    pub fn write_bytes(&mut self, address: u64, data: &[u8]) -> Result<(), AccessFault> {
+        self.view().write_bytes(address, data)
+    }
+
+    pub fn read_bytes(&mut self, address: u64, size: usize) -> Result<Vec<u8>, AccessFault> {
+        self.view().read_bytes(address, size)
+    }
+
+    // 各デバイスを進めた後、割り込み線の状態をPLICに伝える。
+    pub fn tick(&mut self) {
+        let mut levels = std::mem::take(&mut self.levels);
+        levels.clear();
+        for i in 0..self.regions.len() {
+            let (before, rest) = self.regions.split_at_mut(i);
+            let (region, after) = rest.split_first_mut().unwrap();
+            region.device.tick();
+            region.device.dma(&mut BusView { before, after });
+            if let Some(irq) = region.irq {
+                levels.push((irq as usize, region.device.irq()));
+            }
+        }
+
+        if let Some(plic) = self.find_device_mut::<Plic>() {
+            for (source, level) in levels.iter() {
+                plic.set_level(*source, *level);
+            }
+        }
+        self.levels = levels;
+    }
+
+    pub fn pending_interrupts(&self) -> u64 {
+        self.regions
+            .iter()
+            .fold(0, |mip, region| mip | region.device.pending_interrupts())
+    }
+
+    // 登録されているデバイスを(base, size, irq, device)として順に返す。
+    pub fn devices(&self) -> impl Iterator<Item = (u64, u64, Option<u32>, &dyn Device)> {
+        self.regions
+            .iter()
+            .map(|region| (region.base, region.size, region.irq, region.device.as_ref()))
+    }
+
+    // 型を指定してデバイスを探す関数
+    pub fn find_device<T: Device + 'static>(&self) -> Option<&T> {
+        self.regions
+            .iter()
+            .find_map(|region| region.device.as_any().downcast_ref::<T>())
+    }
+
+    pub fn find_device_mut<T: Device + 'static>(&mut self) -> Option<&mut T> {
+        self.regions
+            .iter_mut()
+            .find_map(|region| region.device.as_any_mut().downcast_mut::<T>())
+    }
+}
+
+// DMAを行うデバイスから見たバス
+// 自分自身を除いたデバイスにアクセスできる。
+pub struct BusView<'a> {
+    before: &'a mut [Region],
+    after: &'a mut [Region],
+}
+
+impl BusView<'_> {
+    fn region_mut(&mut self, address: u64, size: usize) -> Option<&mut Region> {
+        self.before
+            .iter_mut()
+            .chain(self.after.iter_mut())
+            .find(|region| region.contains(address, size))
+    }
+
+    pub fn read(&mut self, address: u64, size: usize) -> Result<u64, AccessFault> {
+        let region = self.region_mut(address, size).ok_or(AccessFault)?;
+        region.device.read(address - region.base, size)
+    }
+
+    pub fn write(&mut self, address: u64, size: usize, value: u64) -> Result<(), AccessFault> {
+        let region = self.region_mut(address, size).ok_or(AccessFault)?;
+        region.device.write(address - region.base, size, value)
+    }
+
+    pub fn read_bytes(&mut self, address: u64, size: usize) -> Result<Vec<u8>, AccessFault> {
+        let region = self.region_mut(address, size).ok_or(AccessFault)?;
+        let offset = (address - region.base) as usize;
+        let bytes = region.device.as_bytes_mut().ok_or(AccessFault)?;
+        Ok(bytes[offset..offset + size].to_vec())
+    }
+
+    pub fn write_bytes(&mut self, address: u64, data: &[u8]) -> Result<(), AccessFault> {
+        let region = self.region_mut(address, data.len()).ok_or(AccessFault)?;
+        let offset = (address - region.base) as usize;
+        let bytes = region.device.as_bytes_mut().ok_or(AccessFault)?;
+        bytes[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
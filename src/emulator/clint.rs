@@ -0,0 +1,124 @@
+use std::any::Any;
+
+use super::{
+    bus::{AccessFault, Device},
+    fdt::{FdtWriter, CPU_INTC_PHANDLE},
+};
+
+pub const CLINT_BASE: u64 = 0x0200_0000;
+pub const CLINT_SIZE: u64 = 0x1_0000;
+// mtimeは1命令ごとに1進むので、1秒あたりの命令数として扱われる。
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+// Core Local INTerruptor
+// ハートは1つだけなのでmsipとmtimecmpも1つずつ持つ。
+// mtimeは1命令ごとに1進む。
+pub struct Clint {
+    msip: u64,
+    mtimecmp: u64,
+    mtime: u64,
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Clint {
+            msip: 0,
+            // リセット直後にタイマ割り込みが起こらないようにする。
+            mtimecmp: u64::MAX,
+            mtime: 0,
+        }
+    }
+
+    // レジスタの先頭からのオフセットを返す。
+    fn register(offset: u64) -> Option<(u64, u64)> {
+        match offset {
+            MSIP..=0x3 => Some((MSIP, offset - MSIP)),
+            MTIMECMP..=0x4007 => Some((MTIMECMP, offset - MTIMECMP)),
+            MTIME..=0xbfff => Some((MTIME, offset - MTIME)),
+            _ => None,
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+}
+
+impl Device for Clint {
+    // 存在しないレジスタを読んだ場合は0を返す。
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, AccessFault> {
+        let value = match Self::register(offset) {
+            Some((MSIP, shift)) => self.msip >> (8 * shift),
+            Some((MTIMECMP, shift)) => self.mtimecmp >> (8 * shift),
+            Some((_, shift)) => self.mtime >> (8 * shift),
+            None => 0,
+        };
+
+        match size {
+            8 => Ok(value),
+            size => Ok(value & ((1 << (8 * size)) - 1)),
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), AccessFault> {
+        let (register, shift) = match Self::register(offset) {
+            Some(register) => register,
+            None => return Ok(()),
+        };
+        let mask = match size {
+            8 => u64::MAX,
+            size => (1 << (8 * size)) - 1,
+        } << (8 * shift);
+        let value = (value << (8 * shift)) & mask;
+
+        match register {
+            MSIP => self.msip = ((self.msip & !mask) | value) & 0x1,
+            MTIMECMP => self.mtimecmp = (self.mtimecmp & !mask) | value,
+            _ => self.mtime = (self.mtime & !mask) | value,
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.mtime = self.mtime.wrapping_add(1);
+    }
+
+    // MSIPとMTIP
+    fn pending_interrupts(&self) -> u64 {
+        let mut mip = 0;
+        if self.msip & 1 == 1 {
+            mip |= 0x8;
+        }
+        if self.mtime >= self.mtimecmp {
+            mip |= 0x80;
+        }
+
+        mip
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("clint")
+    }
+
+    // MSIP(3)とMTIP(7)
+    fn fdt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_cells(
+            "interrupts-extended",
+            &[CPU_INTC_PHANDLE, 3, CPU_INTC_PHANDLE, 7],
+        );
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
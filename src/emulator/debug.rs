@@ -0,0 +1,240 @@
+use std::collections::BTreeSet;
+
+use super::{mmu::AccessType, ExitReason, MachineMode, Rv64SGEmulator};
+
+// デバッガから終了させた場合の終了コード
+pub const SIGKILL: u64 = 9;
+
+// レジスタのABI名
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+pub const FPR_ABI_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+// デバッガから名前で指定できるCSR
+pub const CSR_NAMES: [(&str, usize); 31] = [
+    ("fflags", 0x001),
+    ("frm", 0x002),
+    ("fcsr", 0x003),
+    ("sstatus", 0x100),
+    ("sie", 0x104),
+    ("stvec", 0x105),
+    ("scounteren", 0x106),
+    ("sscratch", 0x140),
+    ("sepc", 0x141),
+    ("scause", 0x142),
+    ("stval", 0x143),
+    ("sip", 0x144),
+    ("satp", 0x180),
+    ("mstatus", 0x300),
+    ("misa", 0x301),
+    ("medeleg", 0x302),
+    ("mideleg", 0x303),
+    ("mie", 0x304),
+    ("mtvec", 0x305),
+    ("mcounteren", 0x306),
+    ("mscratch", 0x340),
+    ("mepc", 0x341),
+    ("mcause", 0x342),
+    ("mtval", 0x343),
+    ("mip", 0x344),
+    ("cycle", 0xc00),
+    ("time", 0xc01),
+    ("mvendorid", 0xf11),
+    ("marchid", 0xf12),
+    ("mimpid", 0xf13),
+    ("mhartid", 0xf14),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+struct Watchpoint {
+    address: u64,
+    length: u64,
+    kind: WatchKind,
+}
+
+// ブレークポイントとウォッチポイント
+// ウォッチポイントはロード・ストアのたびに確認し、当たったアクセスを記録しておく。
+pub struct Debugger {
+    breakpoints: BTreeSet<u64>,
+    watchpoints: Vec<Watchpoint>,
+    hit: Option<(WatchKind, u64)>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            hit: None,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: u64) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u64) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u64> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, address: u64, length: u64, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint {
+            address,
+            length,
+            kind,
+        });
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u64, length: u64, kind: WatchKind) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| {
+            (watchpoint.address, watchpoint.length, watchpoint.kind) != (address, length, kind)
+        });
+        self.watchpoints.len() != before
+    }
+
+    // アクセスした範囲がウォッチポイントと重なっていれば記録する。
+    fn check_access(&mut self, address: u64, size: u64, write: bool) {
+        let hit = self.watchpoints.iter().find(|watchpoint| {
+            let kind_matches = match watchpoint.kind {
+                WatchKind::Write => write,
+                WatchKind::Read => !write,
+                WatchKind::Access => true,
+            };
+            kind_matches
+                && address < watchpoint.address.wrapping_add(watchpoint.length)
+                && watchpoint.address < address.wrapping_add(size)
+        });
+
+        if let Some(watchpoint) = hit {
+            self.hit
+                .get_or_insert((watchpoint.kind, watchpoint.address));
+        }
+    }
+}
+
+// デバッガが実行を止めた理由
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    Step,
+    Breakpoint,
+    Watchpoint(WatchKind, u64),
+    Interrupted,
+    Exited(ExitReason),
+}
+
+impl Rv64SGEmulator {
+    // ロード・ストアのたびに呼ばれ、ウォッチポイントに当たったか確認する。
+    pub(super) fn check_watchpoints(&mut self, vaddr: u64, size: usize, access: AccessType) {
+        if self.debugger.watchpoints.is_empty() || access == AccessType::Instruction {
+            return;
+        }
+        self.debugger
+            .check_access(vaddr, size as u64, access == AccessType::Store);
+    }
+
+    // 1命令実行し、終了したかウォッチポイントに当たった場合はその理由を返す。
+    pub(super) fn debug_step(&mut self) -> Option<StopReason> {
+        if let Some(reason) = self.step() {
+            return Some(StopReason::Exited(reason));
+        }
+
+        self.debugger
+            .hit
+            .take()
+            .map(|(kind, address)| StopReason::Watchpoint(kind, address))
+    }
+
+    // 止まる理由があるまで実行する関数
+    // 最初の命令はブレークポイントがあっても実行し、その後はブレークポイントのpcに来たら止まる。
+    // interruptedは実行中に定期的に呼ばれ、trueを返すと中断する。
+    pub(super) fn debug_continue(&mut self, mut interrupted: impl FnMut() -> bool) -> StopReason {
+        for count in 0u64.. {
+            if let Some(stop) = self.debug_step() {
+                return stop;
+            }
+            if self.debugger.breakpoints.contains(&self.pc) {
+                return StopReason::Breakpoint;
+            }
+            if count % 0x1000 == 0 && interrupted() {
+                return StopReason::Interrupted;
+            }
+        }
+
+        unreachable!()
+    }
+
+    // 今のモードに関係なくM-modeとしてCSRを読み書きする。
+    pub(super) fn debug_read_csr(&mut self, csr: usize) -> Option<u64> {
+        let mode = std::mem::replace(&mut self.mode, MachineMode::M);
+        let value = self.read_csr(csr).ok();
+        self.mode = mode;
+        value
+    }
+
+    pub(super) fn debug_write_csr(&mut self, csr: usize, value: u64) -> Option<()> {
+        let mode = std::mem::replace(&mut self.mode, MachineMode::M);
+        let result = self.write_csr(csr, value).ok();
+        self.mode = mode;
+        result
+    }
+
+    // 仮想アドレスを今のモードで変換し、ウォッチポイントを確認せずに読み書きする。
+    // 読み出しで状態が変わるデバイスがあるので、RAM以外にはアクセスしない。
+    pub(super) fn debug_read_memory(&mut self, vaddr: u64, size: u64) -> Option<Vec<u8>> {
+        self.debug_read(vaddr, size, AccessType::Load)
+    }
+
+    // 逆アセンブル用に命令フェッチとして変換して読み出す。実行のみのページも読める。
+    pub(super) fn debug_fetch_memory(&mut self, vaddr: u64, size: u64) -> Option<Vec<u8>> {
+        self.debug_read(vaddr, size, AccessType::Instruction)
+    }
+
+    fn debug_read(&mut self, vaddr: u64, size: u64, access: AccessType) -> Option<Vec<u8>> {
+        (0..size)
+            .map(|i| {
+                let paddr = self.debug_translate(vaddr.wrapping_add(i), access)?;
+                self.debug_load_physical_memory(paddr, 1)
+                    .map(|byte| byte as u8)
+            })
+            .collect()
+    }
+
+    pub(super) fn debug_write_memory(&mut self, vaddr: u64, data: &[u8]) -> Option<()> {
+        for (i, byte) in data.iter().enumerate() {
+            let paddr = self.debug_translate(vaddr.wrapping_add(i as u64), AccessType::Store)?;
+            self.bus.write_bytes(paddr, &[*byte]).ok()?;
+        }
+
+        Some(())
+    }
+
+    // RAMの物理アドレスからsizeバイト読み出す関数
+    // デバイスのレジスタの場合はNoneを返す。
+    pub(super) fn debug_load_physical_memory(&mut self, paddr: u64, size: usize) -> Option<u64> {
+        let bytes = self.bus.read_bytes(paddr, size).ok()?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, byte| (value << 8) | *byte as u64),
+        )
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, Write},
+};
+
+use super::{
+    disasm::disassemble,
+    trace::{Commit, Register, Trace},
+    ExitReason, Rv64SGEmulator,
+};
+
+// 乖離したときに表示する直前の命令の数
+const HISTORY_SIZE: usize = 8;
+
+// 参照トレースと比較した結果
+#[derive(Debug, PartialEq)]
+pub enum DiffOutcome {
+    // 参照トレースの最後まで一致した場合は比較した命令数
+    Matched(u64),
+    // 参照トレースの途中でゲストが終了した場合
+    Exited(ExitReason, u64),
+    // n番目の命令で乖離した場合
+    Diverged(u64),
+}
+
+impl Rv64SGEmulator {
+    // 参照トレースと1命令ずつ比較しながら実行する関数
+    // 参照トレースはspikeの--log-commitsの形式(Commit::parseを参照)で、読めない行は無視する。
+    // spikeのブートROMのように開始時のpcより前の命令は、pcが一致するまで読み飛ばす。
+    // 例外と割り込みは命令の完了として扱わず、次に完了した命令を比較する。
+    // 最初に乖離したところで両方の結果と直前の命令、レジスタをoutputに表示して止まる。
+    pub fn exec_with_reference(
+        &mut self,
+        reference: &mut dyn BufRead,
+        output: &mut dyn Write,
+    ) -> io::Result<DiffOutcome> {
+        self.initialize_csrs();
+        if self.trace.is_none() {
+            self.trace = Some(Trace::new(None));
+        }
+
+        let mut commits = reference
+            .lines()
+            .filter_map(|line| line.map(|line| Commit::parse(&line)).transpose());
+        let mut expected = None;
+        for commit in commits.by_ref() {
+            let commit = commit?;
+            if commit.pc == self.pc {
+                expected = Some(commit);
+                break;
+            }
+        }
+
+        let mut history = VecDeque::new();
+        let mut count = 0;
+        while let Some(reference) = expected {
+            let (commit, exit) = loop {
+                let exit = self.step();
+                match (self.take_commit(), exit) {
+                    (Some(commit), exit) => break (commit, exit),
+                    (None, Some(reason)) => return Ok(DiffOutcome::Exited(reason, count)),
+                    (None, None) => {}
+                }
+            };
+            count += 1;
+
+            if let Some(difference) = self.compare_commit(&reference, &commit) {
+                writeln!(
+                    output,
+                    "divergence at instruction {}: {}",
+                    count, difference
+                )?;
+                writeln!(output, "reference: {}", reference)?;
+                writeln!(output, "emulator:  {}", commit)?;
+                writeln!(output, "recent instructions:")?;
+                for commit in history.iter().chain([&commit]) {
+                    let Commit {
+                        pc, instruction, ..
+                    } = commit;
+                    writeln!(
+                        output,
+                        "  {:#018x}: {:08x}  {}",
+                        pc,
+                        instruction,
+                        disassemble(*instruction, *pc)
+                    )?;
+                }
+                self.print_registers(output)?;
+                return Ok(DiffOutcome::Diverged(count));
+            }
+
+            if let Some(reason) = exit {
+                return Ok(DiffOutcome::Exited(reason, count));
+            }
+            history.push_back(commit);
+            if history.len() > HISTORY_SIZE {
+                history.pop_front();
+            }
+            expected = commits.next().transpose()?;
+        }
+
+        Ok(DiffOutcome::Matched(count))
+    }
+
+    // 参照トレースと異なる点があればその説明を返す。
+    // CSRは参照トレースに書き込みがあったものだけを今の値と比べる。
+    fn compare_commit(&mut self, reference: &Commit, commit: &Commit) -> Option<String> {
+        if commit.pc != reference.pc {
+            return Some(format!(
+                "pc is {:#x} but reference has {:#x}",
+                commit.pc, reference.pc
+            ));
+        }
+        if commit.instruction != reference.instruction {
+            return Some(format!(
+                "instruction is {:#x} but reference has {:#x}",
+                commit.instruction, reference.instruction
+            ));
+        }
+        if commit.mode != reference.mode {
+            return Some(format!(
+                "privilege mode is {} but reference has {}",
+                commit.mode, reference.mode
+            ));
+        }
+
+        let find = |writes: &[(Register, u64)], register: Register| {
+            writes
+                .iter()
+                .find(|(written, _)| *written == register)
+                .map(|(_, value)| *value)
+        };
+        for (register, expected) in reference.writes.iter() {
+            let actual = match register {
+                Register::Csr(csr) => match self.debug_read_csr(*csr) {
+                    Some(value) => value,
+                    // 実装していないCSRは比べない。
+                    None => continue,
+                },
+                register => match find(&commit.writes, *register) {
+                    Some(value) => value,
+                    None => {
+                        return Some(format!(
+                            "{} is not written but reference has {:#x}",
+                            register, expected
+                        ))
+                    }
+                },
+            };
+            if actual != *expected {
+                return Some(format!(
+                    "{} is {:#x} but reference has {:#x}",
+                    register, actual, expected
+                ));
+            }
+        }
+        for (register, value) in commit.writes.iter() {
+            if !matches!(register, Register::Csr(_)) && find(&reference.writes, *register).is_none()
+            {
+                return Some(format!(
+                    "{} is written with {:#x} but reference has no write",
+                    register, value
+                ));
+            }
+        }
+
+        if commit.stores != reference.stores {
+            return Some(format!(
+                "stores are {:x?} but reference has {:x?}",
+                commit.stores, reference.stores
+            ));
+        }
+
+        None
+    }
+}
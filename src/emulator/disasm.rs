@@ -0,0 +1,714 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Write},
+};
+
+use super::{
+    debug::{ABI_NAMES, CSR_NAMES, FPR_ABI_NAMES},
+    elf::{ElfFile, PF_X},
+};
+
+// 丸めモードの名前。5と6は予約されている。
+const ROUNDING_MODES: [Option<&str>; 8] = [
+    Some("rne"),
+    Some("rtz"),
+    Some("rdn"),
+    Some("rup"),
+    Some("rmm"),
+    None,
+    None,
+    Some("dyn"),
+];
+
+fn bits(instruction: u32, high: u32, low: u32) -> u32 {
+    (instruction >> low) & (u32::MAX >> (31 - (high - low)))
+}
+
+fn bit(instruction: u32, n: u32) -> u32 {
+    bits(instruction, n, n)
+}
+
+fn sign_extend(value: u32, width: u32) -> i64 {
+    ((value as i64) << (64 - width)) >> (64 - width)
+}
+
+fn x(n: u32) -> &'static str {
+    ABI_NAMES[n as usize]
+}
+
+fn f(n: u32) -> &'static str {
+    FPR_ABI_NAMES[n as usize]
+}
+
+fn csr_name(csr: u32) -> String {
+    match CSR_NAMES.iter().find(|(_, number)| *number == csr as usize) {
+        Some((name, _)) => name.to_string(),
+        None => format!("{:#x}", csr),
+    }
+}
+
+// 命令名を8文字に揃えてからオペランドを続ける。
+fn asm(mnemonic: &str, operands: String) -> String {
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{:<7} {}", mnemonic, operands)
+    }
+}
+
+// 命令の長さ。下位2ビットが11でなければC拡張の16ビット命令になる。
+pub fn instruction_length(instruction: u32) -> u64 {
+    if instruction & 0x3 == 0x3 {
+        4
+    } else {
+        2
+    }
+}
+
+// addressに置かれた命令を逆アセンブルする関数
+// C拡張の命令は対応する32ビットの命令に展開してから表示する。
+// 分岐先はaddressを足した絶対アドレスで表示し、デコードできない命令はデータとして表示する。
+pub fn disassemble(instruction: u32, address: u64) -> String {
+    if instruction_length(instruction) == 2 {
+        let instruction = instruction as u16;
+        return expand_compressed(instruction)
+            .and_then(|expanded| disassemble_32bit(expanded, address))
+            .unwrap_or_else(|| format!(".2byte {:#06x}", instruction));
+    }
+
+    disassemble_32bit(instruction, address)
+        .unwrap_or_else(|| format!(".4byte {:#010x}", instruction))
+}
+
+// 逆アセンブラがデコードできる命令かどうか
+pub(super) fn is_decodable(instruction: u32) -> bool {
+    if instruction_length(instruction) == 2 {
+        return expand_compressed(instruction as u16)
+            .and_then(|expanded| disassemble_32bit(expanded, 0))
+            .is_some();
+    }
+
+    disassemble_32bit(instruction, 0).is_some()
+}
+
+fn disassemble_32bit(instruction: u32, address: u64) -> Option<String> {
+    let rd = bits(instruction, 11, 7);
+    let rs1 = bits(instruction, 19, 15);
+    let rs2 = bits(instruction, 24, 20);
+    let rs3 = bits(instruction, 31, 27);
+    let funct3 = bits(instruction, 14, 12);
+    let funct7 = bits(instruction, 31, 25);
+
+    let i_imm = sign_extend(bits(instruction, 31, 20), 12);
+    let s_imm = sign_extend((funct7 << 5) | rd, 12);
+    let b_imm = sign_extend(
+        (bit(instruction, 31) << 12)
+            | (bit(instruction, 7) << 11)
+            | (bits(instruction, 30, 25) << 5)
+            | (bits(instruction, 11, 8) << 1),
+        13,
+    );
+    let u_imm = bits(instruction, 31, 12);
+    let j_imm = sign_extend(
+        (bit(instruction, 31) << 20)
+            | (bits(instruction, 19, 12) << 12)
+            | (bit(instruction, 20) << 11)
+            | (bits(instruction, 30, 21) << 1),
+        21,
+    );
+    let target = |offset: i64| format!("{:#x}", address.wrapping_add(offset as u64));
+
+    // 丸めモードは動的(dyn)の場合のみ省略する。
+    let rounding = || match ROUNDING_MODES[funct3 as usize] {
+        Some("dyn") => Some(String::new()),
+        Some(name) => Some(format!(", {}", name)),
+        None => None,
+    };
+    let fmt = |funct7: u32| if funct7 & 1 == 0 { "s" } else { "d" };
+
+    let text = match instruction & 0x7f {
+        0x03 => {
+            let name = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"].get(funct3 as usize)?;
+            asm(name, format!("{}, {}({})", x(rd), i_imm, x(rs1)))
+        }
+        0x07 => {
+            let name = match funct3 {
+                2 => "flw",
+                3 => "fld",
+                _ => return None,
+            };
+            asm(name, format!("{}, {}({})", f(rd), i_imm, x(rs1)))
+        }
+        0x0f => match funct3 {
+            0 => fence(instruction),
+            1 => "fence.i".to_string(),
+            _ => return None,
+        },
+        0x13 => {
+            let shamt = bits(instruction, 25, 20);
+            match (funct3, bits(instruction, 31, 26)) {
+                (0, _) if rd == 0 && rs1 == 0 && i_imm == 0 => "nop".to_string(),
+                (0, _) if rs1 == 0 => asm("li", format!("{}, {}", x(rd), i_imm)),
+                (0, _) if i_imm == 0 => asm("mv", format!("{}, {}", x(rd), x(rs1))),
+                (1, 0) => asm("slli", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+                (3, _) if i_imm == 1 => asm("seqz", format!("{}, {}", x(rd), x(rs1))),
+                (4, _) if i_imm == -1 => asm("not", format!("{}, {}", x(rd), x(rs1))),
+                (5, 0) => asm("srli", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+                (5, 0x10) => asm("srai", format!("{}, {}, {}", x(rd), x(rs1), shamt)),
+                (1 | 5, _) => return None,
+                (funct3, _) => {
+                    let name =
+                        ["addi", "", "slti", "sltiu", "xori", "", "ori", "andi"][funct3 as usize];
+                    asm(name, format!("{}, {}, {}", x(rd), x(rs1), i_imm))
+                }
+            }
+        }
+        0x17 => asm("auipc", format!("{}, {:#x}", x(rd), u_imm)),
+        0x1b => match (funct3, funct7) {
+            (0, _) if i_imm == 0 => asm("sext.w", format!("{}, {}", x(rd), x(rs1))),
+            (0, _) => asm("addiw", format!("{}, {}, {}", x(rd), x(rs1), i_imm)),
+            (1, 0) => asm("slliw", format!("{}, {}, {}", x(rd), x(rs1), rs2)),
+            (5, 0) => asm("srliw", format!("{}, {}, {}", x(rd), x(rs1), rs2)),
+            (5, 0x20) => asm("sraiw", format!("{}, {}, {}", x(rd), x(rs1), rs2)),
+            _ => return None,
+        },
+        0x23 => {
+            let name = ["sb", "sh", "sw", "sd"].get(funct3 as usize)?;
+            asm(name, format!("{}, {}({})", x(rs2), s_imm, x(rs1)))
+        }
+        0x27 => {
+            let name = match funct3 {
+                2 => "fsw",
+                3 => "fsd",
+                _ => return None,
+            };
+            asm(name, format!("{}, {}({})", f(rs2), s_imm, x(rs1)))
+        }
+        0x2f => atomic(instruction)?,
+        0x33 => {
+            let name = match (funct7, funct3) {
+                (0, 0) if rs1 == 0 => return Some(asm("mv", format!("{}, {}", x(rd), x(rs2)))),
+                (0x20, 0) if rs1 == 0 => return Some(asm("neg", format!("{}, {}", x(rd), x(rs2)))),
+                (0, 2) if rs2 == 0 => return Some(asm("sltz", format!("{}, {}", x(rd), x(rs1)))),
+                (0, 2) if rs1 == 0 => return Some(asm("sgtz", format!("{}, {}", x(rd), x(rs2)))),
+                (0, 3) if rs1 == 0 => return Some(asm("snez", format!("{}, {}", x(rd), x(rs2)))),
+                (0, funct3) => {
+                    ["add", "sll", "slt", "sltu", "xor", "srl", "or", "and"][funct3 as usize]
+                }
+                (0x20, 0) => "sub",
+                (0x20, 5) => "sra",
+                (1, funct3) => [
+                    "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu",
+                ][funct3 as usize],
+                _ => return None,
+            };
+            asm(name, format!("{}, {}, {}", x(rd), x(rs1), x(rs2)))
+        }
+        0x37 => asm("lui", format!("{}, {:#x}", x(rd), u_imm)),
+        0x3b => {
+            let name = match (funct7, funct3) {
+                (0x20, 0) if rs1 == 0 => {
+                    return Some(asm("negw", format!("{}, {}", x(rd), x(rs2))))
+                }
+                (0, 0) => "addw",
+                (0, 1) => "sllw",
+                (0, 5) => "srlw",
+                (0x20, 0) => "subw",
+                (0x20, 5) => "sraw",
+                (1, 0) => "mulw",
+                (1, 4) => "divw",
+                (1, 5) => "divuw",
+                (1, 6) => "remw",
+                (1, 7) => "remuw",
+                _ => return None,
+            };
+            asm(name, format!("{}, {}, {}", x(rd), x(rs1), x(rs2)))
+        }
+        opcode @ (0x43 | 0x47 | 0x4b | 0x4f) => {
+            let name = match opcode {
+                0x43 => "fmadd",
+                0x47 => "fmsub",
+                0x4b => "fnmsub",
+                _ => "fnmadd",
+            };
+            if funct7 & 0x2 != 0 {
+                return None;
+            }
+            asm(
+                &format!("{}.{}", name, fmt(funct7)),
+                format!(
+                    "{}, {}, {}, {}{}",
+                    f(rd),
+                    f(rs1),
+                    f(rs2),
+                    f(rs3),
+                    rounding()?
+                ),
+            )
+        }
+        0x53 => floating_point(instruction, rounding)?,
+        0x63 => {
+            let offset = target(b_imm);
+            let (name, operands) = match funct3 {
+                0 | 1 | 4 | 5 if rs2 == 0 => {
+                    let name = ["beqz", "bnez", "", "", "bltz", "bgez"][funct3 as usize];
+                    (name, format!("{}, {}", x(rs1), offset))
+                }
+                4 | 5 if rs1 == 0 => {
+                    let name = if funct3 == 4 { "bgtz" } else { "blez" };
+                    (name, format!("{}, {}", x(rs2), offset))
+                }
+                0 | 1 | 4..=7 => {
+                    let name =
+                        ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"][funct3 as usize];
+                    (name, format!("{}, {}, {}", x(rs1), x(rs2), offset))
+                }
+                _ => return None,
+            };
+            asm(name, operands)
+        }
+        0x67 if funct3 == 0 => match (rd, i_imm) {
+            (0, 0) if rs1 == 1 => "ret".to_string(),
+            (0, 0) => asm("jr", x(rs1).to_string()),
+            (1, 0) => asm("jalr", x(rs1).to_string()),
+            _ => asm("jalr", format!("{}, {}({})", x(rd), i_imm, x(rs1))),
+        },
+        0x6f => match rd {
+            0 => asm("j", target(j_imm)),
+            1 => asm("jal", target(j_imm)),
+            _ => asm("jal", format!("{}, {}", x(rd), target(j_imm))),
+        },
+        0x73 => system(instruction)?,
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+// fenceの先行・後続の集合をiorwの文字で表示する。
+fn fence(instruction: u32) -> String {
+    let set = |bits: u32| -> String {
+        "iorw"
+            .chars()
+            .enumerate()
+            .filter(|(i, _)| bits & (0x8 >> i) != 0)
+            .map(|(_, c)| c)
+            .collect()
+    };
+    let predecessor = bits(instruction, 27, 24);
+    let successor = bits(instruction, 23, 20);
+
+    match (bits(instruction, 31, 28), predecessor, successor) {
+        (0x8, 0x3, 0x3) => "fence.tso".to_string(),
+        (_, 0xf, 0xf) => "fence".to_string(),
+        _ => asm("fence", format!("{}, {}", set(predecessor), set(successor))),
+    }
+}
+
+fn atomic(instruction: u32) -> Option<String> {
+    let rd = bits(instruction, 11, 7);
+    let rs1 = bits(instruction, 19, 15);
+    let rs2 = bits(instruction, 24, 20);
+    let width = match bits(instruction, 14, 12) {
+        2 => "w",
+        3 => "d",
+        _ => return None,
+    };
+    let ordering = match bits(instruction, 26, 25) {
+        0 => "",
+        1 => ".rl",
+        2 => ".aq",
+        _ => ".aqrl",
+    };
+
+    let name = match bits(instruction, 31, 27) {
+        0x2 if rs2 == 0 => {
+            let name = format!("lr.{}{}", width, ordering);
+            return Some(asm(&name, format!("{}, ({})", x(rd), x(rs1))));
+        }
+        0x0 => "amoadd",
+        0x1 => "amoswap",
+        0x3 => "sc",
+        0x4 => "amoxor",
+        0x8 => "amoor",
+        0xc => "amoand",
+        0x10 => "amomin",
+        0x14 => "amomax",
+        0x18 => "amominu",
+        0x1c => "amomaxu",
+        _ => return None,
+    };
+
+    let name = format!("{}.{}{}", name, width, ordering);
+    Some(asm(&name, format!("{}, {}, ({})", x(rd), x(rs2), x(rs1))))
+}
+
+fn floating_point(instruction: u32, rounding: impl Fn() -> Option<String>) -> Option<String> {
+    let rd = bits(instruction, 11, 7);
+    let rs1 = bits(instruction, 19, 15);
+    let rs2 = bits(instruction, 24, 20);
+    let funct3 = bits(instruction, 14, 12);
+    let funct7 = bits(instruction, 31, 25);
+    let fmt = if funct7 & 1 == 0 { "s" } else { "d" };
+    // 整数レジスタに書く変換の名前
+    let integer = ["w", "wu", "l", "lu"];
+
+    let text = match funct7 >> 1 {
+        0x00 | 0x02 | 0x04 | 0x06 => {
+            let name = ["fadd", "fsub", "fmul", "fdiv"][(funct7 >> 2) as usize];
+            asm(
+                &format!("{}.{}", name, fmt),
+                format!("{}, {}, {}{}", f(rd), f(rs1), f(rs2), rounding()?),
+            )
+        }
+        0x16 if rs2 == 0 => asm(
+            &format!("fsqrt.{}", fmt),
+            format!("{}, {}{}", f(rd), f(rs1), rounding()?),
+        ),
+        0x08 => {
+            let name = match (funct3, rs1 == rs2) {
+                (0, true) => return Some(asm(&format!("fmv.{}", fmt), fmv(rd, rs1))),
+                (1, true) => return Some(asm(&format!("fneg.{}", fmt), fmv(rd, rs1))),
+                (2, true) => return Some(asm(&format!("fabs.{}", fmt), fmv(rd, rs1))),
+                (0, false) => "fsgnj",
+                (1, false) => "fsgnjn",
+                (2, false) => "fsgnjx",
+                _ => return None,
+            };
+            asm(
+                &format!("{}.{}", name, fmt),
+                format!("{}, {}, {}", f(rd), f(rs1), f(rs2)),
+            )
+        }
+        0x0a => {
+            let name = ["fmin", "fmax"].get(funct3 as usize)?;
+            asm(
+                &format!("{}.{}", name, fmt),
+                format!("{}, {}, {}", f(rd), f(rs1), f(rs2)),
+            )
+        }
+        0x10 if funct7 == 0x20 && rs2 == 1 => {
+            asm("fcvt.s.d", format!("{}, {}{}", f(rd), f(rs1), rounding()?))
+        }
+        0x10 if funct7 == 0x21 && rs2 == 0 => {
+            asm("fcvt.d.s", format!("{}, {}{}", f(rd), f(rs1), rounding()?))
+        }
+        0x28 => {
+            let name = ["fle", "flt", "feq"].get(funct3 as usize)?;
+            asm(
+                &format!("{}.{}", name, fmt),
+                format!("{}, {}, {}", x(rd), f(rs1), f(rs2)),
+            )
+        }
+        0x30 => asm(
+            &format!("fcvt.{}.{}", integer.get(rs2 as usize)?, fmt),
+            format!("{}, {}{}", x(rd), f(rs1), rounding()?),
+        ),
+        0x34 => asm(
+            &format!("fcvt.{}.{}", fmt, integer.get(rs2 as usize)?),
+            format!("{}, {}{}", f(rd), x(rs1), rounding()?),
+        ),
+        0x38 if rs2 == 0 => match funct3 {
+            0 => asm(
+                &format!("fmv.x.{}", if fmt == "s" { "w" } else { "d" }),
+                format!("{}, {}", x(rd), f(rs1)),
+            ),
+            1 => asm(&format!("fclass.{}", fmt), format!("{}, {}", x(rd), f(rs1))),
+            _ => return None,
+        },
+        0x3c if rs2 == 0 && funct3 == 0 => asm(
+            &format!("fmv.{}.x", if fmt == "s" { "w" } else { "d" }),
+            format!("{}, {}", f(rd), x(rs1)),
+        ),
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+fn fmv(rd: u32, rs: u32) -> String {
+    format!("{}, {}", f(rd), f(rs))
+}
+
+fn system(instruction: u32) -> Option<String> {
+    let rd = bits(instruction, 11, 7);
+    let rs1 = bits(instruction, 19, 15);
+    let rs2 = bits(instruction, 24, 20);
+    let csr = csr_name(bits(instruction, 31, 20));
+
+    let text = match bits(instruction, 14, 12) {
+        0 => match instruction {
+            0x0000_0073 => "ecall".to_string(),
+            0x0010_0073 => "ebreak".to_string(),
+            0x1020_0073 => "sret".to_string(),
+            0x3020_0073 => "mret".to_string(),
+            0x1050_0073 => "wfi".to_string(),
+            _ if bits(instruction, 31, 25) == 0x09 && rd == 0 => match (rs1, rs2) {
+                (0, 0) => "sfence.vma".to_string(),
+                (_, 0) => asm("sfence.vma", x(rs1).to_string()),
+                _ => asm("sfence.vma", format!("{}, {}", x(rs1), x(rs2))),
+            },
+            _ => return None,
+        },
+        2 if rs1 == 0 => asm("csrr", format!("{}, {}", x(rd), csr)),
+        funct3 @ (1..=3) if rd == 0 => {
+            let name = ["", "csrw", "csrs", "csrc"][funct3 as usize];
+            asm(name, format!("{}, {}", csr, x(rs1)))
+        }
+        funct3 @ (5..=7) if rd == 0 => {
+            let name = ["", "", "", "", "", "csrwi", "csrsi", "csrci"][funct3 as usize];
+            asm(name, format!("{}, {}", csr, rs1))
+        }
+        funct3 @ (1..=3) => {
+            let name = ["", "csrrw", "csrrs", "csrrc"][funct3 as usize];
+            asm(name, format!("{}, {}, {}", x(rd), csr, x(rs1)))
+        }
+        funct3 @ (5..=7) => {
+            let name = ["", "", "", "", "", "csrrwi", "csrrsi", "csrrci"][funct3 as usize];
+            asm(name, format!("{}, {}, {}", x(rd), csr, rs1))
+        }
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+fn r_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn i_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: i64) -> u32 {
+    ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s_type(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: u32) -> u32 {
+    ((imm >> 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | opcode
+}
+
+fn b_type(funct3: u32, rs1: u32, rs2: u32, imm: i64) -> u32 {
+    let imm = imm as u32;
+    (bit(imm, 12) << 31)
+        | (bits(imm, 10, 5) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (bits(imm, 4, 1) << 8)
+        | (bit(imm, 11) << 7)
+        | 0x63
+}
+
+fn j_type(rd: u32, imm: i64) -> u32 {
+    let imm = imm as u32;
+    (bit(imm, 20) << 31)
+        | (bits(imm, 10, 1) << 21)
+        | (bit(imm, 11) << 20)
+        | (bits(imm, 19, 12) << 12)
+        | (rd << 7)
+        | 0x6f
+}
+
+// C拡張の命令を対応する32ビットの命令に展開する。予約されている命令の場合はNoneを返す。
+pub(super) fn expand_compressed(instruction: u16) -> Option<u32> {
+    let c = instruction as u32;
+    let rd = bits(c, 11, 7);
+    let rs2 = bits(c, 6, 2);
+    // 3ビットで指定するレジスタ(x8-x15)
+    let rd_short = bits(c, 4, 2) + 8;
+    let rs1_short = bits(c, 9, 7) + 8;
+    let imm6 = sign_extend((bit(c, 12) << 5) | bits(c, 6, 2), 6);
+    let shamt = (bit(c, 12) << 5) | bits(c, 6, 2);
+    // c.lw/c.swとc.ld/c.sd等のオフセット
+    let word_offset = (bits(c, 12, 10) << 3) | (bit(c, 6) << 2) | (bit(c, 5) << 6);
+    let double_offset = (bits(c, 12, 10) << 3) | (bits(c, 6, 5) << 6);
+    // c.lwspとc.ldsp等のオフセット
+    let word_sp_offset = (bit(c, 12) << 5) | (bits(c, 6, 4) << 2) | (bits(c, 3, 2) << 6);
+    let double_sp_offset = (bit(c, 12) << 5) | (bits(c, 6, 5) << 3) | (bits(c, 4, 2) << 6);
+
+    let expanded = match (c & 0x3, bits(c, 15, 13)) {
+        (0, 0) => {
+            let imm = (bits(c, 12, 11) << 4)
+                | (bits(c, 10, 7) << 6)
+                | (bit(c, 6) << 2)
+                | (bit(c, 5) << 3);
+            if imm == 0 {
+                return None;
+            }
+            i_type(0x13, rd_short, 0, 2, imm as i64)
+        }
+        (0, 1) => i_type(0x07, rd_short, 3, rs1_short, double_offset as i64),
+        (0, 2) => i_type(0x03, rd_short, 2, rs1_short, word_offset as i64),
+        (0, 3) => i_type(0x03, rd_short, 3, rs1_short, double_offset as i64),
+        (0, 5) => s_type(0x27, 3, rs1_short, rd_short, double_offset),
+        (0, 6) => s_type(0x23, 2, rs1_short, rd_short, word_offset),
+        (0, 7) => s_type(0x23, 3, rs1_short, rd_short, double_offset),
+        (1, 0) => i_type(0x13, rd, 0, rd, imm6),
+        (1, 1) if rd != 0 => i_type(0x1b, rd, 0, rd, imm6),
+        (1, 2) => i_type(0x13, rd, 0, 0, imm6),
+        (1, 3) if rd == 2 => {
+            let imm = sign_extend(
+                (bit(c, 12) << 9)
+                    | (bit(c, 6) << 4)
+                    | (bit(c, 5) << 6)
+                    | (bits(c, 4, 3) << 7)
+                    | (bit(c, 2) << 5),
+                10,
+            );
+            if imm == 0 {
+                return None;
+            }
+            i_type(0x13, 2, 0, 2, imm)
+        }
+        (1, 3) => {
+            if imm6 == 0 {
+                return None;
+            }
+            ((imm6 as u32) << 12) | (rd << 7) | 0x37
+        }
+        (1, 4) => match (bits(c, 11, 10), bit(c, 12), bits(c, 6, 5)) {
+            (0, _, _) => r_type(0x13, rs1_short, 5, rs1_short, shamt & 0x1f, shamt >> 5),
+            (1, _, _) => r_type(
+                0x13,
+                rs1_short,
+                5,
+                rs1_short,
+                shamt & 0x1f,
+                (shamt >> 5) | 0x20,
+            ),
+            (2, _, _) => i_type(0x13, rs1_short, 7, rs1_short, imm6),
+            (3, 0, 0) => r_type(0x33, rs1_short, 0, rs1_short, rd_short, 0x20),
+            (3, 0, 1) => r_type(0x33, rs1_short, 4, rs1_short, rd_short, 0),
+            (3, 0, 2) => r_type(0x33, rs1_short, 6, rs1_short, rd_short, 0),
+            (3, 0, 3) => r_type(0x33, rs1_short, 7, rs1_short, rd_short, 0),
+            (3, 1, 0) => r_type(0x3b, rs1_short, 0, rs1_short, rd_short, 0x20),
+            (3, 1, 1) => r_type(0x3b, rs1_short, 0, rs1_short, rd_short, 0),
+            _ => return None,
+        },
+        (1, 5) => {
+            let offset = sign_extend(
+                (bit(c, 12) << 11)
+                    | (bit(c, 11) << 4)
+                    | (bits(c, 10, 9) << 8)
+                    | (bit(c, 8) << 10)
+                    | (bit(c, 7) << 6)
+                    | (bit(c, 6) << 7)
+                    | (bits(c, 5, 3) << 1)
+                    | (bit(c, 2) << 5),
+                12,
+            );
+            j_type(0, offset)
+        }
+        (1, funct3 @ (6 | 7)) => {
+            let offset = sign_extend(
+                (bit(c, 12) << 8)
+                    | (bits(c, 11, 10) << 3)
+                    | (bits(c, 6, 5) << 6)
+                    | (bits(c, 4, 3) << 1)
+                    | (bit(c, 2) << 5),
+                9,
+            );
+            b_type(funct3 - 6, rs1_short, 0, offset)
+        }
+        (2, 0) => r_type(0x13, rd, 1, rd, shamt & 0x1f, shamt >> 5),
+        (2, 1) => i_type(0x07, rd, 3, 2, double_sp_offset as i64),
+        (2, 2) if rd != 0 => i_type(0x03, rd, 2, 2, word_sp_offset as i64),
+        (2, 3) if rd != 0 => i_type(0x03, rd, 3, 2, double_sp_offset as i64),
+        (2, 4) => match (bit(c, 12), rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => i_type(0x67, 0, 0, rd, 0),
+            (0, _, _) => r_type(0x33, rd, 0, 0, rs2, 0),
+            (_, 0, 0) => 0x0010_0073,
+            (_, _, 0) => i_type(0x67, 1, 0, rd, 0),
+            _ => r_type(0x33, rd, 0, rd, rs2, 0),
+        },
+        (2, 5) => s_type(
+            0x27,
+            3,
+            2,
+            rs2,
+            (bits(c, 12, 10) << 3) | (bits(c, 9, 7) << 6),
+        ),
+        (2, 6) => s_type(
+            0x23,
+            2,
+            2,
+            rs2,
+            (bits(c, 12, 9) << 2) | (bits(c, 8, 7) << 6),
+        ),
+        (2, 7) => s_type(
+            0x23,
+            3,
+            2,
+            rs2,
+            (bits(c, 12, 10) << 3) | (bits(c, 9, 7) << 6),
+        ),
+        _ => return None,
+    };
+
+    Some(expanded)
+}
+
+// 実行可能なセグメントの命令をobjdumpのように表示する関数
+// ELFでないファイルは0番地から置かれた命令列として扱う。
+pub fn objdump(filename: &str, output: &mut dyn Write) -> io::Result<()> {
+    let mut buf = Vec::new();
+    File::open(filename)?.read_to_end(&mut buf)?;
+
+    let mut labels = BTreeMap::new();
+    let sections = if buf.starts_with(b"\x7fELF") {
+        let elf = ElfFile::parse(&buf)?;
+        for (name, address) in elf.symbols {
+            let label = labels.entry(address).or_insert_with(|| name.clone());
+            if name < *label {
+                *label = name;
+            }
+        }
+        elf.segments
+            .into_iter()
+            .filter(|segment| segment.flags & PF_X != 0)
+            .map(|segment| (segment.vaddr, segment.data))
+            .collect()
+    } else {
+        vec![(0, buf)]
+    };
+
+    for (base, data) in sections {
+        writeln!(
+            output,
+            "\nDisassembly of {:#x}-{:#x}:",
+            base,
+            base + data.len() as u64
+        )?;
+        let mut offset = 0;
+        while offset + 2 <= data.len() {
+            let address = base + offset as u64;
+            if let Some(label) = labels.get(&address) {
+                writeln!(output, "\n{:016x} <{}>:", address, label)?;
+            }
+
+            let low = u16::from_le_bytes([data[offset], data[offset + 1]]) as u32;
+            let (length, instruction) = match data.get(offset + 2..offset + 4) {
+                Some(high) if instruction_length(low) == 4 => (
+                    4,
+                    low | (u16::from_le_bytes([high[0], high[1]]) as u32) << 16,
+                ),
+                _ => (2, low),
+            };
+            let hex = if length == 4 {
+                format!("{:08x}", instruction)
+            } else {
+                format!("{:04x}", instruction)
+            };
+            // 16ビットしか残っていない32ビット命令はデータとして表示する。
+            let text = if instruction_length(instruction) == length as u64 {
+                disassemble(instruction, address)
+            } else {
+                format!(".2byte {:#06x}", instruction)
+            };
+            writeln!(output, "{:8x}:\t{:<8}\t{}", address, hex, text)?;
+            offset += length;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use std::{collections::HashMap, io};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+// 実行可能なセグメント
+pub const PF_X: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const ELF64_EHDR_SIZE: usize = 64;
+const ELF64_PHDR_SIZE: usize = 56;
+const ELF64_SHDR_SIZE: usize = 64;
+const ELF64_SYM_SIZE: usize = 24;
+
+pub struct ElfSegment {
+    pub paddr: u64,
+    pub vaddr: u64,
+    // PF_X等のフラグ
+    pub flags: u32,
+    // ファイル内のオフセット
+    pub offset: u64,
+    pub memsz: u64,
+    pub data: Vec<u8>,
+}
+
+pub struct ElfFile {
+    pub entry: u64,
+    // プログラムヘッダのファイル内のオフセットと数
+    pub phoff: u64,
+    pub phnum: u16,
+    pub segments: Vec<ElfSegment>,
+    pub symbols: HashMap<String, u64>,
+}
+
+fn invalid_data(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("elf: {}", what))
+}
+
+fn read_bytes(buf: &[u8], offset: usize, size: usize) -> io::Result<&[u8]> {
+    offset
+        .checked_add(size)
+        .and_then(|end| buf.get(offset..end))
+        .ok_or_else(|| invalid_data("file is truncated"))
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> io::Result<u16> {
+    let bytes = read_bytes(buf, offset, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(read_bytes(buf, offset, 4)?);
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(read_bytes(buf, offset, 8)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+impl ElfFile {
+    // RISC-VのELF64実行ファイルであることを確認してからPT_LOADセグメントを読み出す。
+    pub fn parse(buf: &[u8]) -> io::Result<Self> {
+        if read_bytes(buf, 0, 4)? != ELF_MAGIC {
+            return Err(invalid_data("bad magic number"));
+        }
+
+        match read_bytes(buf, 4, 1)?[0] {
+            ELFCLASS64 => {}
+            ELFCLASS32 => return Err(invalid_data("32-bit ELF is not supported")),
+            class => return Err(invalid_data(&format!("unknown class {}", class))),
+        }
+
+        if read_bytes(buf, 5, 1)?[0] != ELFDATA2LSB {
+            return Err(invalid_data("big-endian ELF is not supported"));
+        }
+
+        let machine = read_u16(buf, 18)?;
+        if machine != EM_RISCV {
+            return Err(invalid_data(&format!(
+                "machine {} is not RISC-V ({})",
+                machine, EM_RISCV
+            )));
+        }
+
+        let e_type = read_u16(buf, 16)?;
+        if e_type != ET_EXEC {
+            return Err(invalid_data(&format!(
+                "type {} is not an executable",
+                e_type
+            )));
+        }
+
+        let entry = read_u64(buf, 24)?;
+        let phoff = read_u64(buf, 32)?;
+        let phentsize = read_u16(buf, 54)?;
+        let phnum = read_u16(buf, 56)?;
+
+        if read_u16(buf, 52)? as usize != ELF64_EHDR_SIZE {
+            return Err(invalid_data("unexpected header size"));
+        }
+        if phnum != 0 && (phentsize as usize) < ELF64_PHDR_SIZE {
+            return Err(invalid_data("unexpected program header size"));
+        }
+
+        let mut segments = Vec::new();
+        for i in 0..phnum as usize {
+            let ph = (phoff as usize)
+                .checked_add(i * phentsize as usize)
+                .ok_or_else(|| invalid_data("program header is out of range"))?;
+
+            if read_u32(buf, ph)? != PT_LOAD {
+                continue;
+            }
+
+            let flags = read_u32(buf, ph + 4)?;
+            let offset = read_u64(buf, ph + 8)?;
+            let vaddr = read_u64(buf, ph + 16)?;
+            let paddr = read_u64(buf, ph + 24)?;
+            let filesz = read_u64(buf, ph + 32)?;
+            let memsz = read_u64(buf, ph + 40)?;
+
+            if filesz > memsz {
+                return Err(invalid_data("segment file size exceeds memory size"));
+            }
+
+            segments.push(ElfSegment {
+                paddr,
+                vaddr,
+                flags,
+                offset,
+                memsz,
+                data: read_bytes(buf, offset as usize, filesz as usize)?.to_vec(),
+            });
+        }
+
+        let symbols = parse_symbols(buf)?;
+
+        Ok(ElfFile {
+            entry,
+            phoff,
+            phnum,
+            segments,
+            symbols,
+        })
+    }
+}
+
+// .symtabのシンボル名と値を読み出す。
+// セクションヘッダやシンボルテーブルが無い場合は空のHashMapを返す。
+fn parse_symbols(buf: &[u8]) -> io::Result<HashMap<String, u64>> {
+    let mut symbols = HashMap::new();
+    let shoff = read_u64(buf, 40)? as usize;
+    let shentsize = read_u16(buf, 58)? as usize;
+    let shnum = read_u16(buf, 60)? as usize;
+
+    if shoff == 0 || shnum == 0 {
+        return Ok(symbols);
+    }
+    if shentsize < ELF64_SHDR_SIZE {
+        return Err(invalid_data("unexpected section header size"));
+    }
+
+    let section = |index: usize| -> io::Result<usize> {
+        if index >= shnum {
+            return Err(invalid_data("section index is out of range"));
+        }
+        shoff
+            .checked_add(index * shentsize)
+            .ok_or_else(|| invalid_data("section header is out of range"))
+    };
+
+    for i in 0..shnum {
+        let sh = section(i)?;
+        if read_u32(buf, sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+
+        let offset = read_u64(buf, sh + 24)? as usize;
+        let size = read_u64(buf, sh + 32)? as usize;
+        let strtab = section(read_u32(buf, sh + 40)? as usize)?;
+        let strtab = read_bytes(
+            buf,
+            read_u64(buf, strtab + 24)? as usize,
+            read_u64(buf, strtab + 32)? as usize,
+        )?;
+
+        for sym in read_bytes(buf, offset, size)?.chunks_exact(ELF64_SYM_SIZE) {
+            let name = read_u32(sym, 0)? as usize;
+            let name = match strtab.get(name..) {
+                Some(name) => name.split(|c| *c == 0).next().unwrap_or_default(),
+                None => return Err(invalid_data("symbol name is out of range")),
+            };
+
+            if !name.is_empty() {
+                symbols.insert(
+                    String::from_utf8_lossy(name).into_owned(),
+                    read_u64(sym, 8)?,
+                );
+            }
+        }
+    }
+
+    Ok(symbols)
+}
@@ -1,10 +1,31 @@
 #[cfg(test)]
 mod tests {
-    use crate::emulator::Rv64SGEmulator;
+    use std::{
+        cell::RefCell,
+        io::{self, Write},
+        rc::Rc,
+    };
+
+    use crate::emulator::{
+        difftest::DiffOutcome,
+        disasm::disassemble,
+        fuzz,
+        fuzz::FuzzCase,
+        mmu::AccessType,
+        objdump,
+        tlb::TlbStats,
+        trace::{Commit, Register},
+        trap::Exception,
+        virt::VIRT_RAM_BASE,
+        DiskMode, ExitReason, FuzzConfig, MachineMode, Rv64SGEmulator, Uart, VirtConfig,
+        VirtioBlock, M_CAUSE, M_EPC, M_IP, M_STATUS, M_TVAL, S_ATP, S_CAUSE, S_EPC, UART_BASE,
+        UART_IRQ, UART_SIZE, VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE,
+    };
 
     const TEST_DIR: &str = "rv64-tests/share/riscv-tests/isa/";
 
-    fn test_exec_program(filename: &str, end_point: u64, addrres: usize, value: u64) {
+    // .binにはシンボルが無いのでtohostのアドレスを直接指定する。
+    fn test_exec_program(filename: &str, tohost: u64) {
         let mut rv64sg_emulator = Rv64SGEmulator::load_from_filename(
             0,
             4096,
@@ -12,139 +33,1641 @@ mod tests {
             &format!("{}{}", TEST_DIR, filename),
         )
         .unwrap();
+        rv64sg_emulator.set_htif(tohost, None);
 
-        rv64sg_emulator.exec_program(end_point);
-        assert!(rv64sg_emulator.load_memory_64bit(addrres).unwrap() == value);
+        assert_eq!(rv64sg_emulator.exec_program().reason, ExitReason::Pass);
     }
 
     #[test]
     fn rv64ui_p_all() {
-        test_exec_program("rv64ui-p-add.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-addi.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-addiw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-and.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-andi.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-auipc.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-beq.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-bge.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-bgeu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-blt.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-bltu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-bne.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-fence_i.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-jal.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-jalr.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-jalr.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-lb.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-lbu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-ld.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-lh.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-lhu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-lui.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-lw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-lwu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-ma_data.bin", 0x4c, 0x2000, 1);
-        test_exec_program("rv64ui-p-or.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-ori.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sb.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sd.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sh.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-simple.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sll.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-slli.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-slliw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sllw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-slt.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-slti.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sltiu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sltu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sra.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-srai.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sraiw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sraw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-srl.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-srli.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-srliw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-srlw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sub.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-subw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-sw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-xor.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ui-p-xori.bin", 0x4c, 0x1000, 1);
+        test_exec_program("rv64ui-p-add.bin", 0x1000);
+        test_exec_program("rv64ui-p-addi.bin", 0x1000);
+        test_exec_program("rv64ui-p-addiw.bin", 0x1000);
+        test_exec_program("rv64ui-p-and.bin", 0x1000);
+        test_exec_program("rv64ui-p-andi.bin", 0x1000);
+        test_exec_program("rv64ui-p-auipc.bin", 0x1000);
+        test_exec_program("rv64ui-p-beq.bin", 0x1000);
+        test_exec_program("rv64ui-p-bge.bin", 0x1000);
+        test_exec_program("rv64ui-p-bgeu.bin", 0x1000);
+        test_exec_program("rv64ui-p-blt.bin", 0x1000);
+        test_exec_program("rv64ui-p-bltu.bin", 0x1000);
+        test_exec_program("rv64ui-p-bne.bin", 0x1000);
+        test_exec_program("rv64ui-p-fence_i.bin", 0x1000);
+        test_exec_program("rv64ui-p-jal.bin", 0x1000);
+        test_exec_program("rv64ui-p-jalr.bin", 0x1000);
+        test_exec_program("rv64ui-p-jalr.bin", 0x1000);
+        test_exec_program("rv64ui-p-lb.bin", 0x1000);
+        test_exec_program("rv64ui-p-lbu.bin", 0x1000);
+        test_exec_program("rv64ui-p-ld.bin", 0x1000);
+        test_exec_program("rv64ui-p-lh.bin", 0x1000);
+        test_exec_program("rv64ui-p-lhu.bin", 0x1000);
+        test_exec_program("rv64ui-p-lui.bin", 0x1000);
+        test_exec_program("rv64ui-p-lw.bin", 0x1000);
+        test_exec_program("rv64ui-p-lwu.bin", 0x1000);
+        test_exec_program("rv64ui-p-ma_data.bin", 0x2000);
+        test_exec_program("rv64ui-p-or.bin", 0x1000);
+        test_exec_program("rv64ui-p-ori.bin", 0x1000);
+        test_exec_program("rv64ui-p-sb.bin", 0x1000);
+        test_exec_program("rv64ui-p-sd.bin", 0x1000);
+        test_exec_program("rv64ui-p-sh.bin", 0x1000);
+        test_exec_program("rv64ui-p-simple.bin", 0x1000);
+        test_exec_program("rv64ui-p-sll.bin", 0x1000);
+        test_exec_program("rv64ui-p-slli.bin", 0x1000);
+        test_exec_program("rv64ui-p-slliw.bin", 0x1000);
+        test_exec_program("rv64ui-p-sllw.bin", 0x1000);
+        test_exec_program("rv64ui-p-slt.bin", 0x1000);
+        test_exec_program("rv64ui-p-slti.bin", 0x1000);
+        test_exec_program("rv64ui-p-sltiu.bin", 0x1000);
+        test_exec_program("rv64ui-p-sltu.bin", 0x1000);
+        test_exec_program("rv64ui-p-sra.bin", 0x1000);
+        test_exec_program("rv64ui-p-srai.bin", 0x1000);
+        test_exec_program("rv64ui-p-sraiw.bin", 0x1000);
+        test_exec_program("rv64ui-p-sraw.bin", 0x1000);
+        test_exec_program("rv64ui-p-srl.bin", 0x1000);
+        test_exec_program("rv64ui-p-srli.bin", 0x1000);
+        test_exec_program("rv64ui-p-srliw.bin", 0x1000);
+        test_exec_program("rv64ui-p-srlw.bin", 0x1000);
+        test_exec_program("rv64ui-p-sub.bin", 0x1000);
+        test_exec_program("rv64ui-p-subw.bin", 0x1000);
+        test_exec_program("rv64ui-p-sw.bin", 0x1000);
+        test_exec_program("rv64ui-p-xor.bin", 0x1000);
+        test_exec_program("rv64ui-p-xori.bin", 0x1000);
     }
 
     #[test]
     fn rv64um_p_all() {
-        test_exec_program("rv64um-p-div.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-divu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-divuw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-divw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-mul.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-mulh.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-mulhsu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-mulhu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-mulw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-rem.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-remu.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-remuw.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64um-p-remw.bin", 0x4c, 0x1000, 1);
+        test_exec_program("rv64um-p-div.bin", 0x1000);
+        test_exec_program("rv64um-p-divu.bin", 0x1000);
+        test_exec_program("rv64um-p-divuw.bin", 0x1000);
+        test_exec_program("rv64um-p-divw.bin", 0x1000);
+        test_exec_program("rv64um-p-mul.bin", 0x1000);
+        test_exec_program("rv64um-p-mulh.bin", 0x1000);
+        test_exec_program("rv64um-p-mulhsu.bin", 0x1000);
+        test_exec_program("rv64um-p-mulhu.bin", 0x1000);
+        test_exec_program("rv64um-p-mulw.bin", 0x1000);
+        test_exec_program("rv64um-p-rem.bin", 0x1000);
+        test_exec_program("rv64um-p-remu.bin", 0x1000);
+        test_exec_program("rv64um-p-remuw.bin", 0x1000);
+        test_exec_program("rv64um-p-remw.bin", 0x1000);
     }
 
     #[test]
     fn rv64ud_p_all() {
-        test_exec_program("rv64ud-p-fadd.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-fclass.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-fcmp.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-fcvt.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-fcvt_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-fdiv.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-fmadd.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-fmin.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-ldst.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-move.bin", 0x4c, 0x2000, 1);
-        test_exec_program("rv64ud-p-recoding.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ud-p-structural.bin", 0x4c, 0x1000, 1);
+        test_exec_program("rv64ud-p-fadd.bin", 0x1000);
+        test_exec_program("rv64ud-p-fclass.bin", 0x1000);
+        test_exec_program("rv64ud-p-fcmp.bin", 0x1000);
+        test_exec_program("rv64ud-p-fcvt.bin", 0x1000);
+        test_exec_program("rv64ud-p-fcvt_w.bin", 0x1000);
+        test_exec_program("rv64ud-p-fdiv.bin", 0x1000);
+        test_exec_program("rv64ud-p-fmadd.bin", 0x1000);
+        test_exec_program("rv64ud-p-fmin.bin", 0x1000);
+        test_exec_program("rv64ud-p-ldst.bin", 0x1000);
+        test_exec_program("rv64ud-p-move.bin", 0x2000);
+        test_exec_program("rv64ud-p-recoding.bin", 0x1000);
+        test_exec_program("rv64ud-p-structural.bin", 0x1000);
     }
 
     #[test]
     fn rv64uf_p_all() {
-        test_exec_program("rv64uf-p-fadd.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64uf-p-fclass.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64uf-p-fcmp.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64uf-p-fcvt.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64uf-p-fcvt_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64uf-p-fmadd.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64uf-p-fmin.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64uf-p-ldst.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64uf-p-move.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64uf-p-recoding.bin", 0x4c, 0x1000, 1);
+        test_exec_program("rv64uf-p-fadd.bin", 0x1000);
+        test_exec_program("rv64uf-p-fclass.bin", 0x1000);
+        test_exec_program("rv64uf-p-fcmp.bin", 0x1000);
+        test_exec_program("rv64uf-p-fcvt.bin", 0x1000);
+        test_exec_program("rv64uf-p-fcvt_w.bin", 0x1000);
+        test_exec_program("rv64uf-p-fmadd.bin", 0x1000);
+        test_exec_program("rv64uf-p-fmin.bin", 0x1000);
+        test_exec_program("rv64uf-p-ldst.bin", 0x1000);
+        test_exec_program("rv64uf-p-move.bin", 0x1000);
+        test_exec_program("rv64uf-p-recoding.bin", 0x1000);
     }
 
     #[test]
     fn rv64ua_p_all() {
-        test_exec_program("rv64ua-p-amoadd_d.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amoadd_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amoand_d.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amoand_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amomax_d.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amomax_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amomaxu_d.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amomaxu_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amomin_d.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amomin_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amominu_d.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amominu_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amoor_d.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amoor_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amoswap_d.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amoswap_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amoxor_d.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-amoxor_w.bin", 0x4c, 0x1000, 1);
-        test_exec_program("rv64ua-p-lrsc.bin", 0x4c, 0x1000, 1);
+        test_exec_program("rv64ua-p-amoadd_d.bin", 0x1000);
+        test_exec_program("rv64ua-p-amoadd_w.bin", 0x1000);
+        test_exec_program("rv64ua-p-amoand_d.bin", 0x1000);
+        test_exec_program("rv64ua-p-amoand_w.bin", 0x1000);
+        test_exec_program("rv64ua-p-amomax_d.bin", 0x1000);
+        test_exec_program("rv64ua-p-amomax_w.bin", 0x1000);
+        test_exec_program("rv64ua-p-amomaxu_d.bin", 0x1000);
+        test_exec_program("rv64ua-p-amomaxu_w.bin", 0x1000);
+        test_exec_program("rv64ua-p-amomin_d.bin", 0x1000);
+        test_exec_program("rv64ua-p-amomin_w.bin", 0x1000);
+        test_exec_program("rv64ua-p-amominu_d.bin", 0x1000);
+        test_exec_program("rv64ua-p-amominu_w.bin", 0x1000);
+        test_exec_program("rv64ua-p-amoor_d.bin", 0x1000);
+        test_exec_program("rv64ua-p-amoor_w.bin", 0x1000);
+        test_exec_program("rv64ua-p-amoswap_d.bin", 0x1000);
+        test_exec_program("rv64ua-p-amoswap_w.bin", 0x1000);
+        test_exec_program("rv64ua-p-amoxor_d.bin", 0x1000);
+        test_exec_program("rv64ua-p-amoxor_w.bin", 0x1000);
+        test_exec_program("rv64ua-p-lrsc.bin", 0x1000);
     }
 
     #[test]
     fn rv64uc_p_rvc() {
-        test_exec_program("rv64uc-p-rvc.bin", 0x4c, 0x3000, 1);
+        test_exec_program("rv64uc-p-rvc.bin", 0x3000);
+    }
+
+    // PT_LOADセグメントを1つだけ持つELFを作る。
+    fn build_elf(class: u8, entry: u64, paddr: u64, data: &[u8], memsz: u64) -> Vec<u8> {
+        let mut elf = vec![0; 64 + 56];
+        elf[0..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+        elf[4] = class;
+        elf[5] = 1;
+        elf[6] = 1;
+        elf[16..18].copy_from_slice(&2u16.to_le_bytes());
+        elf[18..20].copy_from_slice(&243u16.to_le_bytes());
+        elf[24..32].copy_from_slice(&entry.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[52..54].copy_from_slice(&64u16.to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        elf[56..58].copy_from_slice(&1u16.to_le_bytes());
+
+        let ph = &mut elf[64..];
+        ph[0..4].copy_from_slice(&1u32.to_le_bytes());
+        ph[4..8].copy_from_slice(&7u32.to_le_bytes());
+        ph[8..16].copy_from_slice(&120u64.to_le_bytes());
+        ph[16..24].copy_from_slice(&paddr.to_le_bytes());
+        ph[24..32].copy_from_slice(&paddr.to_le_bytes());
+        ph[32..40].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        ph[40..48].copy_from_slice(&memsz.to_le_bytes());
+
+        elf.extend_from_slice(data);
+        elf
+    }
+
+    fn write_temp_file(name: &str, contents: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("udy-cream-{}", name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn load_elf_segments() {
+        let data = [0x13, 0, 0, 0, 0x6f, 0, 0, 0];
+        let path = write_temp_file(
+            "segments.elf",
+            &build_elf(2, 0x8000_0104, 0x8000_0100, &data, 16),
+        );
+        let mut rv64sg_emulator =
+            Rv64SGEmulator::load_from_elf_file(0x8000_0000, 0x8000_1000, 4096, &path).unwrap();
+
+        assert_eq!(rv64sg_emulator.pc, 0x8000_0104);
+        assert_eq!(rv64sg_emulator.registers[2], 0x8000_1000);
+        assert_eq!(
+            rv64sg_emulator.load_memory_64bit(0x8000_0100).unwrap(),
+            0x6f00000013
+        );
+        assert_eq!(rv64sg_emulator.load_memory_64bit(0x8000_0108).unwrap(), 0);
+        assert!(rv64sg_emulator.load_memory_64bit(0x100).is_err());
+    }
+
+    #[test]
+    fn load_elf_rejects_invalid_files() {
+        let elf32 = write_temp_file("class32.elf", &build_elf(1, 0, 0, &[], 0));
+        let outside = write_temp_file("outside.elf", &build_elf(2, 0, 0x8000_1000, &[], 4));
+        let mut not_riscv = build_elf(2, 0, 0, &[], 0);
+        not_riscv[18] = 62;
+        let not_riscv = write_temp_file("x86.elf", &not_riscv);
+        let truncated = write_temp_file("truncated.elf", b"\x7fELF");
+        let huge = write_temp_file("huge.elf", &build_elf(2, 0, 0x8000_0000, &[], 1 << 62));
+
+        assert!(Rv64SGEmulator::load_from_elf_file(0x8000_0000, 0, 4096, &elf32).is_err());
+        assert!(Rv64SGEmulator::load_from_elf_file(0x8000_0000, 0, 4096, &outside).is_err());
+        assert!(Rv64SGEmulator::load_from_elf_file(0x8000_0000, 0, 4096, &not_riscv).is_err());
+        assert!(Rv64SGEmulator::load_from_elf_file(0x8000_0000, 0, 4096, &truncated).is_err());
+        assert!(Rv64SGEmulator::load_from_elf_file(0x8000_0000, 0, 4096, &huge).is_err());
+    }
+
+    fn load_program(program: &[u32]) -> Rv64SGEmulator {
+        let mut rv64sg_emulator = Rv64SGEmulator::new(0, 4096, 0, 1024 * 64);
+        for (i, instruction) in program.iter().enumerate() {
+            rv64sg_emulator
+                .bus
+                .write_bytes(i as u64 * 4, &instruction.to_le_bytes())
+                .unwrap();
+        }
+
+        rv64sg_emulator
+    }
+
+    #[test]
+    fn htif_console_and_exit() {
+        let mut rv64sg_emulator = load_program(&[
+            0x000012b7, // lui t0, 0x1
+            0x10100313, // li t1, 0x101
+            0x03031313, // slli t1, t1, 48
+            0x06830313, // addi t1, t1, 'h'
+            0x0062b023, // sd t1, 0(t0)
+            0x00700393, // li t2, 7
+            0x0072b023, // sd t2, 0(t0)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, Some(0x1040));
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(3));
+        assert_eq!(exit_status.console, b"h");
+    }
+
+    #[test]
+    fn illegal_instruction_sets_mtval() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00001337, // lui t1, 0x1
+            0x01400293, // li t0, 0x14
+            0x30529073, // csrw mtvec, t0
+            0xffffffff, // 不正な命令
+            0x0000006f, // j .
+            0x342022f3, // csrr t0, mcause
+            0x00129293, // slli t0, t0, 1
+            0x0012e293, // ori t0, t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(2));
+        assert_eq!(rv64sg_emulator.csrs[M_EPC], 0xc);
+        assert_eq!(rv64sg_emulator.csrs[M_TVAL], 0xffffffff);
+    }
+
+    #[test]
+    fn delegate_ecall_to_supervisor() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00001337, // lui t1, 0x1
+            0x10000293, // li t0, 0x100
+            0x30229073, // csrw medeleg, t0
+            0x04000293, // li t0, 0x40
+            0x10529073, // csrw stvec, t0
+            0x02400293, // li t0, 0x24
+            0x34129073, // csrw mepc, t0
+            0x30200073, // mret
+            0x0000006f, // j .
+            0x00000073, // ecall (U-mode)
+            0x00129293, // slli t0, t0, 1
+            0x0012e293, // ori t0, t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+            0x00000013, // nop
+            0x00000013, // nop
+            0x142022f3, // csrr t0, scause (S-mode)
+            0x141023f3, // csrr t2, sepc
+            0x00438393, // addi t2, t2, 4
+            0x14139073, // csrw sepc, t2
+            0x10200073, // sret
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(8));
+        assert!(rv64sg_emulator.mode == MachineMode::U);
+        assert_eq!(rv64sg_emulator.csrs[S_EPC], 0x28);
+    }
+
+    #[test]
+    fn sret_clears_mprv() {
+        let mut rv64sg_emulator = load_program(&[
+            0x000202b7, // lui t0, 0x20
+            0x3002a073, // csrs mstatus, t0 (MPRV = 1)
+            0x01800293, // li t0, 0x18
+            0x14129073, // csrw sepc, t0
+            0x10200073, // sret
+            0x0000006f, // j .
+            0x00001337, // lui t1, 0x1 (U-mode)
+            0x00100293, // li t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+        assert!(rv64sg_emulator.mode == MachineMode::U);
+        assert_eq!(rv64sg_emulator.csrs[M_STATUS] & 0x20000, 0);
+    }
+
+    #[test]
+    fn machine_trap_preserves_spp() {
+        let mut rv64sg_emulator = load_program(&[
+            0x10000293, // li t0, 0x100
+            0x3002a073, // csrs mstatus, t0 (SPP = S)
+            0x02000293, // li t0, 0x20
+            0x30529073, // csrw mtvec, t0
+            0x00000073, // ecall
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00001337, // lui t1, 0x1
+            0x00100293, // li t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+
+        // M-modeへのトラップで変わるのはMPP/MPIE/MIEだけ
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+        assert_eq!(rv64sg_emulator.csrs[M_CAUSE], 11);
+        assert_eq!(rv64sg_emulator.csrs[M_STATUS] & 0x1988, 0x1900);
+    }
+
+    #[test]
+    fn sv39_translate() {
+        let mut rv64sg_emulator = load_program(&[]);
+        let pte = |ppn: u64, flags: u64| ((ppn << 10) | flags).to_le_bytes();
+        // 0x1000 -> 0x2000 -> 0x3000 の3段のページテーブル
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x1000, &pte(0x2, 0x1))
+            .unwrap();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x2000, &pte(0x3, 0x1))
+            .unwrap();
+        // VA 0x5000 -> PA 0x8000 (R/W, A/Dは0)
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x3028, &pte(0x8, 0x7))
+            .unwrap();
+        // VA 0x6000 -> PA 0x9000 (R/W)
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x3030, &pte(0x9, 0x7))
+            .unwrap();
+        rv64sg_emulator.csrs[S_ATP] = (8 << 60) | 0x1;
+        rv64sg_emulator.mode = MachineMode::S;
+
+        assert_eq!(
+            rv64sg_emulator.translate(0x5123, AccessType::Load),
+            Ok(0x8123)
+        );
+        assert_eq!(rv64sg_emulator.bus.read(0x3028, 1), Ok(0x47));
+        assert_eq!(
+            rv64sg_emulator.translate(0x5123, AccessType::Store),
+            Ok(0x8123)
+        );
+        assert_eq!(rv64sg_emulator.bus.read(0x3028, 1), Ok(0xc7));
+        assert_eq!(
+            rv64sg_emulator.translate(0x5123, AccessType::Instruction),
+            Err(Exception::InstructionPageFault(0x5123).into())
+        );
+        assert_eq!(
+            rv64sg_emulator.translate(0x7000, AccessType::Load),
+            Err(Exception::LoadPageFault(0x7000).into())
+        );
+
+        // ページ境界をまたぐストアはそれぞれのページに書き込まれる。
+        rv64sg_emulator
+            .save_memory_32bit(0x5ffe, 0x44332211)
+            .unwrap();
+        assert_eq!(rv64sg_emulator.bus.read(0x8ffe, 2), Ok(0x2211));
+        assert_eq!(rv64sg_emulator.bus.read(0x9000, 2), Ok(0x4433));
+
+        rv64sg_emulator.mode = MachineMode::U;
+        assert_eq!(
+            rv64sg_emulator.translate(0x5123, AccessType::Store),
+            Err(Exception::StorePageFault(0x5123).into())
+        );
+    }
+
+    #[test]
+    fn tlb_hit_and_flush() {
+        let mut rv64sg_emulator = load_program(&[]);
+        let pte = |ppn: u64, flags: u64| ((ppn << 10) | flags).to_le_bytes();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x1000, &pte(0x2, 0x1))
+            .unwrap();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x2000, &pte(0x3, 0x1))
+            .unwrap();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x3028, &pte(0x8, 0xc7))
+            .unwrap();
+        rv64sg_emulator.csrs[S_ATP] = (8 << 60) | (1 << 44) | 0x1;
+        rv64sg_emulator.mode = MachineMode::S;
+
+        assert_eq!(
+            rv64sg_emulator.translate(0x5010, AccessType::Load),
+            Ok(0x8010)
+        );
+        assert_eq!(
+            rv64sg_emulator.translate(0x5020, AccessType::Load),
+            Ok(0x8020)
+        );
+        assert_eq!(
+            rv64sg_emulator.tlb_stats().1,
+            TlbStats { hits: 1, misses: 1 }
+        );
+
+        // sfence.vmaするまでは古い変換が使われる。
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x3028, &pte(0x9, 0xc7))
+            .unwrap();
+        assert_eq!(
+            rv64sg_emulator.translate(0x5000, AccessType::Load),
+            Ok(0x8000)
+        );
+
+        // 別のASIDを指定した場合は無効化されない。
+        rv64sg_emulator.flush_tlb(Some(0x5000), Some(2));
+        assert_eq!(
+            rv64sg_emulator.translate(0x5000, AccessType::Load),
+            Ok(0x8000)
+        );
+
+        rv64sg_emulator.flush_tlb(Some(0x5000), Some(1));
+        assert_eq!(
+            rv64sg_emulator.translate(0x5000, AccessType::Load),
+            Ok(0x9000)
+        );
+        assert_eq!(
+            rv64sg_emulator.tlb_stats().1,
+            TlbStats { hits: 3, misses: 2 }
+        );
+    }
+
+    #[test]
+    fn clint_timer_interrupt() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00001337, // lui t1, 0x1
+            0x04000293, // li t0, 0x40
+            0x30529073, // csrw mtvec, t0
+            0x020043b7, // lui t2, 0x2004 (mtimecmp)
+            0x03200293, // li t0, 50
+            0x0053b023, // sd t0, 0(t2)
+            0x08000293, // li t0, 0x80
+            0x30429073, // csrw mie, t0
+            0x00800293, // li t0, 0x8
+            0x3002a073, // csrs mstatus, t0
+            0x0000006f, // j .
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x342022f3, // csrr t0, mcause
+            0x00129293, // slli t0, t0, 1
+            0x0012e293, // ori t0, t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(7));
+        assert_eq!(rv64sg_emulator.csrs[M_CAUSE], (1 << 63) | 7);
+        assert_eq!(rv64sg_emulator.csrs[M_EPC], 0x28);
+    }
+
+    // UARTの出力をテストから読めるようにする。
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn uart_echo() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00001337, // lui t1, 0x1
+            0x100003b7, // lui t2, 0x10000 (UART)
+            0x06800293, // li t0, 'h'
+            0x00538023, // sb t0, 0(t2)
+            0x0053c283, // lbu t0, 5(t2) (LSR)
+            0x0012f293, // andi t0, t0, 1
+            0xfe028ce3, // beqz t0, -8
+            0x0003c283, // lbu t0, 0(t2)
+            0x00538023, // sb t0, 0(t2)
+            0x00100293, // li t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let uart = Uart::new(
+            Some(Box::new(io::Cursor::new(b"x".to_vec()))),
+            Box::new(SharedBuffer(output.clone())),
+        );
+        rv64sg_emulator.add_device(UART_BASE, UART_SIZE, None, Box::new(uart));
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+        assert_eq!(*output.borrow(), b"hx");
+    }
+
+    #[test]
+    fn plic_uart_interrupt() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00001337, // lui t1, 0x1
+            0x0c0003b7, // lui t2, 0xc000 (PLIC)
+            0x00100293, // li t0, 1
+            0x0253a423, // sw t0, 0x28(t2) (priority[10])
+            0x0c002e37, // lui t3, 0xc002
+            0x40000293, // li t0, 0x400
+            0x005e2023, // sw t0, 0(t3) (context 0のenable)
+            0x10000eb7, // lui t4, 0x10000 (UART)
+            0x00100293, // li t0, 1
+            0x005e80a3, // sb t0, 1(t4) (IER)
+            0x05000293, // li t0, 0x50
+            0x30529073, // csrw mtvec, t0
+            0x00100293, // li t0, 1
+            0x00b29293, // slli t0, t0, 11
+            0x30429073, // csrw mie, t0
+            0x00800293, // li t0, 0x8
+            0x3002a073, // csrs mstatus, t0
+            0x0000006f, // j .
+            0x00000013, // nop
+            0x00000013, // nop
+            0x0c200e37, // lui t3, 0xc200
+            0x004e2283, // lw t0, 4(t3) (claim)
+            0x005e2223, // sw t0, 4(t3) (complete)
+            0x00129293, // slli t0, t0, 1
+            0x0012e293, // ori t0, t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+        let uart = Uart::new(
+            Some(Box::new(io::Cursor::new(b"x".to_vec()))),
+            Box::new(io::sink()),
+        );
+        rv64sg_emulator.add_device(UART_BASE, UART_SIZE, Some(UART_IRQ), Box::new(uart));
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(UART_IRQ as u64));
+        assert_eq!(rv64sg_emulator.csrs[M_CAUSE], (1 << 63) | 11);
+    }
+
+    #[test]
+    fn plic_supervisor_interrupt() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00001337, // lui t1, 0x1
+            0x0c0003b7, // lui t2, 0xc000 (PLIC)
+            0x00100293, // li t0, 1
+            0x0253a423, // sw t0, 0x28(t2) (priority[10])
+            0x0c002e37, // lui t3, 0xc002
+            0x40000293, // li t0, 0x400
+            0x085e2023, // sw t0, 0x80(t3) (context 1のenable)
+            0x10000eb7, // lui t4, 0x10000 (UART)
+            0x00100293, // li t0, 1
+            0x005e80a3, // sb t0, 1(t4) (IER)
+            0x20000293, // li t0, 0x200
+            0x30329073, // csrw mideleg, t0
+            0x30429073, // csrw mie, t0
+            0x00000297, // auipc t0, 0
+            0x03028293, // addi t0, t0, 48
+            0x10529073, // csrw stvec, t0
+            0x000012b7, // lui t0, 0x1
+            0x8002829b, // addiw t0, t0, -2048
+            0x3002a073, // csrs mstatus, t0 (MPP = S)
+            0x00000297, // auipc t0, 0
+            0x01028293, // addi t0, t0, 16
+            0x34129073, // csrw mepc, t0
+            0x30200073, // mret
+            0x10016073, // csrsi sstatus, 2
+            0x0000006f, // j .
+            0x0c201e37, // lui t3, 0xc201
+            0x004e2283, // lw t0, 4(t3) (claim)
+            0x000ecf03, // lbu t5, 0(t4) (RBR)
+            0x005e2223, // sw t0, 4(t3) (complete)
+            0x00129293, // slli t0, t0, 1
+            0x0012e293, // ori t0, t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+        let uart = Uart::new(
+            Some(Box::new(io::Cursor::new(b"x".to_vec()))),
+            Box::new(io::sink()),
+        );
+        rv64sg_emulator.add_device(UART_BASE, UART_SIZE, Some(UART_IRQ), Box::new(uart));
+
+        // completeした後はPLICがSEIPを下げるので、mipにも残らない。
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(UART_IRQ as u64));
+        assert_eq!(rv64sg_emulator.csrs[S_CAUSE], (1 << 63) | 9);
+        assert_eq!(rv64sg_emulator.registers[30], b'x' as u64);
+        assert_eq!(rv64sg_emulator.csrs[M_IP] & 0x200, 0);
+    }
+
+    #[test]
+    fn csr_read_does_not_write() {
+        let mut rv64sg_emulator = load_program(&[
+            0x0c0003b7, // lui t2, 0xc000 (PLIC)
+            0x00100293, // li t0, 1
+            0x0253a423, // sw t0, 0x28(t2) (priority[10])
+            0x0c002e37, // lui t3, 0xc002
+            0x40000293, // li t0, 0x400
+            0x085e2023, // sw t0, 0x80(t3) (context 1のenable)
+            0x10000eb7, // lui t4, 0x10000 (UART)
+            0x00100293, // li t0, 1
+            0x005e80a3, // sb t0, 1(t4) (IER)
+            0x34402f73, // csrr t5, mip
+            0x200f7293, // andi t0, t5, 0x200
+            0xfe028ce3, // beqz t0, -8
+            0x34407ff3, // csrrci t6, mip, 0
+            0x0c201e37, // lui t3, 0xc201
+            0x004e2283, // lw t0, 4(t3) (claim)
+            0x000ec483, // lbu s1, 0(t4) (RBR)
+            0x005e2223, // sw t0, 4(t3) (complete)
+            0x00001337, // lui t1, 0x1
+            0x00100293, // li t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+        let uart = Uart::new(
+            Some(Box::new(io::Cursor::new(b"x".to_vec()))),
+            Box::new(io::sink()),
+        );
+        rv64sg_emulator.add_device(UART_BASE, UART_SIZE, Some(UART_IRQ), Box::new(uart));
+
+        // 読み出しただけのSEIPはソフトウェアが書き込んだ値として残らない。
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+        assert_eq!(rv64sg_emulator.registers[31] & 0x200, 0x200);
+        assert_eq!(rv64sg_emulator.csrs[M_IP] & 0x200, 0);
+    }
+
+    #[test]
+    fn csr_set_mip_does_not_latch_seip() {
+        let mut rv64sg_emulator = load_program(&[
+            0x0c0003b7, // lui t2, 0xc000 (PLIC)
+            0x00100293, // li t0, 1
+            0x0253a423, // sw t0, 0x28(t2) (priority[10])
+            0x0c002e37, // lui t3, 0xc002
+            0x40000293, // li t0, 0x400
+            0x085e2023, // sw t0, 0x80(t3) (context 1のenable)
+            0x10000eb7, // lui t4, 0x10000 (UART)
+            0x00100293, // li t0, 1
+            0x005e80a3, // sb t0, 1(t4) (IER)
+            0x34402f73, // csrr t5, mip
+            0x200f7293, // andi t0, t5, 0x200
+            0xfe028ce3, // beqz t0, -8
+            0x00200293, // li t0, 2
+            0x3442a073, // csrs mip, t0
+            0x34487073, // csrci mip, 0x10
+            0x0c201e37, // lui t3, 0xc201
+            0x004e2283, // lw t0, 4(t3) (claim)
+            0x000ec483, // lbu s1, 0(t4) (RBR)
+            0x005e2223, // sw t0, 4(t3) (complete)
+            0x00001337, // lui t1, 0x1
+            0x00100293, // li t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+        let uart = Uart::new(
+            Some(Box::new(io::Cursor::new(b"x".to_vec()))),
+            Box::new(io::sink()),
+        );
+        rv64sg_emulator.add_device(UART_BASE, UART_SIZE, Some(UART_IRQ), Box::new(uart));
+
+        // csrs/csrcはPLICが立てているSEIPを書き戻さず、SSIPだけが残る。
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+        assert_eq!(rv64sg_emulator.csrs[M_IP] & 0x222, 0x2);
+    }
+
+    #[test]
+    fn virtio_block_copy_on_write() {
+        let image: Vec<u8> = (0..4 * 512).map(|i| (i / 512) as u8).collect();
+        let path = write_temp_file("disk.img", &image);
+        let mut rv64sg_emulator = load_program(&[]);
+        let disk = VirtioBlock::new(&path, DiskMode::CopyOnWrite).unwrap();
+        rv64sg_emulator.add_device(VIRTIO_BASE, VIRTIO_SIZE, Some(VIRTIO_IRQ), Box::new(disk));
+
+        let bus = &mut rv64sg_emulator.bus;
+        assert_eq!(bus.read(VIRTIO_BASE, 4), Ok(0x74726976));
+        assert_eq!(bus.read(VIRTIO_BASE + 0x100, 8), Ok(4));
+        for (offset, value) in [
+            (0x38, 8),
+            (0x80, 0x2000),
+            (0x90, 0x3000),
+            (0xa0, 0x4000),
+            (0x44, 1),
+        ] {
+            bus.write(VIRTIO_BASE + offset, 4, value).unwrap();
+        }
+
+        // セクタ1に0xaaを書き込み、その後読み出す。
+        let descriptors: [(u64, u32, u16, u16); 6] = [
+            (0x5000, 16, 0x1, 1),
+            (0x6000, 512, 0x1, 2),
+            (0x5010, 1, 0x2, 0),
+            (0x5020, 16, 0x1, 4),
+            (0x7000, 512, 0x3, 5),
+            (0x5030, 1, 0x2, 0),
+        ];
+        for (i, (addr, len, flags, next)) in descriptors.iter().enumerate() {
+            let mut desc = addr.to_le_bytes().to_vec();
+            desc.extend(len.to_le_bytes());
+            desc.extend(flags.to_le_bytes());
+            desc.extend(next.to_le_bytes());
+            bus.write_bytes(0x2000 + 16 * i as u64, &desc).unwrap();
+        }
+        bus.write_bytes(0x5000, &[1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        bus.write_bytes(0x5020, &[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        bus.write_bytes(0x5010, &[0xff]).unwrap();
+        bus.write_bytes(0x5030, &[0xff]).unwrap();
+        bus.write_bytes(0x6000, &[0xaa; 512]).unwrap();
+        bus.write_bytes(0x3000, &[0, 0, 2, 0, 0, 0, 3, 0]).unwrap();
+
+        bus.write(VIRTIO_BASE + 0x50, 4, 0).unwrap();
+        bus.tick();
+
+        assert_eq!(bus.read(0x4002, 2), Ok(2));
+        assert_eq!(bus.read(0x4004, 4), Ok(0));
+        assert_eq!(bus.read(0x400c, 4), Ok(3));
+        assert_eq!(bus.read(0x4010, 4), Ok(513));
+        assert_eq!(bus.read(0x5010, 1), Ok(0));
+        assert_eq!(bus.read(0x5030, 1), Ok(0));
+        assert_eq!(bus.read(0x7000, 8), Ok(0xaaaaaaaaaaaaaaaa));
+        assert_eq!(bus.read(0x71f8, 8), Ok(0xaaaaaaaaaaaaaaaa));
+        assert_eq!(bus.read(VIRTIO_BASE + 0x60, 4), Ok(1));
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+    }
+
+    #[test]
+    fn sbi_console_timer_and_shutdown() {
+        let mut rv64sg_emulator = load_program(&[
+            0x01000893, // li a7, 0x10 (BASE)
+            0x00300813, // li a6, 3 (probe_extension)
+            0x54495537, // lui a0, 0x54495
+            0xd455051b, // addiw a0, a0, -699 (TIME)
+            0x00000073, // ecall
+            0x00058413, // mv s0, a1
+            0x00100893, // li a7, 1 (console_putchar)
+            0x06f00513, // li a0, 'o'
+            0x00000073, // ecall
+            0x06b00513, // li a0, 'k'
+            0x00000073, // ecall
+            0x06000293, // li t0, 0x60
+            0x10529073, // csrw stvec, t0
+            0x02000293, // li t0, 0x20
+            0x10429073, // csrw sie, t0
+            0x00200293, // li t0, 2
+            0x1002a073, // csrs sstatus, t0
+            0x544958b7, // lui a7, 0x54495
+            0xd458889b, // addiw a7, a7, -699 (TIME)
+            0x00000813, // li a6, 0 (set_timer)
+            0xc0102573, // rdtime a0
+            0x00a50513, // addi a0, a0, 10
+            0x00000073, // ecall
+            0x0000006f, // j .
+            0x535258b7, // lui a7, 0x53525
+            0x3548889b, // addiw a7, a7, 852 (SRST)
+            0x00000813, // li a6, 0 (system_reset)
+            0x00000513, // li a0, 0 (shutdown)
+            0x00000593, // li a1, 0 (no reason)
+            0x00000073, // ecall
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.enable_sbi();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let uart = Uart::new(None, Box::new(SharedBuffer(output.clone())));
+        rv64sg_emulator.add_device(UART_BASE, UART_SIZE, None, Box::new(uart));
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+        assert_eq!(*output.borrow(), b"ok");
+        assert_eq!(rv64sg_emulator.registers[8], 1);
+        assert_eq!(rv64sg_emulator.registers[10], 0);
+        assert!(rv64sg_emulator.mode == MachineMode::S);
+        assert_eq!(rv64sg_emulator.csrs[S_CAUSE], (1 << 63) | 5);
+        assert_eq!(rv64sg_emulator.csrs[S_EPC], 0x5c);
+    }
+
+    #[test]
+    fn virt_boot_linux_image() {
+        let mut kernel = vec![0; 64];
+        kernel[0..4].copy_from_slice(&0x0400006fu32.to_le_bytes()); // j 64
+        kernel[8..16].copy_from_slice(&0x20_0000u64.to_le_bytes());
+        kernel[16..24].copy_from_slice(&0x1000u64.to_le_bytes());
+        kernel[48..56].copy_from_slice(b"RISCV\0\0\0");
+        kernel[56..60].copy_from_slice(b"RSC\x05");
+        for instruction in [
+            0x00050493u32, // mv s1, a0
+            0x0005e903,    // lwu s2, 0(a1)
+            0x00800893,    // li a7, 8 (legacy shutdown)
+            0x00000073,    // ecall
+        ] {
+            kernel.extend(instruction.to_le_bytes());
+        }
+        let kernel = write_temp_file("Image", &kernel);
+        let initrd = write_temp_file("initrd", &[0x5a; 16]);
+
+        let mut rv64sg_emulator = Rv64SGEmulator::load_virt(VirtConfig {
+            memsz: 1024 * 1024 * 4,
+            kernel,
+            initrd: Some(initrd),
+            bootargs: "console=ttyS0".to_string(),
+            uart: Uart::new(None, Box::new(io::sink())),
+            disk: None,
+        })
+        .unwrap();
+        assert_eq!(rv64sg_emulator.pc, VIRT_RAM_BASE + 0x20_0000);
+        assert_eq!(rv64sg_emulator.registers[11], VIRT_RAM_BASE + 0x3f_0000);
+        assert_eq!(
+            rv64sg_emulator.bus.read(VIRT_RAM_BASE + 0x3e_f000, 8),
+            Ok(0x5a5a5a5a5a5a5a5a)
+        );
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+        assert_eq!(rv64sg_emulator.registers[9], 0);
+        assert_eq!(rv64sg_emulator.registers[18], 0xedfe0dd0);
+        assert!(rv64sg_emulator.mode == MachineMode::S);
+    }
+
+    #[test]
+    fn virt_rejects_image_without_header() {
+        // text_offsetやimage_sizeを足すとアドレスが溢れるヘッダも受け付けない。
+        let header = |text_offset: u64, image_size: u64| {
+            let mut header = vec![0; 64];
+            header[8..16].copy_from_slice(&text_offset.to_le_bytes());
+            header[16..24].copy_from_slice(&image_size.to_le_bytes());
+            header[48..56].copy_from_slice(b"RISCV\0\0\0");
+            header
+        };
+        for (name, image) in [
+            ("not-an-Image", vec![0x13; 64]),
+            ("text-offset.Image", header(u64::MAX, 0)),
+            ("image-size.Image", header(0x20_0000, u64::MAX)),
+        ] {
+            let result = Rv64SGEmulator::load_virt(VirtConfig {
+                memsz: 1024 * 1024 * 4,
+                kernel: write_temp_file(name, &image),
+                initrd: None,
+                bootargs: String::new(),
+                uart: Uart::new(None, Box::new(io::sink())),
+                disk: None,
+            });
+            assert_eq!(
+                result.err().map(|err| err.kind()),
+                Some(io::ErrorKind::InvalidData)
+            );
+        }
+    }
+
+    #[test]
+    fn device_tree_from_devices() {
+        let mut rv64sg_emulator = load_program(&[]);
+        let uart = Uart::new(None, Box::new(io::sink()));
+        rv64sg_emulator.add_device(UART_BASE, UART_SIZE, Some(UART_IRQ), Box::new(uart));
+        rv64sg_emulator
+            .load_device_tree(0x8000, "console=ttyS0", None)
+            .unwrap();
+        assert_eq!(rv64sg_emulator.registers[11], 0x8000);
+        assert_eq!(rv64sg_emulator.bus.read(0x8000, 4), Ok(0xedfe0dd0));
+
+        let path = std::env::temp_dir().join("udy-cream-devices.dtb");
+        let path = path.to_str().unwrap();
+        rv64sg_emulator.dump_device_tree(path).unwrap();
+        let dtb = std::fs::read(path).unwrap();
+        assert_eq!(
+            u32::from_be_bytes(dtb[4..8].try_into().unwrap()) as usize,
+            dtb.len()
+        );
+        let contains = |needle: &[u8]| dtb.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"memory@0\0"));
+        assert!(contains(b"serial@10000000\0"));
+        assert!(contains(b"plic@c000000\0"));
+        assert!(contains(b"/soc/serial@10000000\0"));
+        assert!(contains(b"rv64imafdc_zicsr_zifencei\0"));
+        assert!(!contains(b"virtio_mmio"));
+    }
+
+    #[test]
+    fn linux_user_syscalls() {
+        let program: Vec<u8> = [
+            0xf9c00513, // addi a0, zero, -100
+            0x01013583, // ld a1, 16(sp)
+            0x24100613, // addi a2, zero, 0x241
+            0x1a400693, // addi a3, zero, 0x1a4
+            0x03800893, // addi a7, zero, 56
+            0x00000073, // ecall
+            0x00050413, // addi s0, a0, 0
+            0x01813583, // ld a1, 24(sp)
+            0x00500613, // addi a2, zero, 5
+            0x04000893, // addi a7, zero, 64
+            0x00000073, // ecall
+            0x00040513, // addi a0, s0, 0
+            0x03900893, // addi a7, zero, 57
+            0x00000073, // ecall
+            0x00013503, // ld a0, 0(sp)
+            0x05e00893, // addi a7, zero, 94
+            0x00000073, // ecall
+        ]
+        .iter()
+        .flat_map(|instruction: &u32| instruction.to_le_bytes())
+        .collect();
+        let path = write_temp_file(
+            "user.elf",
+            &build_elf(2, 0x1_0000, 0x1_0000, &program, 0x1000),
+        );
+        let output = write_temp_file("user.out", b"");
+        let args = [path.clone(), output.clone(), "hello".to_string()];
+
+        let mut rv64sg_emulator =
+            Rv64SGEmulator::load_linux_user(1024 * 1024 * 16, &path, &args, &[]).unwrap();
+        assert!(rv64sg_emulator.mode == MachineMode::U);
+        assert_eq!(rv64sg_emulator.registers[2] % 16, 0);
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(3));
+        assert_eq!(std::fs::read(&output).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn linux_user_rejects_huge_buffers() {
+        let path = write_temp_file(
+            "huge.elf",
+            &build_elf(2, 0x1_0000, 0x1_0000, &0x0000006fu32.to_le_bytes(), 0x1000),
+        );
+        let mut rv64sg_emulator =
+            Rv64SGEmulator::load_linux_user(1024 * 1024 * 16, &path, &[], &[]).unwrap();
+
+        // ゲストのバッファがメモリに無い場合は確保する前にエラーにする。
+        let getrandom = [0x2_0000, 1 << 40, 0, 0, 0, 0];
+        assert_eq!(rv64sg_emulator.syscall(278, &getrandom), Err(14));
+        let mmap_fixed = [0, 1 << 40, 3, 0x32, u64::MAX, 0];
+        assert_eq!(rv64sg_emulator.syscall(222, &mmap_fixed), Err(12));
+        let mmap_overflow = [0, u64::MAX - 1, 3, 0x22, u64::MAX, 0];
+        assert_eq!(rv64sg_emulator.syscall(222, &mmap_overflow), Err(12));
+
+        let mmap = [0, 0x2000, 3, 0x22, u64::MAX, 0];
+        let address = rv64sg_emulator.syscall(222, &mmap).unwrap();
+        assert_eq!(
+            rv64sg_emulator.syscall(278, &[address, 0x2000, 0, 0, 0, 0]),
+            Ok(0x2000)
+        );
+
+        // bufが不正なreadはファイルの内容を読み捨てない。
+        let input = write_temp_file("read.txt", b"abc");
+        let mut filename = input.into_bytes();
+        filename.push(0);
+        rv64sg_emulator.bus.write_bytes(address, &filename).unwrap();
+        let fd = rv64sg_emulator
+            .syscall(56, &[-100i64 as u64, address, 0, 0, 0, 0])
+            .unwrap();
+        assert_eq!(
+            rv64sg_emulator.syscall(63, &[fd, 1 << 40, 3, 0, 0, 0]),
+            Err(14)
+        );
+        assert_eq!(
+            rv64sg_emulator.syscall(63, &[fd, address, 16, 0, 0, 0]),
+            Ok(3)
+        );
+        assert_eq!(rv64sg_emulator.bus.read_bytes(address, 3).unwrap(), b"abc");
+        assert_eq!(rv64sg_emulator.syscall(62, &[0, 0, 0, 0, 0, 0]), Err(29));
+    }
+
+    #[test]
+    fn proxy_kernel_syscalls() {
+        let mut rv64sg_emulator = load_program(&[
+            0x40000513, // addi a0, zero, 0x400
+            0x24100593, // addi a1, zero, 0x241
+            0x1a400613, // addi a2, zero, 0x1a4
+            0x40000893, // addi a7, zero, 1024
+            0x00000073, // ecall
+            0x00050413, // addi s0, a0, 0
+            0x50000593, // addi a1, zero, 0x500
+            0x00500613, // addi a2, zero, 5
+            0x04000893, // addi a7, zero, 64
+            0x00000073, // ecall
+            0x00040513, // addi a0, s0, 0
+            0x03900893, // addi a7, zero, 57
+            0x00000073, // ecall
+            0x60000293, // addi t0, zero, 0x600
+            0x70000313, // addi t1, zero, 0x700
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // jal zero, 0
+        ]);
+        let output = write_temp_file("pk.out", b"");
+        let mut path = output.as_bytes().to_vec();
+        path.push(0);
+        let bus = &mut rv64sg_emulator.bus;
+        bus.write_bytes(0x400, &path).unwrap();
+        bus.write_bytes(0x500, b"hello").unwrap();
+        // exit(7)
+        bus.write_bytes(0x600, &93u64.to_le_bytes()).unwrap();
+        bus.write_bytes(0x608, &7u64.to_le_bytes()).unwrap();
+        rv64sg_emulator.set_htif(0x700, Some(0x708));
+        rv64sg_emulator.enable_proxy_kernel();
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(7));
+        assert_eq!(std::fs::read(&output).unwrap(), b"hello");
+        assert_eq!(rv64sg_emulator.load_memory_64bit(0x600).unwrap(), 0);
+        assert_eq!(rv64sg_emulator.load_memory_64bit(0x708).unwrap(), 1);
+    }
+
+    #[test]
+    fn semihosting_file_and_exit() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00100513, // addi a0, zero, 1
+            0x60000593, // addi a1, zero, 0x600
+            0x01f01013, // slli zero, zero, 0x1f
+            0x00100073, // ebreak
+            0x40705013, // srai zero, zero, 7
+            0x04a5b023, // sd a0, 0x40(a1)
+            0x00500513, // addi a0, zero, 5
+            0x64000593, // addi a1, zero, 0x640
+            0x01f01013, // slli zero, zero, 0x1f
+            0x00100073, // ebreak
+            0x40705013, // srai zero, zero, 7
+            0x01500513, // addi a0, zero, 0x15
+            0x68000593, // addi a1, zero, 0x680
+            0x01f01013, // slli zero, zero, 0x1f
+            0x00100073, // ebreak
+            0x40705013, // srai zero, zero, 7
+            0x01800513, // addi a0, zero, 0x18
+            0x6c000593, // addi a1, zero, 0x6c0
+            0x01f01013, // slli zero, zero, 0x1f
+            0x00100073, // ebreak
+            0x40705013, // srai zero, zero, 7
+            0x0000006f, // j .
+        ]);
+        let output = write_temp_file("semihosting.out", b"");
+        let bus = &mut rv64sg_emulator.bus;
+        bus.write_bytes(0x400, output.as_bytes()).unwrap();
+        bus.write_bytes(0x500, b"hello").unwrap();
+        for (address, value) in [
+            // SYS_OPEN: 名前、モード(w)、名前の長さ
+            (0x600, 0x400),
+            (0x608, 4),
+            (0x610, output.len() as u64),
+            // SYS_WRITE: ハンドル(SYS_OPENの結果)、バッファ、長さ
+            (0x648, 0x500),
+            (0x650, 5),
+            // SYS_GET_CMDLINE: バッファ、長さ
+            (0x680, 0x700),
+            (0x688, 64),
+            // SYS_EXIT: ADP_Stopped_ApplicationExit、終了コード
+            (0x6c0, 0x20026),
+            (0x6c8, 3),
+        ] {
+            bus.write_bytes(address, &value.to_le_bytes()).unwrap();
+        }
+        rv64sg_emulator.enable_semihosting("prog arg");
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(3));
+        assert_eq!(std::fs::read(&output).unwrap(), b"hello");
+        assert_eq!(rv64sg_emulator.load_memory_64bit(0x640).unwrap(), 1);
+        assert_eq!(
+            rv64sg_emulator.load_memory_64bit(0x700).unwrap(),
+            0x6772_6120_676f_7270
+        );
+        assert_eq!(rv64sg_emulator.load_memory_64bit(0x688).unwrap(), 8);
+    }
+
+    #[test]
+    fn semihosting_read_huge_length() {
+        let mut rv64sg_emulator = load_program(&[]);
+        let input = write_temp_file("semihosting.in", b"abc");
+        let bus = &mut rv64sg_emulator.bus;
+        bus.write_bytes(0x400, input.as_bytes()).unwrap();
+        for (address, value) in [
+            // SYS_OPEN: 名前、モード(r)、名前の長さ
+            (0x600, 0x400),
+            (0x608, 0),
+            (0x610, input.len() as u64),
+            // SYS_READ: バッファ、長さ
+            (0x648, 0x800),
+            (0x650, 1 << 40),
+        ] {
+            bus.write_bytes(address, &value.to_le_bytes()).unwrap();
+        }
+        rv64sg_emulator.enable_semihosting("");
+
+        rv64sg_emulator.registers[10] = 1;
+        rv64sg_emulator.registers[11] = 0x600;
+        rv64sg_emulator.semihosting_call().unwrap();
+        let handle = rv64sg_emulator.registers[10];
+        rv64sg_emulator.save_memory_64bit(0x640, handle).unwrap();
+
+        // 長さの分だけ確保せず、ファイルの終わりまで読んで残りのバイト数を返す。
+        rv64sg_emulator.registers[10] = 6;
+        rv64sg_emulator.registers[11] = 0x640;
+        rv64sg_emulator.semihosting_call().unwrap();
+        assert_eq!(rv64sg_emulator.registers[10], (1 << 40) - 3);
+        assert_eq!(rv64sg_emulator.bus.read_bytes(0x800, 3).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn ebreak_raises_breakpoint() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00001337, // lui t1, 0x1
+            0x01400293, // li t0, 0x14
+            0x30529073, // csrw mtvec, t0
+            0x00100073, // ebreak
+            0x0000006f, // j .
+            0x342022f3, // csrr t0, mcause
+            0x00129293, // slli t0, t0, 1
+            0x0012e293, // ori t0, t0, 1
+            0x00533023, // sd t0, 0(t1)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+        rv64sg_emulator.enable_semihosting("");
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Fail(3));
+        assert_eq!(rv64sg_emulator.csrs[M_EPC], 0xc);
+        assert_eq!(rv64sg_emulator.csrs[M_TVAL], 0xc);
+    }
+
+    // GDBの代わりにパケットを送り、応答を返す。
+    fn gdb_request(stream: &mut std::net::TcpStream, packet: &str) -> String {
+        use std::io::Read;
+
+        let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'+' if reply.is_empty() => {}
+                b'#' => break,
+                byte => reply.push(byte),
+            }
+        }
+        stream.read_exact(&mut [0; 2]).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn debug_memory_access_has_no_side_effects() {
+        let mut rv64sg_emulator = load_program(&[]);
+        let pte = |ppn: u64, flags: u64| ((ppn << 10) | flags).to_le_bytes();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x1000, &pte(0x2, 0x1))
+            .unwrap();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x2000, &pte(0x3, 0x1))
+            .unwrap();
+        // VA 0x5000 -> PA 0x8000 (R/W, A/Dは0)
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x3028, &pte(0x8, 0x7))
+            .unwrap();
+        rv64sg_emulator.bus.write_bytes(0x8010, b"abcd").unwrap();
+        rv64sg_emulator.csrs[S_ATP] = (8 << 60) | 0x1;
+        rv64sg_emulator.mode = MachineMode::S;
+        let uart = Uart::new(None, Box::new(io::sink()));
+        rv64sg_emulator.add_device(UART_BASE, UART_SIZE, Some(UART_IRQ), Box::new(uart));
+
+        // A/Dビットを立てず、TLBも使わない。
+        assert_eq!(
+            rv64sg_emulator.debug_read_memory(0x5010, 4),
+            Some(b"abcd".to_vec())
+        );
+        assert_eq!(rv64sg_emulator.debug_write_memory(0x5012, b"x"), Some(()));
+        assert_eq!(rv64sg_emulator.bus.read_bytes(0x8010, 4).unwrap(), b"abxd");
+        assert_eq!(rv64sg_emulator.bus.read(0x3028, 1), Ok(0x07));
+        assert_eq!(
+            rv64sg_emulator.tlb_stats().1,
+            TlbStats { hits: 0, misses: 0 }
+        );
+
+        // デバイスのレジスタは読み書きしない。
+        rv64sg_emulator.mode = MachineMode::M;
+        assert_eq!(rv64sg_emulator.debug_read_memory(UART_BASE, 1), None);
+        assert_eq!(
+            rv64sg_emulator.debug_write_memory(UART_BASE + 7, b"x"),
+            None
+        );
+        assert_eq!(rv64sg_emulator.bus.read(UART_BASE + 7, 1), Ok(0));
+    }
+
+    #[test]
+    fn gdb_step_breakpoint_and_watchpoint() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00500293, // li t0, 5
+            0x20000313, // li t1, 0x200
+            0x00533023, // sd t0, 0(t1)
+            0x00100393, // li t2, 1
+            0x00001e37, // lui t3, 0x1
+            0x007e3023, // sd t2, 0(t3)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            let mut replies = Vec::new();
+            for packet in [
+                "qXfer:features:read:target.xml:0,40",
+                "?",
+                "s",
+                "p20",
+                "p5",
+                "P6=3412000000000000",
+                "p6",
+                "Z2,200,8",
+                "c",
+                "p20",
+                "z2,200,8",
+                "Z0,14,4",
+                "c",
+                "m200,8",
+                "p1041",
+                "c",
+            ] {
+                replies.push(gdb_request(&mut stream, packet));
+            }
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let exit_status = rv64sg_emulator.gdb_session(Box::new(stream)).unwrap();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+
+        let replies = client.join().unwrap();
+        assert!(replies[0].starts_with("m<?xml"));
+        assert_eq!(
+            &replies[1..],
+            [
+                "S05",
+                "S05",
+                "0400000000000000",
+                "0500000000000000",
+                "OK",
+                "3412000000000000",
+                "OK",
+                "T05watch:200;",
+                "0c00000000000000",
+                "OK",
+                "OK",
+                "T05swbreak:;",
+                "0500000000000000",
+                "0300000000000000",
+                "W00",
+            ]
+        );
+    }
+
+    #[test]
+    fn monitor_breakpoint_and_memory() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00500293, // li t0, 5
+            0x20000313, // li t1, 0x200
+            0x00533023, // sd t0, 0(t1)
+            0x00100393, // li t2, 1
+            0x00001e37, // lui t3, 0x1
+            0x007e3023, // sd t2, 0(t3)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+        rv64sg_emulator.symbols.insert("store".to_string(), 0x8);
+
+        let mut input = io::Cursor::new("b store\nc\nset t0 0x7\ns\n\nx 0x200 8\nc\n");
+        let mut output = Vec::new();
+        let exit_status = rv64sg_emulator
+            .exec_with_monitor(&mut input, &mut output)
+            .unwrap();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.contains("breakpoint at 0x8\n0x0000000000000008: 00533023  sd      t0, 0(t1)")
+        );
+        assert!(output.contains("0x0000000000000010: 00001e37  lui     t3, 0x1"));
+        assert!(output.contains("0x0000000000000200: 07 00 00 00 00 00 00 00"));
+        assert!(output.contains("exited: Pass"));
+    }
+
+    #[test]
+    fn monitor_examines_memory_without_side_effects() {
+        let mut rv64sg_emulator = load_program(&[]);
+        let pte = |ppn: u64, flags: u64| ((ppn << 10) | flags).to_le_bytes();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x1000, &pte(0x2, 0x1))
+            .unwrap();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x2000, &pte(0x3, 0x1))
+            .unwrap();
+        // VA 0x5000 -> PA 0x8000 (実行のみ), VA 0x6000 -> PA 0x9000 (R/W)
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x3028, &pte(0x8, 0x9))
+            .unwrap();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x3030, &pte(0x9, 0x7))
+            .unwrap();
+        rv64sg_emulator
+            .bus
+            .write_bytes(0x8000, &0x00500293u32.to_le_bytes())
+            .unwrap();
+        rv64sg_emulator.bus.write_bytes(0x9000, b"abcd").unwrap();
+        rv64sg_emulator.csrs[S_ATP] = (8 << 60) | 0x1;
+        rv64sg_emulator.mode = MachineMode::S;
+        rv64sg_emulator.pc = 0x5000;
+
+        let mut input = io::Cursor::new("x 0x6000 4\ndisas 0x5000 1\nq\n");
+        let mut output = Vec::new();
+        rv64sg_emulator
+            .exec_with_monitor(&mut input, &mut output)
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("0x0000000000006000: 61 62 63 64"));
+        assert!(output.contains("=> 0x0000000000005000: 00500293  li      t0, 5"));
+        // PTEのAビットもTLBの統計も変わらない。
+        assert_eq!(rv64sg_emulator.bus.read(0x3028, 1), Ok(0x09));
+        assert_eq!(rv64sg_emulator.bus.read(0x3030, 1), Ok(0x07));
+        assert_eq!(
+            rv64sg_emulator.tlb_stats(),
+            (
+                TlbStats { hits: 0, misses: 0 },
+                TlbStats { hits: 0, misses: 0 }
+            )
+        );
+    }
+
+    #[test]
+    fn disassemble_instructions() {
+        let cases: &[(u32, &str)] = &[
+            (0x00000013, "nop"),
+            (0xff010113, "addi    sp, sp, -16"),
+            (0x00533023, "sd      t0, 0(t1)"),
+            (0x00008067, "ret"),
+            (0x34202573, "csrr    a0, mcause"),
+            (0x30529073, "csrw    mtvec, t0"),
+            (0x00050463, "beqz    a0, 0x1008"),
+            (0xff9ff06f, "j       0xff8"),
+            (0x02c5f553, "fadd.d  fa0, fa1, fa2"),
+            (0x0cb6252f, "amoswap.w.aq a0, a1, (a2)"),
+            (0x4501, "li      a0, 0"),
+            (0x8082, "ret"),
+            (0x1141, "addi    sp, sp, -16"),
+            (0xe406, "sd      ra, 8(sp)"),
+            (0x0000, ".2byte 0x0000"),
+            (0xffffffff, ".4byte 0xffffffff"),
+        ];
+
+        for (instruction, text) in cases {
+            assert_eq!(disassemble(*instruction, 0x1000), *text);
+        }
+    }
+
+    #[test]
+    fn objdump_executable_segments() {
+        let data = [
+            0x13, 0x05, 0x10, 0x00, // li a0, 1
+            0x82, 0x80, // ret
+        ];
+        let path = write_temp_file(
+            "objdump.elf",
+            &build_elf(2, 0x8000_0000, 0x8000_0000, &data, 6),
+        );
+
+        let mut output = Vec::new();
+        objdump(&path, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("80000000:\t00100513\tli      a0, 1\n"));
+        assert!(output.contains("80000004:\t8082    \tret\n"));
+    }
+
+    #[test]
+    fn trace_commit_log() {
+        let mut rv64sg_emulator = load_program(&[
+            0x00500293, // li t0, 5
+            0x20000313, // li t1, 0x200
+            0x00533023, // sd t0, 0(t1)
+            0x00033383, // ld t2, 0(t1)
+            0x34029073, // csrw mscratch, t0
+            0x00100393, // li t2, 1
+            0x00001e37, // lui t3, 0x1
+            0x007e3023, // sd t2, 0(t3)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+        let path = write_temp_file("trace.log", &[]);
+        rv64sg_emulator.enable_trace(&path).unwrap();
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::Pass);
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines[..2],
+            [
+                "core   0: 0x0000000000000000 (0x00500293) li      t0, 5",
+                "core   0: 3 0x0000000000000000 (0x00500293) x5  0x0000000000000005",
+            ]
+        );
+        assert!(lines.contains(
+            &"core   0: 3 0x0000000000000008 (0x00533023) mem 0x0000000000000200 0x0000000000000005"
+        ));
+        assert!(lines.contains(
+            &"core   0: 3 0x000000000000000c (0x00033383) x7  0x0000000000000005 mem 0x0000000000000200"
+        ));
+        assert!(lines.contains(
+            &"core   0: 3 0x0000000000000010 (0x34029073) c832_mscratch 0x0000000000000005"
+        ));
+        assert_eq!(
+            lines.last(),
+            Some(&"core   0: 3 0x000000000000001c (0x007e3023) mem 0x0000000000001000 0x0000000000000001")
+        );
+    }
+
+    #[test]
+    fn parse_spike_commit_log() {
+        let line = "core   0: 1 0x0000000080000010 (0x4501) x10 0x0000000000000000 \
+                    c768_mstatus 0x0000000a00000000 mem 0x0000000080001000 \
+                    mem 0x0000000080001008 0x0000002a";
+        let commit = Commit::parse(line).unwrap();
+        assert_eq!(commit.mode, 1);
+        assert_eq!(commit.pc, 0x8000_0010);
+        assert_eq!(commit.instruction, 0x4501);
+        assert_eq!(
+            commit.writes,
+            [(Register::X(10), 0), (Register::Csr(0x300), 0xa_0000_0000)]
+        );
+        assert_eq!(commit.loads, [0x8000_1000]);
+        assert_eq!(commit.stores, [(0x8000_1008, 4, 0x2a)]);
+        assert_eq!(
+            commit.to_string(),
+            "core   0: 1 0x0000000080000010 (0x4501) x10 0x0000000000000000 \
+             c768_mstatus 0x0000000a00000000 mem 0x0000000080001000 \
+             mem 0x0000000080001008 0x0000002a"
+        );
+
+        assert!(Commit::parse("core   0: 0x0000000080000010 (0x4501) li      a0, 0").is_none());
+        assert!(Commit::parse("core   0: exception trap_illegal_instruction, epc 0x0").is_none());
+    }
+
+    #[test]
+    fn lockstep_against_reference_trace() {
+        let program = [
+            0x00500293, // li t0, 5
+            0x20000313, // li t1, 0x200
+            0x00533023, // sd t0, 0(t1)
+            0x00100393, // li t2, 1
+            0x00001e37, // lui t3, 0x1
+            0x007e3023, // sd t2, 0(t3)
+            0x0000006f, // j .
+        ];
+        // spikeのブートROMの命令は読み飛ばす。
+        let reference = "\
+core   0: 0x0000000000001000 (0x00000297) auipc   t0, 0x0
+core   0: 3 0x0000000000001000 (0x00000297) x5  0x0000000000001000
+core   0: 3 0x0000000000000000 (0x00500293) x5  0x0000000000000005
+core   0: 3 0x0000000000000004 (0x20000313) x6  0x0000000000000200
+core   0: 3 0x0000000000000008 (0x00533023) mem 0x0000000000000200 0x0000000000000005
+";
+
+        let mut rv64sg_emulator = load_program(&program);
+        let mut output = Vec::new();
+        let outcome = rv64sg_emulator
+            .exec_with_reference(&mut io::Cursor::new(reference), &mut output)
+            .unwrap();
+        assert_eq!(outcome, DiffOutcome::Matched(3));
+        assert!(output.is_empty());
+
+        // tohostに書き込んだ後の命令は比べない。
+        let full = reference.to_string()
+            + "core   0: 3 0x000000000000000c (0x00100393) x7  0x0000000000000001\n\
+               core   0: 3 0x0000000000000010 (0x00001e37) x28 0x0000000000001000\n\
+               core   0: 3 0x0000000000000014 (0x007e3023) mem 0x0000000000001000 0x0000000000000001\n\
+               core   0: 3 0x0000000000000018 (0x0000006f)\n";
+        let mut rv64sg_emulator = load_program(&program);
+        rv64sg_emulator.set_htif(0x1000, None);
+        let outcome = rv64sg_emulator
+            .exec_with_reference(&mut io::Cursor::new(full), &mut output)
+            .unwrap();
+        assert_eq!(outcome, DiffOutcome::Exited(ExitReason::Pass, 6));
+
+        let mut rv64sg_emulator = load_program(&program);
+        let diverged = reference.replace("x6  0x0000000000000200", "x6  0x0000000000000300");
+        let outcome = rv64sg_emulator
+            .exec_with_reference(&mut io::Cursor::new(diverged), &mut output)
+            .unwrap();
+        assert_eq!(outcome, DiffOutcome::Diverged(2));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("divergence at instruction 2: x6 is 0x200 but reference has 0x300"));
+        assert!(output.contains("  0x0000000000000004: 20000313  li      t1, 512"));
+    }
+
+    #[test]
+    fn fuzz_random_programs() {
+        let config = FuzzConfig {
+            seed: 0,
+            programs: 500,
+            length: 64,
+            steps: 1000,
+        };
+        let mut output = Vec::new();
+        assert_eq!(fuzz(&config, &mut output).unwrap(), None);
+        assert_eq!(output, b"500 programs passed\n");
+    }
+
+    #[test]
+    fn fuzz_found_programs() {
+        // 予約されている丸めモードの命令、MPPに2を書いた後のmret、
+        // shamt[5]が1のsraiwはパニックしていた。
+        for program in [
+            vec![
+                0xf331e0cf, // fnmadd.d ft1, ft3, fs3, ft10, rm=6
+            ],
+            vec![
+                0x43935d1b, // sraiw s10, t1, 25 (funct7 = 0x21)
+            ],
+            vec![
+                0x423dd81b, // sraiw a6, s11, 3 (funct7 = 0x21)
+            ],
+            vec![
+                0x300f9473, // csrrw fp, mstatus, t6
+                0x30200073, // mret
+            ],
+        ] {
+            let case = FuzzCase {
+                seed: 50093,
+                program,
+            };
+            assert_eq!(case.run(1000), Ok(()));
+        }
+    }
+
+    #[test]
+    fn riscof_signature() {
+        let mut rv64sg_emulator = load_program(&[
+            0x000012b7, // lui t0, 0x1
+            0x10000313, // li t1, 0x100
+            0x123453b7, // lui t2, 0x12345
+            0x67838393, // addi t2, t2, 0x678
+            0x00732023, // sw t2, 0(t1)
+            0xfff00393, // li t2, -1
+            0x00732223, // sw t2, 4(t1)
+            0x00100393, // li t2, 1
+            0x0072b023, // sd t2, 0(t0)
+            0x0000006f, // j .
+        ]);
+        rv64sg_emulator.set_htif(0x1000, None);
+        let mut output = Vec::new();
+        assert!(rv64sg_emulator.write_signature(&mut output, 4).is_err());
+
+        rv64sg_emulator
+            .symbols
+            .insert("begin_signature".to_string(), 0x100);
+        rv64sg_emulator
+            .symbols
+            .insert("end_signature".to_string(), 0x10c);
+        assert_eq!(rv64sg_emulator.exec_program().reason, ExitReason::Pass);
+
+        rv64sg_emulator.write_signature(&mut output, 4).unwrap();
+        assert_eq!(output, b"12345678\nffffffff\n00000000\n");
+        output.clear();
+        rv64sg_emulator.write_signature(&mut output, 8).unwrap();
+        assert_eq!(output, b"ffffffff12345678\n0000000000000000\n");
+    }
+
+    #[test]
+    fn instruction_limit_and_raw_image() {
+        let image = write_temp_file(
+            "loop.bin",
+            &[
+                0x93, 0x82, 0x12, 0x00, // addi t0, t0, 1
+                0xf5, 0xbf, // j .-4
+            ],
+        );
+        let mut rv64sg_emulator = load_program(&[]);
+        rv64sg_emulator.load_image(0x100, &image).unwrap();
+        assert!(rv64sg_emulator.load_image(0x1_0000, &image).is_err());
+        rv64sg_emulator.set_entry(0x100);
+        rv64sg_emulator.set_instruction_limit(10);
+
+        let exit_status = rv64sg_emulator.exec_program();
+        assert_eq!(exit_status.reason, ExitReason::InstructionLimit);
+        assert_eq!(rv64sg_emulator.registers[5], 5);
     }
 }
@@ -0,0 +1,286 @@
+use std::{collections::HashMap, fs, io};
+
+use super::{clint::TIMEBASE_FREQUENCY, Rv64SGEmulator, M_HARTID};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+// 予約領域は終端のエントリのみ
+const FDT_RSVMAP_SIZE: usize = 16;
+
+pub const CPU_INTC_PHANDLE: u32 = 1;
+pub const PLIC_PHANDLE: u32 = 2;
+
+// エミュレータが実装している拡張
+const RISCV_ISA: &str = "rv64imafdc_zicsr_zifencei";
+const MMU_TYPE: &str = "riscv,sv48";
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+// Flattened Device Treeを組み立てる。
+// begin_node/end_nodeの間にプロパティを書き込み、最後にfinishでDTBのバイト列にする。
+// 値は全てビッグエンディアンで書き込む。
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        FdtWriter {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend(value.to_be_bytes());
+    }
+
+    // 構造ブロックは4バイト境界に揃える。
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    // プロパティ名は文字列ブロックに1度だけ書き込み、そのオフセットを使い回す。
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+
+        let offset = self.strings.len() as u32;
+        self.strings.extend(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    // ルートノードの名前は空文字列にする。
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "fdt: end_node without begin_node");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(offset);
+        self.structure.extend(value);
+        self.align();
+    }
+
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    // #address-cells = <2>, #size-cells = <2>のノードのreg
+    pub fn property_reg(&mut self, address: u64, size: u64) {
+        self.property_cells(
+            "reg",
+            &[
+                (address >> 32) as u32,
+                address as u32,
+                (size >> 32) as u32,
+                size as u32,
+            ],
+        );
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    // 複数の文字列はそれぞれNUL終端して並べる。
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        assert!(self.depth == 0, "fdt: unclosed node");
+        self.push_u32(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + FDT_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let totalsize = off_dt_strings + self.strings.len();
+
+        let mut dtb = Vec::with_capacity(totalsize);
+        for value in [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            dtb.extend(value.to_be_bytes());
+        }
+        dtb.extend([0; FDT_RSVMAP_SIZE]);
+        dtb.extend(self.structure);
+        dtb.extend(self.strings);
+
+        dtb
+    }
+}
+
+impl Rv64SGEmulator {
+    // 接続されているデバイスからデバイスツリーを作る関数
+    // RAMはルートに、その他のデバイスはsocの下に置き、PLICにつながるデバイスにはinterruptsを付ける。
+    // initrdは(開始アドレス, 終了アドレス)で指定する。
+    pub fn build_device_tree(&self, bootargs: &str, initrd: Option<(u64, u64)>) -> Vec<u8> {
+        let devices: Vec<_> = self
+            .bus
+            .devices()
+            .filter_map(|(base, size, irq, device)| {
+                device
+                    .fdt_name()
+                    .map(|name| (format!("{}@{:x}", name, base), base, size, irq, device))
+            })
+            .collect();
+        let is_memory = |name: &str| name.starts_with("memory@");
+
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "riscv-virtio");
+        fdt.property_string("model", "udy-cream");
+
+        fdt.begin_node("chosen");
+        fdt.property_string("bootargs", bootargs);
+        if let Some((name, ..)) = devices
+            .iter()
+            .find(|(name, ..)| name.starts_with("serial@"))
+        {
+            fdt.property_string("stdout-path", &format!("/soc/{}", name));
+        }
+        if let Some((start, end)) = initrd {
+            fdt.property_u64("linux,initrd-start", start);
+            fdt.property_u64("linux,initrd-end", end);
+        }
+        fdt.end_node();
+
+        for (name, base, size, _, device) in devices.iter().filter(|(name, ..)| is_memory(name)) {
+            fdt.begin_node(name);
+            device.fdt_properties(&mut fdt);
+            fdt.property_reg(*base, *size);
+            fdt.end_node();
+        }
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+        fdt.begin_node(&format!("cpu@{:x}", self.csrs[M_HARTID]));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", self.csrs[M_HARTID] as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", RISCV_ISA);
+        fdt.property_string("mmu-type", MMU_TYPE);
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+        for (name, base, size, irq, device) in devices.iter().filter(|(name, ..)| !is_memory(name))
+        {
+            fdt.begin_node(name);
+            device.fdt_properties(&mut fdt);
+            fdt.property_reg(*base, *size);
+            if let Some(irq) = irq {
+                fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+                fdt.property_u32("interrupts", *irq);
+            }
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.finish()
+    }
+
+    // デバイスツリーを作ってaddressに置き、ブートの規約に従ってa1にそのアドレスを入れる。
+    pub fn load_device_tree(
+        &mut self,
+        address: u64,
+        bootargs: &str,
+        initrd: Option<(u64, u64)>,
+    ) -> io::Result<()> {
+        let dtb = self.build_device_tree(bootargs, initrd);
+        self.bus.write_bytes(address, &dtb).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "fdt: device tree {:#x}-{:#x} is outside of memory",
+                    address,
+                    address + dtb.len() as u64
+                ),
+            )
+        })?;
+        self.registers[11] = address;
+        self.device_tree = Some(dtb);
+
+        Ok(())
+    }
+
+    // load_device_treeで置いたデバイスツリーをファイルに書き出す。
+    pub fn dump_device_tree(&self, filename: &str) -> io::Result<()> {
+        let dtb = self.device_tree.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "fdt: device tree is not loaded")
+        })?;
+        fs::write(filename, dtb)
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    fmt,
+    io::{self, Write},
+    panic::{self, AssertUnwindSafe},
+    sync::Once,
+};
+
+use super::{
+    debug::CSR_NAMES,
+    disasm::{disassemble, instruction_length, is_decodable},
+    trace::Trace,
+    MachineMode, Rv64SGEmulator, M_CAUSE, M_EPC, M_TVEC, S_CAUSE, S_EPC, S_TVEC,
+};
+
+// 命令列を置くRAM
+const RAM_BASE: u64 = 0x8000_0000;
+const RAM_SIZE: usize = 1024 * 64;
+
+// 定義されている例外と割り込みの原因の番号
+const EXCEPTION_CAUSES: [u64; 14] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 11, 12, 13, 15];
+const INTERRUPT_CAUSES: [u64; 6] = [1, 3, 5, 7, 9, 11];
+
+// 制約付きで生成するときに選ぶRV64GCのメジャーオペコード
+const OPCODES: [u32; 21] = [
+    0x03, 0x07, 0x0f, 0x13, 0x17, 0x1b, 0x23, 0x27, 0x2f, 0x33, 0x37, 0x3b, 0x43, 0x47, 0x4b, 0x4f,
+    0x53, 0x63, 0x67, 0x6f, 0x73,
+];
+// funct3が0のSYSTEM命令(ecall、ebreak、sret、mret、wfi、sfence.vma)
+// 決まった値なのでランダムなビットからは滅多に出ない。
+const SFENCE_VMA: u32 = 0x12000073;
+const SYSTEM_INSTRUCTIONS: [u32; 6] = [
+    0x00000073, 0x00100073, 0x10200073, 0x30200073, 0x10500073, SFENCE_VMA,
+];
+// デコードできる命令が出るまで生成し直す回数
+const RETRIES: usize = 16;
+
+thread_local! {
+    // 検査中のパニックは表示せずに場所だけ記録する。
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+static PANIC_HOOK: Once = Once::new();
+
+pub struct FuzzConfig {
+    // i番目のプログラムはseed + iをシードにして生成する。
+    pub seed: u64,
+    pub programs: u64,
+    // 1つのプログラムの命令数
+    pub length: usize,
+    // 1つのプログラムを実行する最大のステップ数
+    pub steps: usize,
+}
+
+// 生成したプログラム
+// 初期状態のレジスタもseedから決まるので、seedとprogramがあれば同じ実行を再現できる。
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzCase {
+    pub seed: u64,
+    pub program: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FuzzError {
+    // エミュレータがパニックした場合
+    Panic { location: String, message: String },
+    // 命令が完了せず、正しく例外も起こらなかった場合
+    InvalidTrap(String),
+}
+
+impl FuzzError {
+    // 最小化で同じ失敗とみなすかどうか。パニックは場所が同じものだけを同じとする。
+    fn same_kind(&self, other: &FuzzError) -> bool {
+        match (self, other) {
+            (
+                FuzzError::Panic { location, .. },
+                FuzzError::Panic {
+                    location: other, ..
+                },
+            ) => location == other,
+            (FuzzError::InvalidTrap(_), FuzzError::InvalidTrap(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for FuzzError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FuzzError::Panic { location, message } => {
+                write!(f, "panicked at {}: {}", location, message)
+            }
+            FuzzError::InvalidTrap(message) => write!(f, "invalid trap: {}", message),
+        }
+    }
+}
+
+// xorshift64*による疑似乱数
+struct Rng(u64);
+
+impl Rng {
+    // 近いシードでも違う列になるようにsplitmix64で混ぜてから使う。
+    fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Rng((z ^ (z >> 31)) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+// 4分の1はC拡張の命令にし、残りは下位2ビットだけを11にした32ビットの値にする。
+fn random_instruction(rng: &mut Rng) -> u32 {
+    let bits = rng.next_u64() as u32;
+    if rng.below(4) == 0 {
+        let half = bits & 0xffff;
+        if half & 0x3 == 0x3 {
+            half & !1
+        } else {
+            half
+        }
+    } else {
+        bits | 0x3
+    }
+}
+
+// オペコードとCSRの番号を実在するものから選び、デコードできる命令になるまで生成し直す。
+// RETRIES回でデコードできる命令が出ない場合は最後の値を使う。
+fn constrained_instruction(rng: &mut Rng) -> u32 {
+    let mut instruction = 0;
+    for _ in 0..RETRIES {
+        instruction = random_instruction(rng);
+        if instruction_length(instruction) == 4 {
+            let opcode = OPCODES[rng.below(OPCODES.len() as u64) as usize];
+            instruction = (instruction & !0x7f) | opcode;
+            if opcode == 0x73 && (instruction >> 12) & 0x7 == 0 {
+                instruction =
+                    SYSTEM_INSTRUCTIONS[rng.below(SYSTEM_INSTRUCTIONS.len() as u64) as usize];
+                // sfence.vmaはrs1とrs2をランダムにする。
+                if instruction == SFENCE_VMA {
+                    instruction |= rng.next_u64() as u32 & 0x01ff8000;
+                }
+            } else if opcode == 0x73 {
+                let (_, csr) = CSR_NAMES[rng.below(CSR_NAMES.len() as u64) as usize];
+                instruction = (instruction & 0xfffff) | (csr as u32) << 20;
+            }
+        }
+        if is_decodable(instruction) {
+            break;
+        }
+    }
+
+    instruction
+}
+
+fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING.with(|catching| catching.get()) {
+                let location = info.location().map(|location| location.to_string());
+                PANIC_LOCATION.with(|last| *last.borrow_mut() = location);
+            } else {
+                previous(info);
+            }
+        }));
+    });
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .unwrap_or_default(),
+    }
+}
+
+impl FuzzCase {
+    // 偶数のシードは完全にランダムな命令列、奇数のシードは制約付きの命令列にする。
+    pub fn generate(seed: u64, length: usize) -> Self {
+        let mut rng = Rng::new(seed);
+        let program = (0..length)
+            .map(|_| {
+                if seed & 1 == 0 {
+                    random_instruction(&mut rng)
+                } else {
+                    constrained_instruction(&mut rng)
+                }
+            })
+            .collect();
+
+        FuzzCase { seed, program }
+    }
+
+    // 最大stepsステップ実行し、パニックしたか正しくない例外が起きた場合はその内容を返す。
+    pub fn run(&self, steps: usize) -> Result<(), FuzzError> {
+        install_panic_hook();
+        CATCHING.with(|catching| catching.set(true));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.check(steps)));
+        CATCHING.with(|catching| catching.set(false));
+
+        result.unwrap_or_else(|payload| {
+            Err(FuzzError::Panic {
+                location: PANIC_LOCATION
+                    .with(|last| last.borrow_mut().take())
+                    .unwrap_or_default(),
+                message: panic_message(payload),
+            })
+        })
+    }
+
+    // 命令列をRAM_BASEから詰めて置いた状態で作り、命令列の終わりのアドレスも返す。
+    // ロード・ストアが例外だけにならないように、ほとんどのレジスタはRAMの中を指すようにする。
+    fn load(&self) -> (Rv64SGEmulator, u64) {
+        let mut rv64sg_emulator = Rv64SGEmulator::new(RAM_BASE, 0, RAM_BASE, RAM_SIZE);
+        let mut address = RAM_BASE;
+        for instruction in self.program.iter() {
+            let length = instruction_length(*instruction);
+            rv64sg_emulator
+                .bus
+                .write_bytes(address, &instruction.to_le_bytes()[..length as usize])
+                .unwrap();
+            address += length;
+        }
+
+        let mut rng = Rng::new(!self.seed);
+        for register in rv64sg_emulator.registers.iter_mut().skip(1) {
+            *register = if rng.below(4) == 0 {
+                rng.next_u64()
+            } else {
+                RAM_BASE + (rng.below(RAM_SIZE as u64) & !0x7)
+            };
+        }
+        for register in rv64sg_emulator.f_registers.iter_mut() {
+            *register = rng.next_u64();
+        }
+
+        rv64sg_emulator.trace = Some(Trace::new(None));
+        rv64sg_emulator.report_not_implemented = false;
+        rv64sg_emulator.initialize_csrs();
+        (rv64sg_emulator, address)
+    }
+
+    // 1ステップごとに命令が完了したか、正しく例外が起きたかを確かめる。
+    // 例外が起きた場合はトラップハンドラの代わりに命令列の次の命令から実行を続け、
+    // 命令列の終わりに着くかゲストが終了した場合は止める。
+    fn check(&self, steps: usize) -> Result<(), FuzzError> {
+        let (mut rv64sg_emulator, end) = self.load();
+        let program = RAM_BASE..end;
+        let mut resume = RAM_BASE;
+
+        for _ in 0..steps {
+            let pc = rv64sg_emulator.pc;
+            if pc == end {
+                break;
+            }
+            if program.contains(&pc) {
+                let low = rv64sg_emulator
+                    .load_physical_memory(pc, 2)
+                    .unwrap_or_default();
+                resume = pc + instruction_length(low as u32);
+            }
+
+            if rv64sg_emulator.step().is_some() {
+                break;
+            }
+            if rv64sg_emulator.take_commit().is_none() {
+                let cause = rv64sg_emulator.check_trap(pc)?;
+                // 割り込みの場合は割り込まれた命令から続ける。
+                rv64sg_emulator.pc = if cause >> 63 == 1 { pc } else { resume };
+            }
+        }
+
+        Ok(())
+    }
+
+    // 同じ種類の失敗が起きる間は命令を1つずつ取り除いていき、小さくしたプログラムとその失敗を返す。
+    pub fn minimize(&self, error: &FuzzError, steps: usize) -> (FuzzCase, FuzzError) {
+        let mut case = self.clone();
+        let mut error = error.clone();
+        loop {
+            let mut removed = false;
+            for i in (0..case.program.len()).rev() {
+                let mut candidate = case.clone();
+                candidate.program.remove(i);
+                if let Err(candidate_error) = candidate.run(steps) {
+                    if candidate_error.same_kind(&error) {
+                        case = candidate;
+                        error = candidate_error;
+                        removed = true;
+                    }
+                }
+            }
+            if !removed {
+                return (case, error);
+            }
+        }
+    }
+}
+
+impl Rv64SGEmulator {
+    // pcの命令で例外か割り込みが起きた後の状態が正しいか確かめ、原因の値を返す関数
+    // 原因が定義されているもので、epcがpcを指し、pcがtvecの飛び先になっている必要がある。
+    fn check_trap(&self, pc: u64) -> Result<u64, FuzzError> {
+        let (cause, epc, tvec) = match self.mode {
+            MachineMode::M => (self.csrs[M_CAUSE], self.csrs[M_EPC], self.csrs[M_TVEC]),
+            MachineMode::S => (self.csrs[S_CAUSE], self.csrs[S_EPC], self.csrs[S_TVEC]),
+            MachineMode::U => {
+                return Err(FuzzError::InvalidTrap(format!(
+                    "instruction at {:#x} neither retired nor trapped",
+                    pc
+                )))
+            }
+        };
+
+        let interrupt = cause >> 63 == 1;
+        let code = cause & !(1 << 63);
+        let valid = if interrupt {
+            INTERRUPT_CAUSES.contains(&code)
+        } else {
+            EXCEPTION_CAUSES.contains(&code)
+        };
+        if !valid {
+            return Err(FuzzError::InvalidTrap(format!(
+                "cause {:#x} at {:#x} is not defined",
+                cause, pc
+            )));
+        }
+        if epc != pc & !1 {
+            return Err(FuzzError::InvalidTrap(format!(
+                "epc is {:#x} but the trapped instruction is at {:#x}",
+                epc, pc
+            )));
+        }
+
+        let target = if tvec & 0x3 == 1 && interrupt {
+            (tvec & !0x3) + 4 * code
+        } else {
+            tvec & !0x3
+        };
+        if self.pc != target {
+            return Err(FuzzError::InvalidTrap(format!(
+                "pc is {:#x} after the trap at {:#x} but tvec points to {:#x}",
+                self.pc, pc, target
+            )));
+        }
+
+        Ok(cause)
+    }
+}
+
+// config.programs個のプログラムを生成して実行し、最初に失敗したプログラムを最小化して返す関数
+// 失敗した場合は最小化したプログラムを逆アセンブルしてoutputに表示する。
+pub fn fuzz(
+    config: &FuzzConfig,
+    output: &mut dyn Write,
+) -> io::Result<Option<(FuzzCase, FuzzError)>> {
+    for i in 0..config.programs {
+        let case = FuzzCase::generate(config.seed.wrapping_add(i), config.length);
+        if let Err(error) = case.run(config.steps) {
+            writeln!(output, "seed {}: {}", case.seed, error)?;
+            let (case, error) = case.minimize(&error, config.steps);
+            writeln!(
+                output,
+                "minimized to {} instructions: {}",
+                case.program.len(),
+                error
+            )?;
+            let mut address = RAM_BASE;
+            for instruction in case.program.iter() {
+                writeln!(
+                    output,
+                    "  {:#x}: {:08x}  {}",
+                    address,
+                    instruction,
+                    disassemble(*instruction, address)
+                )?;
+                address += instruction_length(*instruction);
+            }
+            return Ok(Some((case, error)));
+        }
+    }
+
+    writeln!(output, "{} programs passed", config.programs)?;
+    Ok(None)
+}
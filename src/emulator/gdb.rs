@@ -0,0 +1,470 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+};
+
+use super::{
+    debug::{StopReason, WatchKind, ABI_NAMES, CSR_NAMES, FPR_ABI_NAMES, SIGKILL},
+    ExitReason, ExitStatus, MachineMode, Rv64SGEmulator,
+};
+
+// GDBのレジスタ番号
+const REGNUM_PC: usize = 32;
+const REGNUM_FIRST_FPR: usize = 33;
+const REGNUM_FIRST_CSR: usize = 65;
+const REGNUM_PRIV: usize = REGNUM_FIRST_CSR + 4096;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const PACKET_SIZE: usize = 0x4000;
+
+const INTERRUPT: u8 = 0x03;
+
+// GDBとの接続に使うストリーム
+// 実行中にCtrl-Cを受け取るため、ノンブロッキングにできるものに限る。
+pub(super) trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+// 受け取ったパケットに対する動作
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+// レジスタの値はターゲットのバイト順(リトルエンディアン)の16進数にする。
+fn encode_register(value: u64) -> String {
+    encode_hex(&value.to_le_bytes())
+}
+
+fn decode_register(hex: &str) -> Option<u64> {
+    let bytes: [u8; 8] = decode_hex(hex)?.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+// "addr,len"の形式を読む。
+fn parse_range(args: &str) -> Option<(u64, u64)> {
+    let (address, length) = args.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+// GDBが読み込むターゲットの記述
+// レジスタ番号はGDBのRISC-Vの番号(CSRは65 + CSR番号)に合わせる。
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\">\
+         <architecture>riscv:rv64</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (i, name) in ABI_NAMES.iter().enumerate() {
+        let kind = match i {
+            2 => "data_ptr",
+            1 => "code_ptr",
+            _ => "int",
+        };
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>",
+            name, kind, i
+        );
+    }
+    xml += &format!(
+        "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/></feature>",
+        REGNUM_PC
+    );
+
+    xml += "<feature name=\"org.gnu.gdb.riscv.fpu\">";
+    for (i, name) in FPR_ABI_NAMES.iter().enumerate() {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"ieee_double\" regnum=\"{}\"/>",
+            name,
+            REGNUM_FIRST_FPR + i
+        );
+    }
+    let (fpu_csrs, csrs): (Vec<_>, Vec<_>) = CSR_NAMES.iter().partition(|(_, csr)| *csr <= 0x003);
+    for (name, csr) in fpu_csrs {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>",
+            name,
+            REGNUM_FIRST_CSR + csr
+        );
+    }
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.csr\">";
+    for (name, csr) in csrs {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>",
+            name,
+            REGNUM_FIRST_CSR + csr
+        );
+    }
+    xml += &format!(
+        "</feature><feature name=\"org.gnu.gdb.riscv.virtual\">\
+         <reg name=\"priv\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>\
+         </feature></target>",
+        REGNUM_PRIV
+    );
+
+    xml
+}
+
+// GDB Remote Serial Protocolのパケットの送受信
+struct GdbStub {
+    stream: Box<dyn Connection>,
+    // 実行中にCtrl-Cを確認したときに読んでしまったバイト
+    pending: VecDeque<u8>,
+    no_ack: bool,
+}
+
+impl GdbStub {
+    fn new(stream: Box<dyn Connection>) -> Self {
+        GdbStub {
+            stream,
+            pending: VecDeque::new(),
+            no_ack: false,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(byte);
+        }
+
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // パケットの中身を返す。チェックサムが合わない場合は再送を要求する。
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            // '$'の前にある応答(+/-)やCtrl-Cは読み捨てる。
+            while self.read_byte()? != b'$' {}
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(expected);
+
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", data, checksum);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            self.stream.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+
+            // '-'が返ってきた場合は再送する。
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // 実行中に呼ばれ、Ctrl-Cが届いていればtrueを返す。
+    // 接続が切れた場合も中断する。
+    fn poll_interrupt(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+
+        let mut buf = [0; 64];
+        let interrupted = match self.stream.read(&mut buf) {
+            Ok(0) => true,
+            Ok(n) => {
+                self.pending
+                    .extend(buf[..n].iter().filter(|byte| **byte != INTERRUPT));
+                buf[..n].contains(&INTERRUPT)
+            }
+            Err(err) => err.kind() != io::ErrorKind::WouldBlock,
+        };
+
+        self.stream.set_nonblocking(false).is_err() || interrupted
+    }
+}
+
+impl Rv64SGEmulator {
+    // GDBの接続を待ち、GDBの指示に従って実行する関数
+    // addressが"unix:"で始まる場合はUnixドメインソケット、それ以外はTCPのアドレスとして待ち受ける。
+    pub fn exec_with_gdb(&mut self, address: &str) -> io::Result<ExitStatus> {
+        let stream: Box<dyn Connection> = match address.strip_prefix("unix:") {
+            Some(path) => {
+                let listener = UnixListener::bind(path)?;
+                eprintln!("gdb: waiting for connection on {}", address);
+                Box::new(listener.accept()?.0)
+            }
+            None => {
+                let listener = TcpListener::bind(address)?;
+                eprintln!("gdb: waiting for connection on {}", listener.local_addr()?);
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+        };
+
+        self.gdb_session(stream)
+    }
+
+    // 接続したGDBからのパケットを処理する関数
+    // ゲストが終了するか、GDBがkillした場合に終了する。detachした場合は最後まで実行する。
+    pub(super) fn gdb_session(&mut self, stream: Box<dyn Connection>) -> io::Result<ExitStatus> {
+        self.initialize_csrs();
+        let mut stub = GdbStub::new(stream);
+        let mut stop = StopReason::Step;
+
+        loop {
+            let packet = match stub.read_packet() {
+                Ok(packet) => packet,
+                // 応答せずに切断された場合はkillと同じく終了する。
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(self.exit_status(ExitReason::Fail(SIGKILL)));
+                }
+                Err(err) => return Err(err),
+            };
+
+            match self.gdb_command(&packet, &stop) {
+                Action::Reply(reply) => {
+                    stub.send_packet(&reply)?;
+                    if packet == "QStartNoAckMode" {
+                        stub.no_ack = true;
+                    }
+                }
+                Action::Resume { step } => {
+                    stop = if step {
+                        self.debug_step().unwrap_or(StopReason::Step)
+                    } else {
+                        self.debug_continue(|| stub.poll_interrupt())
+                    };
+                    stub.send_packet(&stop_reply(&stop))?;
+                    if let StopReason::Exited(reason) = stop {
+                        return Ok(self.exit_status(reason));
+                    }
+                }
+                Action::Detach => {
+                    stub.send_packet("OK")?;
+                    return Ok(self.exec_program());
+                }
+                Action::Kill => return Ok(self.exit_status(ExitReason::Fail(SIGKILL))),
+            }
+        }
+    }
+
+    // 1つのパケットを解釈する。対応していないパケットには空の応答を返す。
+    fn gdb_command(&mut self, packet: &str, stop: &StopReason) -> Action {
+        let reply =
+            |reply: Option<String>| Action::Reply(reply.unwrap_or_else(|| "E01".to_string()));
+        let ok = |result: Option<()>| reply(result.map(|_| "OK".to_string()));
+
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        match command {
+            "?" => Action::Reply(stop_reply(stop)),
+            "g" => Action::Reply(
+                (0..=REGNUM_PC)
+                    .map(|regnum| encode_register(self.gdb_register(regnum).unwrap()))
+                    .collect(),
+            ),
+            "G" => ok((0..=REGNUM_PC).try_for_each(|regnum| {
+                let value = decode_register(args.get(regnum * 16..regnum * 16 + 16)?)?;
+                self.set_gdb_register(regnum, value)
+            })),
+            "p" => reply(
+                parse_hex(args)
+                    .and_then(|regnum| self.gdb_register(regnum as usize))
+                    .map(encode_register),
+            ),
+            "P" => ok(args.split_once('=').and_then(|(regnum, value)| {
+                self.set_gdb_register(parse_hex(regnum)? as usize, decode_register(value)?)
+            })),
+            "m" => reply(parse_range(args).and_then(|(address, length)| {
+                let length = length.min(PACKET_SIZE as u64 / 2);
+                self.debug_read_memory(address, length)
+                    .map(|data| encode_hex(&data))
+            })),
+            "M" => ok(args.split_once(':').and_then(|(range, data)| {
+                let (address, length) = parse_range(range)?;
+                let data = decode_hex(data)?;
+                if data.len() as u64 != length {
+                    return None;
+                }
+                self.debug_write_memory(address, &data)
+            })),
+            "c" | "s" => {
+                if let Some(address) = parse_hex(args) {
+                    self.pc = address;
+                }
+                Action::Resume {
+                    step: command == "s",
+                }
+            }
+            "Z" | "z" => ok(self.gdb_breakpoint(command == "Z", args)),
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            // スレッドはハート0の1つだけ
+            "H" | "T" => Action::Reply("OK".to_string()),
+            _ => Action::Reply(self.gdb_query(packet).unwrap_or_default()),
+        }
+    }
+
+    fn gdb_query(&self, packet: &str) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            return Some(format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            ));
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, length) = parse_range(args)?;
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(length as usize).min(xml.len());
+            let marker = if end == xml.len() { "l" } else { "m" };
+            return Some(format!("{}{}", marker, &xml[start..end]));
+        }
+
+        match packet {
+            "QStartNoAckMode" => Some("OK".to_string()),
+            "qAttached" => Some("1".to_string()),
+            "qC" => Some("QC1".to_string()),
+            "qfThreadInfo" => Some("m1".to_string()),
+            "qsThreadInfo" => Some("l".to_string()),
+            _ => None,
+        }
+    }
+
+    // Z/zパケット: "種類,アドレス,長さ"
+    // 0(ソフトウェア)と1(ハードウェア)のブレークポイントは区別しない。
+    fn gdb_breakpoint(&mut self, insert: bool, args: &str) -> Option<()> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let address = parse_hex(fields.next()?)?;
+        let length = parse_hex(fields.next()?.split(';').next()?)?;
+
+        let watch_kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+                return Some(());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return None,
+        };
+
+        if insert {
+            self.debugger.add_watchpoint(address, length, watch_kind);
+        } else {
+            self.debugger.remove_watchpoint(address, length, watch_kind);
+        }
+        Some(())
+    }
+
+    fn gdb_register(&mut self, regnum: usize) -> Option<u64> {
+        match regnum {
+            0..=31 => Some(self.registers[regnum]),
+            REGNUM_PC => Some(self.pc),
+            REGNUM_FIRST_FPR..=64 => Some(self.f_registers[regnum - REGNUM_FIRST_FPR]),
+            REGNUM_PRIV => Some(self.mode.to_usize() as u64),
+            REGNUM_FIRST_CSR..REGNUM_PRIV => self.debug_read_csr(regnum - REGNUM_FIRST_CSR),
+            _ => None,
+        }
+    }
+
+    // 特権モードはU/S/Mのいずれかにのみ変更できる。
+    fn set_gdb_register(&mut self, regnum: usize, value: u64) -> Option<()> {
+        match regnum {
+            0 => {}
+            1..=31 => self.registers[regnum] = value,
+            REGNUM_PC => self.pc = value,
+            REGNUM_FIRST_FPR..=64 => self.f_registers[regnum - REGNUM_FIRST_FPR] = value,
+            REGNUM_PRIV => self.mode = MachineMode::from_u64(value)?,
+            REGNUM_FIRST_CSR..REGNUM_PRIV => {
+                return self.debug_write_csr(regnum - REGNUM_FIRST_CSR, value)
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
+
+// 止まった理由をGDBの停止応答にする。
+fn stop_reply(stop: &StopReason) -> String {
+    match stop {
+        StopReason::Step => format!("S{:02x}", SIGTRAP),
+        StopReason::Breakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+        StopReason::Watchpoint(kind, address) => {
+            let name = match kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, name, address)
+        }
+        StopReason::Interrupted => format!("S{:02x}", SIGINT),
+        StopReason::Exited(ExitReason::Fail(code)) => format!("W{:02x}", code & 0xff),
+        StopReason::Exited(_) => "W00".to_string(),
+    }
+}
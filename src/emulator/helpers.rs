@@ -270,7 +270,7 @@ pub fn c_extract_offset_11_4_9_8_10_6_7_3_1_5(instruction: &Vec<u8>) -> u64 {
         + (((instruction[0] as u64) & 0x80) >> 1)
         + (((instruction[0] as u64) & 0x4) << 3)
         + (((instruction[1] as u64) & 0x8) << 1)
-        + (((instruction[0] as u64) & 0x18) >> 2)
+        + (((instruction[0] as u64) & 0x38) >> 2)
 }
 
 pub fn extend_sign_6bit(value: u64) -> u64 {
@@ -288,3 +288,18 @@ pub fn extend_sign_10bit(value: u64) -> u64 {
 pub fn extend_sign_18bit(value: u64) -> u64 {
     (value + 0x7fffffffffff2000) ^ 0x7fffffffffff2000
 }
+
+// 例外
+// 不正な命令のtvalに入れる値(C拡張の場合は下位16bitのみ)
+pub fn extract_instruction_bits(instruction: &Vec<u8>) -> u64 {
+    if instruction[0] & 0x3 == 0x3 {
+        u32::from_le_bytes([
+            instruction[0],
+            instruction[1],
+            instruction[2],
+            instruction[3],
+        ]) as u64
+    } else {
+        u16::from_le_bytes([instruction[0], instruction[1]]) as u64
+    }
+}
@@ -0,0 +1,89 @@
+use super::{ExitReason, Rv64SGEmulator};
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+
+const CMD_CONSOLE_PUTCHAR: u64 = 1;
+
+// Host-Target InterFace
+// ゲストがtohostに書き込んだコマンドを処理し、必要であればfromhostに応答を返す。
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    console: Vec<u8>,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>) -> Self {
+        Htif {
+            tohost,
+            fromhost,
+            console: Vec::new(),
+        }
+    }
+
+    pub fn console(&self) -> &[u8] {
+        &self.console
+    }
+}
+
+impl Rv64SGEmulator {
+    // tohost/fromhostのアドレスを設定する関数
+    // ELFから読み込んだ場合はシンボルから自動的に設定される。
+    pub fn set_htif(&mut self, tohost: u64, fromhost: Option<u64>) {
+        self.htif = Some(Htif::new(tohost, fromhost));
+    }
+
+    // 1命令実行するごとに呼ばれ、tohostに書き込まれたコマンドを処理する。
+    // ゲストが終了を要求した場合はその理由を返す。
+    pub(super) fn check_tohost(&mut self) -> Option<ExitReason> {
+        let (tohost, fromhost) = match &self.htif {
+            Some(htif) => (htif.tohost, htif.fromhost),
+            None => return None,
+        };
+
+        let command = self.load_physical_memory(tohost, 8)?;
+        if command == 0 {
+            return None;
+        }
+        self.save_physical_memory(tohost, 8, 0)?;
+
+        let device = command >> 56;
+        let cmd = (command >> 48) & 0xff;
+        let payload = command & 0xffff_ffff_ffff;
+
+        match (device, cmd) {
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => {
+                return match payload >> 1 {
+                    0 => Some(ExitReason::Pass),
+                    code => Some(ExitReason::Fail(code)),
+                };
+            }
+            // 最下位ビットが0の場合はmagic memoryのアドレスで、完了したらfromhostに1を返す。
+            (DEVICE_SYSCALL, 0) if self.is_proxy_kernel() => {
+                self.magic_mem_syscall(payload);
+                if let Some(fromhost) = fromhost {
+                    self.save_physical_memory(fromhost, 8, 1);
+                }
+                return None;
+            }
+            (DEVICE_CONSOLE, CMD_CONSOLE_PUTCHAR) => {
+                if let Some(htif) = &mut self.htif {
+                    htif.console.push(payload as u8);
+                }
+            }
+            _ => {
+                eprintln!(
+                    "htif: unsupported command device: {:x} cmd: {:x} payload: {:x}",
+                    device, cmd, payload
+                );
+            }
+        }
+
+        if let Some(fromhost) = fromhost {
+            self.save_physical_memory(fromhost, 8, (device << 56) | (cmd << 48));
+        }
+
+        None
+    }
+}
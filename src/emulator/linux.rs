@@ -0,0 +1,691 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{elf::ElfFile, ExitReason, MachineMode, Rv64SGEmulator};
+
+const SYS_IOCTL: u64 = 29;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETPID: u64 = 172;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_GETRANDOM: u64 = 278;
+
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ENOSYS: i64 = 38;
+const EIO: i64 = 5;
+
+pub(super) const AT_FDCWD: i64 = -100;
+const O_ACCMODE: u64 = 0x3;
+const O_WRONLY: u64 = 0x1;
+const O_RDWR: u64 = 0x2;
+const O_CREAT: u64 = 0x40;
+const O_EXCL: u64 = 0x80;
+const O_TRUNC: u64 = 0x200;
+const O_APPEND: u64 = 0x400;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const CLOCK_REALTIME: u64 = 0;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+const PAGE_SIZE: u64 = 4096;
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+// ELF64のプログラムヘッダの大きさ
+const PHENT_SIZE: u64 = 56;
+// IMAFDCの各ビット
+const HWCAP: u64 = (1 << 8) | (1 << 12) | (1 << 0) | (1 << 5) | (1 << 3) | (1 << 2);
+// struct statの大きさ
+const STAT_SIZE: usize = 128;
+// struct utsnameの各フィールドの大きさ
+const UTSNAME_LENGTH: usize = 65;
+// readで一度にホストのファイルから読み込む大きさ
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+fn align_up(value: u64) -> Option<u64> {
+    value
+        .checked_add(PAGE_SIZE - 1)
+        .map(|value| value & !(PAGE_SIZE - 1))
+}
+
+// ゲストのファイルディスクリプタが指すホストのファイル
+pub(super) enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+impl HostFile {
+    pub(super) fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Stdin => io::stdin().read(buf),
+            Self::File(file) => file.read(buf),
+            _ => Err(io::Error::from_raw_os_error(EBADF as i32)),
+        }
+    }
+
+    pub(super) fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = match self {
+            Self::Stdout => io::stdout().write(buf)?,
+            Self::Stderr => io::stderr().write(buf)?,
+            Self::File(file) => file.write(buf)?,
+            Self::Stdin => return Err(io::Error::from_raw_os_error(EBADF as i32)),
+        };
+        if let Self::Stdout = self {
+            io::stdout().flush()?;
+        }
+
+        Ok(written)
+    }
+}
+
+// Linuxのユーザープロセスとして実行する場合の状態
+// 仮想記憶は使わず、仮想アドレスをそのまま物理アドレスとして扱う。
+pub struct LinuxUser {
+    files: HashMap<u64, HostFile>,
+    brk_start: u64,
+    brk: u64,
+    // mmapで確保した領域の下端。ここから下に向かって確保する。
+    mmap_bottom: u64,
+    started: Instant,
+    // riscv-pkと同じシステムコールをM-modeからも受け付ける。
+    proxy_kernel: bool,
+}
+
+impl LinuxUser {
+    fn new(brk: u64, mmap_top: u64) -> Self {
+        let files = HashMap::from([
+            (0, HostFile::Stdin),
+            (1, HostFile::Stdout),
+            (2, HostFile::Stderr),
+        ]);
+
+        LinuxUser {
+            files,
+            brk_start: brk,
+            brk,
+            mmap_bottom: mmap_top,
+            started: Instant::now(),
+            proxy_kernel: false,
+        }
+    }
+
+    pub(super) fn proxy_kernel(brk: u64, mmap_top: u64) -> Self {
+        LinuxUser {
+            proxy_kernel: true,
+            ..Self::new(brk, mmap_top)
+        }
+    }
+
+    pub(super) fn is_proxy_kernel(&self) -> bool {
+        self.proxy_kernel
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut HostFile, i64> {
+        self.files.get_mut(&fd).ok_or(EBADF)
+    }
+
+    fn allocate_fd(&mut self, file: HostFile) -> u64 {
+        let fd = (0..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        self.files.insert(fd, file);
+        fd
+    }
+}
+
+fn errno(err: io::Error) -> i64 {
+    err.raw_os_error().map_or(EIO, |errno| errno as i64)
+}
+
+impl Rv64SGEmulator {
+    // 静的リンクされたRISC-VのLinuxの実行ファイルをU-modeのプロセスとして読み込む関数
+    // RAMは0から配置し、スタックはRAMの末尾、mmapの領域はその下に置く。
+    // スタックにはargc、argv、envp、auxvをLinuxと同じ形式で積む。
+    pub fn load_linux_user(
+        memsz: usize,
+        filename: &str,
+        args: &[String],
+        envs: &[String],
+    ) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        File::open(filename)?.read_to_end(&mut buf)?;
+        let elf = ElfFile::parse(&buf)?;
+
+        let mut rv64sg_emulator = Rv64SGEmulator::new(elf.entry, 0, 0, memsz);
+        let mut end = 0;
+        let mut phdr = 0;
+        for segment in elf.segments.iter() {
+            let outside = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "linux: segment {:#x}-{:#x} is outside of memory",
+                        segment.vaddr,
+                        segment.vaddr.wrapping_add(segment.memsz)
+                    ),
+                )
+            };
+            if !rv64sg_emulator
+                .bus
+                .is_mapped(segment.vaddr, segment.memsz as usize)
+            {
+                return Err(outside());
+            }
+
+            let mut data = segment.data.clone();
+            data.resize(segment.memsz as usize, 0);
+            rv64sg_emulator
+                .bus
+                .write_bytes(segment.vaddr, &data)
+                .map_err(|_| outside())?;
+
+            end = end.max(segment.vaddr + segment.memsz);
+            // プログラムヘッダを含むセグメントからそのアドレスを求める。
+            if (segment.offset..segment.offset + segment.data.len() as u64).contains(&elf.phoff) {
+                phdr = segment.vaddr + (elf.phoff - segment.offset);
+            }
+        }
+
+        let stack_top = memsz as u64;
+        let (brk, mmap_top) = align_up(end)
+            .zip(stack_top.checked_sub(STACK_SIZE))
+            .filter(|&(brk, mmap_top)| mmap_top > brk)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "linux: memory is too small")
+            })?;
+
+        let auxv = [
+            (AT_PHDR, phdr),
+            (AT_PHENT, PHENT_SIZE),
+            (AT_PHNUM, elf.phnum as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, HWCAP),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+        ];
+        let sp = rv64sg_emulator
+            .setup_stack(stack_top, filename, args, envs, &auxv)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "linux: stack is outside of memory",
+                )
+            })?;
+
+        rv64sg_emulator.registers[2] = sp;
+        rv64sg_emulator.mode = MachineMode::U;
+        rv64sg_emulator.linux = Some(LinuxUser::new(brk, mmap_top));
+        rv64sg_emulator.symbols = elf.symbols;
+
+        Ok(rv64sg_emulator)
+    }
+
+    // スタックの上端から文字列、AT_RANDOMのバイト列、auxv、envp、argv、argcの順に積み、spを返す。
+    fn setup_stack(
+        &mut self,
+        stack_top: u64,
+        filename: &str,
+        args: &[String],
+        envs: &[String],
+        auxv: &[(u64, u64)],
+    ) -> Option<u64> {
+        let mut sp = stack_top;
+        let mut push_bytes = |bus: &mut super::bus::Bus, bytes: &[u8]| {
+            sp -= bytes.len() as u64;
+            bus.write_bytes(sp, bytes).ok().map(|_| sp)
+        };
+        let mut push_string = |bus: &mut super::bus::Bus, string: &str| {
+            let mut bytes = string.as_bytes().to_vec();
+            bytes.push(0);
+            push_bytes(bus, &bytes)
+        };
+
+        let execfn = push_string(&mut self.bus, filename)?;
+        let mut envp = Vec::new();
+        for env in envs.iter().rev() {
+            envp.push(push_string(&mut self.bus, env)?);
+        }
+        envp.reverse();
+        let mut argv = Vec::new();
+        for arg in args.iter().rev() {
+            argv.push(push_string(&mut self.bus, arg)?);
+        }
+        argv.reverse();
+
+        let mut random = [0; 16];
+        fill_random(&mut random);
+        let random = push_bytes(&mut self.bus, &random)?;
+
+        let mut words = vec![args.len() as u64];
+        words.extend(&argv);
+        words.push(0);
+        words.extend(&envp);
+        words.push(0);
+        for (key, value) in auxv {
+            words.extend([*key, *value]);
+        }
+        words.extend([AT_RANDOM, random, AT_EXECFN, execfn, AT_NULL, 0]);
+
+        // spは16バイト境界に揃える。
+        let sp = (sp - 8 * words.len() as u64) & !0xf;
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        self.bus.write_bytes(sp, &bytes).ok()?;
+
+        Some(sp)
+    }
+
+    pub(super) fn read_guest(&mut self, address: u64, size: u64) -> Result<Vec<u8>, i64> {
+        self.bus
+            .read_bytes(address, size as usize)
+            .map_err(|_| EFAULT)
+    }
+
+    pub(super) fn write_guest(&mut self, address: u64, data: &[u8]) -> Result<(), i64> {
+        self.bus.write_bytes(address, data).map_err(|_| EFAULT)
+    }
+
+    // ホスト側でバッファを確保する前に、ゲストのaddress..address + sizeがメモリに収まるか確かめる。
+    pub(super) fn check_guest(&self, address: u64, size: u64) -> Result<(), i64> {
+        if size == 0 || self.bus.is_mapped(address, size as usize) {
+            Ok(())
+        } else {
+            Err(EFAULT)
+        }
+    }
+
+    // NUL終端の文字列を読み出す。
+    fn read_guest_string(&mut self, address: u64) -> Result<String, i64> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self
+                .bus
+                .read(address + bytes.len() as u64, 1)
+                .map_err(|_| EFAULT)? as u8;
+            if byte == 0 {
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            bytes.push(byte);
+        }
+    }
+
+    fn linux_user(&mut self) -> &mut LinuxUser {
+        self.linux.as_mut().unwrap()
+    }
+
+    // ecallをシステムコールとして処理する関数
+    // a7がシステムコール番号、a0-a5が引数で、結果をa0に返す。失敗した場合は-errnoを返す。
+    pub(super) fn linux_syscall(&mut self) {
+        let number = self.registers[17];
+        let args = [
+            self.registers[10],
+            self.registers[11],
+            self.registers[12],
+            self.registers[13],
+            self.registers[14],
+            self.registers[15],
+        ];
+
+        let result = if self.linux_user().proxy_kernel {
+            self.pk_syscall(number, &args)
+        } else {
+            self.syscall(number, &args)
+        };
+        self.registers[10] = match result {
+            Ok(value) => value,
+            Err(errno) => (-errno) as u64,
+        };
+    }
+
+    // Linuxのシステムコールを実行し、結果か失敗した場合のerrnoを返す。
+    pub(super) fn syscall(&mut self, number: u64, args: &[u64; 6]) -> Result<u64, i64> {
+        match number {
+            SYS_READ => self.sys_read(args[0], args[1], args[2]),
+            SYS_WRITE => self.sys_write(args[0], args[1], args[2]),
+            SYS_READV => self.sys_iov(args[0], args[1], args[2], Self::sys_read),
+            SYS_WRITEV => self.sys_iov(args[0], args[1], args[2], Self::sys_write),
+            SYS_OPENAT => self.sys_openat(args[0], args[1], args[2], args[3]),
+            SYS_CLOSE => self
+                .linux_user()
+                .files
+                .remove(&args[0])
+                .map(|_| 0)
+                .ok_or(EBADF),
+            SYS_LSEEK => self.sys_lseek(args[0], args[1], args[2]),
+            SYS_FSTAT => self.sys_fstat(args[0], args[1]),
+            SYS_BRK => Ok(self.sys_brk(args[0])),
+            SYS_MMAP => self.sys_mmap(args[0], args[1], args[3], args[4], args[5]),
+            SYS_MUNMAP | SYS_MPROTECT => Ok(0),
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_reason = Some(match args[0] as u8 {
+                    0 => ExitReason::Pass,
+                    code => ExitReason::Fail(code as u64),
+                });
+                Ok(0)
+            }
+            SYS_CLOCK_GETTIME => self.sys_clock_gettime(args[0], args[1]),
+            SYS_GETRANDOM => {
+                self.check_guest(args[0], args[1])?;
+                let mut buf = vec![0; args[1] as usize];
+                fill_random(&mut buf);
+                self.write_guest(args[0], &buf).map(|_| args[1])
+            }
+            SYS_UNAME => self.sys_uname(args[0]),
+            // スレッドやシグナルは無いので、成功したことにする。
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(1),
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => Ok(0),
+            // 端末として扱わない。
+            SYS_IOCTL => Err(ENOTTY),
+            number => {
+                eprintln!("linux: unsupported syscall {}", number);
+                Err(ENOSYS)
+            }
+        }
+    }
+
+    // ゲストが指定した大きさをそのまま確保しないよう、READ_CHUNK_SIZEずつ読み込む。
+    // 要求より少なく読めた場合はそこで終える。
+    // bufが不正な場合にファイルの内容を読み捨てないよう、先にゲストの範囲を確かめる。
+    fn sys_read(&mut self, fd: u64, buf: u64, count: u64) -> Result<u64, i64> {
+        self.check_guest(buf, count)?;
+        let mut data = vec![0; count.min(READ_CHUNK_SIZE) as usize];
+        let mut total = 0;
+        while total < count {
+            let size = (count - total).min(READ_CHUNK_SIZE) as usize;
+            let read = self
+                .linux_user()
+                .file(fd)?
+                .read(&mut data[..size])
+                .map_err(errno)?;
+            self.write_guest(buf.wrapping_add(total), &data[..read])?;
+            total += read as u64;
+            if read < size {
+                break;
+            }
+        }
+
+        Ok(total)
+    }
+
+    fn sys_write(&mut self, fd: u64, buf: u64, count: u64) -> Result<u64, i64> {
+        let data = self.read_guest(buf, count)?;
+        let written = self.linux_user().file(fd)?.write(&data).map_err(errno)?;
+        Ok(written as u64)
+    }
+
+    // readv/writevはstruct iovecの配列を順に処理する。
+    fn sys_iov(
+        &mut self,
+        fd: u64,
+        iov: u64,
+        iovcnt: u64,
+        op: fn(&mut Self, u64, u64, u64) -> Result<u64, i64>,
+    ) -> Result<u64, i64> {
+        let mut total = 0;
+        for i in 0..iovcnt {
+            let base = self.bus.read(iov + 16 * i, 8).map_err(|_| EFAULT)?;
+            let len = self.bus.read(iov + 16 * i + 8, 8).map_err(|_| EFAULT)?;
+            let done = op(self, fd, base, len)?;
+            total += done;
+            if done < len {
+                break;
+            }
+        }
+
+        Ok(total)
+    }
+
+    // 相対パスはdirfdに関係なくホストのカレントディレクトリから開く。
+    pub(super) fn sys_openat(
+        &mut self,
+        dirfd: u64,
+        path: u64,
+        flags: u64,
+        mode: u64,
+    ) -> Result<u64, i64> {
+        let path = self.read_guest_string(path)?;
+        if dirfd as i64 != AT_FDCWD && !path.starts_with('/') {
+            return Err(EBADF);
+        }
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .mode(mode as u32);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+
+        let file = options.open(path).map_err(errno)?;
+        Ok(self.linux_user().allocate_fd(HostFile::File(file)))
+    }
+
+    fn sys_lseek(&mut self, fd: u64, offset: u64, whence: u64) -> Result<u64, i64> {
+        let position = match whence {
+            0 => SeekFrom::Start(offset),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(EINVAL),
+        };
+
+        match self.linux_user().file(fd)? {
+            HostFile::File(file) => file.seek(position).map_err(errno),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    // 標準入出力はキャラクタデバイスとして見せる。
+    fn sys_fstat(&mut self, fd: u64, statbuf: u64) -> Result<u64, i64> {
+        let mut stat = [0; STAT_SIZE];
+        let mut put = |offset: usize, value: u64, size: usize| {
+            stat[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+        };
+
+        match self.linux_user().file(fd)? {
+            HostFile::File(file) => {
+                let metadata = file.metadata().map_err(errno)?;
+                put(0, metadata.dev(), 8);
+                put(8, metadata.ino(), 8);
+                put(16, metadata.mode() as u64, 4);
+                put(20, metadata.nlink(), 4);
+                put(24, metadata.uid() as u64, 4);
+                put(28, metadata.gid() as u64, 4);
+                put(32, metadata.rdev(), 8);
+                put(48, metadata.size(), 8);
+                put(56, metadata.blksize(), 4);
+                put(64, metadata.blocks(), 8);
+                put(72, metadata.atime() as u64, 8);
+                put(80, metadata.atime_nsec() as u64, 8);
+                put(88, metadata.mtime() as u64, 8);
+                put(96, metadata.mtime_nsec() as u64, 8);
+                put(104, metadata.ctime() as u64, 8);
+                put(112, metadata.ctime_nsec() as u64, 8);
+            }
+            _ => {
+                put(16, 0o20620, 4);
+                put(20, 1, 4);
+                put(56, 1024, 4);
+            }
+        }
+
+        self.write_guest(statbuf, &stat)?;
+        Ok(0)
+    }
+
+    // 0や範囲外のアドレスを指定した場合は今のbrkを返す。
+    // 広げた部分は0で埋める。
+    fn sys_brk(&mut self, address: u64) -> u64 {
+        let linux = self.linux_user();
+        let (start, current, limit) = (linux.brk_start, linux.brk, linux.mmap_bottom);
+        if address < start || address > limit {
+            return current;
+        }
+
+        if address > current {
+            let zero = vec![0; (address - current) as usize];
+            if self.bus.write_bytes(current, &zero).is_err() {
+                return current;
+            }
+        }
+        self.linux_user().brk = address;
+        address
+    }
+
+    // 無名のマッピングとファイルの内容をコピーするプライベートなマッピングのみ対応する。
+    // MAP_FIXEDでない場合はmmapの領域を下に向かって確保し、munmapしても再利用しない。
+    fn sys_mmap(
+        &mut self,
+        address: u64,
+        length: u64,
+        flags: u64,
+        fd: u64,
+        offset: u64,
+    ) -> Result<u64, i64> {
+        if length == 0 {
+            return Err(EINVAL);
+        }
+        let length = align_up(length).ok_or(ENOMEM)?;
+
+        let address = if flags & MAP_FIXED != 0 {
+            address
+        } else {
+            let linux = self.linux_user();
+            linux
+                .mmap_bottom
+                .checked_sub(length)
+                .filter(|&address| address >= linux.brk)
+                .ok_or(ENOMEM)?
+        };
+        self.check_guest(address, length).map_err(|_| ENOMEM)?;
+        if flags & MAP_FIXED == 0 {
+            self.linux_user().mmap_bottom = address;
+        }
+
+        let mut data = vec![0; length as usize];
+        if flags & MAP_ANONYMOUS == 0 {
+            match self.linux_user().file(fd)? {
+                HostFile::File(file) => {
+                    let position = file.stream_position().map_err(errno)?;
+                    file.seek(SeekFrom::Start(offset)).map_err(errno)?;
+                    let mut read = 0;
+                    while read < data.len() {
+                        match file.read(&mut data[read..]).map_err(errno)? {
+                            0 => break,
+                            n => read += n,
+                        }
+                    }
+                    file.seek(SeekFrom::Start(position)).map_err(errno)?;
+                }
+                _ => return Err(EBADF),
+            }
+        }
+        self.write_guest(address, &data).map_err(|_| ENOMEM)?;
+
+        Ok(address)
+    }
+
+    // CLOCK_REALTIME以外は起動してからの時間を返す。
+    fn sys_clock_gettime(&mut self, clock: u64, tp: u64) -> Result<u64, i64> {
+        let time = match clock {
+            CLOCK_REALTIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            _ => self.linux_user().started.elapsed(),
+        };
+
+        let mut timespec = time.as_secs().to_le_bytes().to_vec();
+        timespec.extend((time.subsec_nanos() as u64).to_le_bytes());
+        self.write_guest(tp, &timespec)?;
+        Ok(0)
+    }
+
+    fn sys_uname(&mut self, buf: u64) -> Result<u64, i64> {
+        let mut utsname = Vec::new();
+        for field in ["Linux", "udy-cream", "6.1.0", "#1", "riscv64", "(none)"] {
+            let mut bytes = field.as_bytes().to_vec();
+            bytes.resize(UTSNAME_LENGTH, 0);
+            utsname.extend(bytes);
+        }
+
+        self.write_guest(buf, &utsname)?;
+        Ok(0)
+    }
+}
+
+// ホストの/dev/urandomから読む。読めない場合は時刻から作る。
+fn fill_random(buf: &mut [u8]) {
+    if let Ok(mut urandom) = File::open("/dev/urandom") {
+        if urandom.read_exact(buf).is_ok() {
+            return;
+        }
+    }
+
+    let mut state = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+        | 1;
+    for byte in buf.iter_mut() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        *byte = state as u8;
+    }
+}
@@ -0,0 +1,309 @@
+use super::trap::{Exception, Trap};
+use super::{MachineMode, Rv64SGEmulator, M_STATUS, S_ATP};
+
+const PAGE_SIZE: u64 = 4096;
+const PTE_SIZE: u64 = 8;
+
+const SATP_MODE_BARE: u64 = 0;
+const SATP_MODE_SV39: u64 = 8;
+const SATP_MODE_SV48: u64 = 9;
+
+const PTE_V: u64 = 0x1;
+const PTE_R: u64 = 0x2;
+const PTE_W: u64 = 0x4;
+const PTE_X: u64 = 0x8;
+const PTE_U: u64 = 0x10;
+const PTE_A: u64 = 0x40;
+const PTE_D: u64 = 0x80;
+
+const MSTATUS_MPRV: u64 = 1 << 17;
+const MSTATUS_SUM: u64 = 1 << 18;
+const MSTATUS_MXR: u64 = 1 << 19;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessType {
+    Instruction,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    fn page_fault(&self, vaddr: u64) -> Trap {
+        match self {
+            Self::Instruction => Exception::InstructionPageFault(vaddr),
+            Self::Load => Exception::LoadPageFault(vaddr),
+            Self::Store => Exception::StorePageFault(vaddr),
+        }
+        .into()
+    }
+
+    pub fn access_fault(&self, vaddr: u64) -> Trap {
+        match self {
+            Self::Instruction => Exception::InstructionAccessFault(vaddr),
+            Self::Load => Exception::LoadAccessFault(vaddr),
+            Self::Store => Exception::StoreAccessFault(vaddr),
+        }
+        .into()
+    }
+}
+
+// satpに書き込めるモードか判定する関数
+// 対応していないモードが書き込まれた場合はsatp全体の書き込みを無視する。
+pub fn is_supported_satp(value: u64) -> bool {
+    matches!(
+        value >> 60,
+        SATP_MODE_BARE | SATP_MODE_SV39 | SATP_MODE_SV48
+    )
+}
+
+// 葉のPTEが指すページ内のvaddrの物理アドレス
+fn leaf_address(pte: u64, level: u64, vaddr: u64) -> u64 {
+    let offset_mask = (1u64 << (12 + 9 * level)) - 1;
+    (((pte >> 10) & 0xfff_ffff_ffff) * PAGE_SIZE) | (vaddr & offset_mask)
+}
+
+impl Rv64SGEmulator {
+    // アクセスの種類に応じた実効的な特権モード
+    // ロード・ストアはmstatus.MPRVが立っている場合mstatus.MPPのモードで行う。
+    fn effective_mode(&self, access: AccessType) -> MachineMode {
+        let mstatus = self.csrs[M_STATUS];
+        if access != AccessType::Instruction
+            && self.mode == MachineMode::M
+            && mstatus & MSTATUS_MPRV != 0
+        {
+            MachineMode::from_u64((mstatus >> 11) & 0x3).unwrap_or(MachineMode::M)
+        } else {
+            self.mode
+        }
+    }
+
+    // 葉のPTEでアクセスが許可されているか判定する関数
+    fn is_permitted(&self, pte: u64, mode: MachineMode, access: AccessType) -> bool {
+        let mstatus = self.csrs[M_STATUS];
+        let permitted = match access {
+            AccessType::Instruction => pte & PTE_X != 0,
+            AccessType::Load => {
+                pte & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0)
+            }
+            AccessType::Store => pte & PTE_W != 0,
+        };
+        let user_page = pte & PTE_U != 0;
+        let privileged = match mode {
+            MachineMode::U => user_page,
+            _ => !user_page || (access != AccessType::Instruction && mstatus & MSTATUS_SUM != 0),
+        };
+
+        permitted && privileged
+    }
+
+    // 変換が必要な場合はページテーブルの段数と実効的な特権モードを返す関数
+    fn paging(&self, vaddr: u64, access: AccessType) -> Result<Option<(u64, MachineMode)>, Trap> {
+        let mode = self.effective_mode(access);
+        let levels = match self.csrs[S_ATP] >> 60 {
+            SATP_MODE_SV39 => 3,
+            SATP_MODE_SV48 => 4,
+            _ => return Ok(None),
+        };
+        if mode == MachineMode::M {
+            return Ok(None);
+        }
+
+        // 使わない上位ビットは最上位の有効ビットの符号拡張になっていなければならない。
+        let va_bits = 12 + 9 * levels;
+        let top = (vaddr as i64) >> (va_bits - 1);
+        if top != 0 && top != -1 {
+            return Err(access.page_fault(vaddr));
+        }
+
+        Ok(Some((levels, mode)))
+    }
+
+    // 仮想アドレスを物理アドレスに変換する関数
+    // 先にTLBを引き、ヒットしなかった場合やDビットを立てる必要がある場合はページテーブルを辿る。
+    pub(super) fn translate(&mut self, vaddr: u64, access: AccessType) -> Result<u64, Trap> {
+        let (levels, mode) = match self.paging(vaddr, access)? {
+            Some(paging) => paging,
+            None => return Ok(vaddr),
+        };
+
+        let satp = self.csrs[S_ATP];
+        let asid = (satp >> 44) & 0xffff;
+        let vpn = (vaddr >> 12) & ((1 << (9 * levels)) - 1);
+        let tlb = match access {
+            AccessType::Instruction => &mut self.fetch_tlb,
+            _ => &mut self.data_tlb,
+        };
+        if let Some(entry) = tlb.lookup(asid, vpn) {
+            if self.is_permitted(entry.pte, mode, access)
+                && (access != AccessType::Store || entry.pte & PTE_D != 0)
+            {
+                return Ok(entry.page_address(vpn) | (vaddr & (PAGE_SIZE - 1)));
+            }
+        }
+
+        let (pte, level) = self.walk_page_table(vaddr, levels, mode, access, false)?;
+        let tlb = match access {
+            AccessType::Instruction => &mut self.fetch_tlb,
+            _ => &mut self.data_tlb,
+        };
+        tlb.insert(asid, vpn, pte, level);
+
+        Ok(leaf_address(pte, level, vaddr))
+    }
+
+    // デバッガ用に仮想アドレスを今のモードで変換する関数
+    // ゲストの状態を変えないよう、TLBを使わずPTEのA/Dビットも更新しない。
+    pub(super) fn debug_translate(&mut self, vaddr: u64, access: AccessType) -> Option<u64> {
+        let (levels, mode) = match self.paging(vaddr, access).ok()? {
+            Some(paging) => paging,
+            None => return Some(vaddr),
+        };
+
+        let (pte, level) = self
+            .walk_page_table(vaddr, levels, mode, access, true)
+            .ok()?;
+        Some(leaf_address(pte, level, vaddr))
+    }
+
+    // ページテーブルを辿り、葉のPTEとその段数を返す関数
+    // 途中でメモリの外側を読んだ場合はアクセスフォールト、
+    // PTEが不正か権限が足りない場合はページフォールトを返す。
+    // debugの場合はPTEをRAMからのみ読み、A/Dビットを更新しない。
+    fn walk_page_table(
+        &mut self,
+        vaddr: u64,
+        levels: u64,
+        mode: MachineMode,
+        access: AccessType,
+        debug: bool,
+    ) -> Result<(u64, u64), Trap> {
+        let mut table = (self.csrs[S_ATP] & 0xfff_ffff_ffff) * PAGE_SIZE;
+        for level in (0..levels).rev() {
+            let vpn = (vaddr >> (12 + 9 * level)) & 0x1ff;
+            let pte_address = table + vpn * PTE_SIZE;
+            let pte = if debug {
+                self.debug_load_physical_memory(pte_address, 8)
+            } else {
+                self.load_physical_memory(pte_address, 8)
+            }
+            .ok_or_else(|| access.access_fault(vaddr))?;
+
+            // 上位10bit(PBMT, N)は対応していないので0でなければならない。
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte >> 54 != 0 {
+                return Err(access.page_fault(vaddr));
+            }
+
+            let ppn = (pte >> 10) & 0xfff_ffff_ffff;
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = ppn * PAGE_SIZE;
+                continue;
+            }
+
+            if !self.is_permitted(pte, mode, access) {
+                return Err(access.page_fault(vaddr));
+            }
+
+            // スーパーページの場合は物理ページ番号の下位ビットが0でなければならない。
+            if ppn & ((1u64 << (9 * level)) - 1) != 0 {
+                return Err(access.page_fault(vaddr));
+            }
+
+            if debug {
+                return Ok((pte, level));
+            }
+            let mut new_pte = pte | PTE_A;
+            if access == AccessType::Store {
+                new_pte |= PTE_D;
+            }
+            if new_pte != pte {
+                self.save_physical_memory(pte_address, 8, new_pte)
+                    .ok_or_else(|| access.access_fault(vaddr))?;
+            }
+
+            return Ok((new_pte, level));
+        }
+
+        Err(access.page_fault(vaddr))
+    }
+
+    // sfence.vmaとsatpの書き込みで呼ばれる。
+    pub(super) fn flush_tlb(&mut self, vaddr: Option<u64>, asid: Option<u64>) {
+        let levels = match self.csrs[S_ATP] >> 60 {
+            SATP_MODE_SV39 => 3,
+            _ => 4,
+        };
+        let vpn = vaddr.map(|vaddr| (vaddr >> 12) & ((1 << (9 * levels)) - 1));
+        self.fetch_tlb.flush(vpn, asid);
+        self.data_tlb.flush(vpn, asid);
+    }
+
+    // 仮想アドレスからsizeバイト読み出す関数
+    // ページ境界をまたぐ場合はそれぞれのページで変換する。
+    pub(super) fn read_memory(
+        &mut self,
+        vaddr: u64,
+        size: usize,
+        access: AccessType,
+    ) -> Result<u64, Trap> {
+        self.check_watchpoints(vaddr, size, access);
+        self.trace_load(vaddr, size, access);
+        let page_offset = vaddr & (PAGE_SIZE - 1);
+        let first = self.translate(vaddr, access)?;
+        if page_offset + size as u64 <= PAGE_SIZE {
+            return self
+                .load_physical_memory(first, size)
+                .ok_or_else(|| access.access_fault(vaddr));
+        }
+
+        let next_page = (vaddr | (PAGE_SIZE - 1)).wrapping_add(1);
+        let second = self.translate(next_page, access)?;
+        let mut value = 0;
+        for i in 0..size as u64 {
+            let paddr = if page_offset + i < PAGE_SIZE {
+                first + i
+            } else {
+                second + (page_offset + i - PAGE_SIZE)
+            };
+            let byte = self
+                .load_physical_memory(paddr, 1)
+                .ok_or_else(|| access.access_fault(vaddr.wrapping_add(i)))?;
+            value |= byte << (8 * i);
+        }
+
+        Ok(value)
+    }
+
+    // 仮想アドレスにsizeバイト書き込む関数
+    // ページ境界をまたぐ場合は両方のページの変換が成功してから書き込む。
+    pub(super) fn write_memory(&mut self, vaddr: u64, size: usize, value: u64) -> Result<(), Trap> {
+        let access = AccessType::Store;
+        self.check_watchpoints(vaddr, size, access);
+        self.trace_store(vaddr, size, value);
+        let page_offset = vaddr & (PAGE_SIZE - 1);
+        let first = self.translate(vaddr, access)?;
+        if page_offset + size as u64 <= PAGE_SIZE {
+            return self
+                .save_physical_memory(first, size, value)
+                .ok_or_else(|| access.access_fault(vaddr));
+        }
+
+        let next_page = (vaddr | (PAGE_SIZE - 1)).wrapping_add(1);
+        let second = self.translate(next_page, access)?;
+        let first_len = (PAGE_SIZE - page_offset) as usize;
+        if !self.bus.is_mapped(first, first_len) || !self.bus.is_mapped(second, size - first_len) {
+            return Err(access.access_fault(vaddr));
+        }
+
+        for i in 0..size as u64 {
+            let paddr = if page_offset + i < PAGE_SIZE {
+                first + i
+            } else {
+                second + (page_offset + i - PAGE_SIZE)
+            };
+            self.save_physical_memory(paddr, 1, value >> (8 * i))
+                .ok_or_else(|| access.access_fault(vaddr.wrapping_add(i)))?;
+        }
+
+        Ok(())
+    }
+}
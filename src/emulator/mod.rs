@@ -1,13 +1,47 @@
+mod bus;
+mod clint;
+mod debug;
+mod difftest;
+mod disasm;
+mod elf;
 mod emulator_tests;
+mod fdt;
+mod fuzz;
+mod gdb;
 mod helpers;
+mod htif;
+mod linux;
+mod mmu;
+mod monitor;
+mod pk;
+mod plic;
+mod sbi;
+mod semihosting;
+mod signature;
+mod tlb;
+mod trace;
+mod trap;
+mod uart;
+mod virt;
+mod virtio;
 
 use std::{
+    collections::HashMap,
     fs::File,
     io::{self, Read},
 };
 
 use softfloat_wrapper::{ExceptionFlags, Float, F32, F64};
 
+pub use self::bus::Device;
+use self::bus::{Bus, Ram};
+use self::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+use self::debug::Debugger;
+pub use self::difftest::DiffOutcome;
+use self::disasm::disassemble;
+pub use self::disasm::objdump;
+use self::elf::ElfFile;
+pub use self::fuzz::{fuzz, FuzzConfig};
 use self::helpers::{
     c_extract_2_4_rd, c_extract_2_4_rs2, c_extract_2_6_rs2, c_extract_7_11_rs1, c_extract_7_9_rd,
     c_extract_7_9_rs1, c_extract_imm_17_16_12, c_extract_imm_5_4_0, c_extract_imm_9_4_5_8_7_5,
@@ -17,20 +51,68 @@ use self::helpers::{
     c_extract_uimm_5_4_9_6_2_3, extend_sign_10bit, extend_sign_128bit, extend_sign_12bit,
     extend_sign_13bit, extend_sign_16bit, extend_sign_18bit, extend_sign_21bit, extend_sign_32bit,
     extend_sign_6bit, extend_sign_8bit, extend_sign_9bit, extend_sign_n, extract_csr,
-    extract_funct3, extract_funct7, extract_imm_11_0, extract_imm_31_12, extract_offset_11_0,
-    extract_offset_11_5_4_0, extract_offset_12_10_5_4_1_11, extract_rd, extract_rm, extract_rs1,
-    extract_rs2, extract_rs3, extract_shamt, extract_zimm, is_nan_boxing, nan_boxing, rm_to_swrm,
-    swef_to_fflags, truncate_top_16bit, truncate_top_32bit,
+    extract_funct3, extract_funct7, extract_imm_11_0, extract_imm_31_12, extract_instruction_bits,
+    extract_offset_11_0, extract_offset_11_5_4_0, extract_offset_12_10_5_4_1_11, extract_rd,
+    extract_rm, extract_rs1, extract_rs2, extract_rs3, extract_shamt, extract_zimm, is_nan_boxing,
+    nan_boxing, rm_to_swrm, swef_to_fflags, truncate_top_16bit, truncate_top_32bit,
 };
+use self::htif::Htif;
+use self::linux::LinuxUser;
+use self::mmu::{is_supported_satp, AccessType};
+pub use self::monitor::install_interrupt_handler;
+use self::plic::{Plic, PLIC_BASE, PLIC_CONTEXTS, PLIC_SIZE, PLIC_SOURCES};
+use self::sbi::Sbi;
+use self::semihosting::Semihosting;
+use self::tlb::{Tlb, TlbStats};
+use self::trace::Trace;
+use self::trap::{Exception, Interrupt, Trap};
+pub use self::uart::{Uart, UART_BASE, UART_IRQ, UART_SIZE};
+pub use self::virt::VirtConfig;
+pub use self::virtio::{DiskMode, VirtioBlock, VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE};
 
 pub struct Rv64SGEmulator {
-    memory: Vec<u8>,
+    bus: Bus,
     preserved_memory: Option<(usize, usize)>,
     registers: [u64; 32],
     f_registers: [u64; 32],
     csrs: [u64; 4096],
+    // ソフトウェアが書き込んだmipのSSIP/STIP/SEIP。mipはこれとデバイスの割り込みの論理和になる。
+    mip_software: u64,
     pc: u64,
     mode: MachineMode,
+    symbols: HashMap<String, u64>,
+    htif: Option<Htif>,
+    sbi: Option<Sbi>,
+    linux: Option<LinuxUser>,
+    semihosting: Option<Semihosting>,
+    device_tree: Option<Vec<u8>>,
+    // HTIF以外でゲストが終了を要求した場合の理由
+    exit_reason: Option<ExitReason>,
+    debugger: Debugger,
+    trace: Option<Trace>,
+    // 実装していない命令を表示するかどうか。fuzzでは大量に出るので表示しない。
+    report_not_implemented: bool,
+    // これまでに実行したステップ数と、その上限
+    steps: u64,
+    instruction_limit: Option<u64>,
+    fetch_tlb: Tlb,
+    data_tlb: Tlb,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExitReason {
+    Pass,
+    // riscv-testsの場合は失敗したテストの番号
+    Fail(u64),
+    // SBIで再起動が要求された場合
+    Reboot,
+    // 実行できる命令数の上限に達した場合
+    InstructionLimit,
+}
+
+pub struct ExitStatus {
+    pub reason: ExitReason,
+    pub console: Vec<u8>,
 }
 
 impl Rv64SGEmulator {
@@ -40,46 +122,171 @@ impl Rv64SGEmulator {
         memsz: usize,
         filename: &str,
     ) -> io::Result<Self> {
-        let mut file_obj = File::open(filename)?;
-        let mut buf = vec![0; memsz];
+        let mut buf = Vec::new();
+        File::open(filename)?.read_to_end(&mut buf)?;
+        buf.truncate(memsz);
+
+        // RAMはentryから配置する。
+        let mut rv64sg_emulator = Rv64SGEmulator::new(entry, sp, entry, memsz);
+        rv64sg_emulator.bus.write_bytes(entry, &buf).unwrap();
+
+        Ok(rv64sg_emulator)
+    }
+
+    // filenameの内容をそのまま物理アドレスaddressに置く関数
+    pub fn load_image(&mut self, address: u64, filename: &str) -> io::Result<()> {
+        let mut buf = Vec::new();
+        File::open(filename)?.read_to_end(&mut buf)?;
+
+        self.bus.write_bytes(address, &buf).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "image: {} ({:#x}-{:#x}) is outside of memory",
+                    filename,
+                    address,
+                    address.wrapping_add(buf.len() as u64)
+                ),
+            )
+        })
+    }
+
+    // ELF64の実行ファイルを読み込む関数
+    // RAMをram_baseから配置し、PT_LOADセグメントを物理アドレスに置いて.bssの部分は0で埋める。
+    // pcはe_entryから設定し、tohost/fromhostのシンボルがあればHTIFを有効にする。
+    pub fn load_from_elf_file(
+        ram_base: u64,
+        sp: u64,
+        memsz: usize,
+        filename: &str,
+    ) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        File::open(filename)?.read_to_end(&mut buf)?;
+
+        let elf = ElfFile::parse(&buf)?;
+        let mut rv64sg_emulator = Rv64SGEmulator::new(elf.entry, sp, ram_base, memsz);
+
+        // 大きなp_memszで確保に失敗しないよう、RAMに収まることを先に確認する。
+        let ram_end = ram_base.checked_add(memsz as u64);
+        for segment in elf.segments.iter() {
+            let outside = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "elf: segment {:#x}-{:#x} is outside of memory ({:#x}-{:#x})",
+                        segment.paddr,
+                        segment.paddr.wrapping_add(segment.memsz),
+                        ram_base,
+                        ram_base.wrapping_add(memsz as u64)
+                    ),
+                )
+            };
+            match (segment.paddr.checked_add(segment.memsz), ram_end) {
+                (Some(end), Some(ram_end)) if segment.paddr >= ram_base && end <= ram_end => {}
+                _ => return Err(outside()),
+            }
+
+            let mut data = segment.data.clone();
+            data.resize(segment.memsz as usize, 0);
+            rv64sg_emulator
+                .bus
+                .write_bytes(segment.paddr, &data)
+                .map_err(|_| outside())?;
+        }
+
+        if let Some(tohost) = elf.symbols.get("tohost") {
+            rv64sg_emulator.set_htif(*tohost, elf.symbols.get("fromhost").copied());
+        }
+        rv64sg_emulator.symbols = elf.symbols;
+
+        Ok(rv64sg_emulator)
+    }
+
+    // RAM、CLINT、PLICを接続した状態で作る。
+    fn new(entry: u64, sp: u64, ram_base: u64, memsz: usize) -> Self {
+        let mut bus = Bus::new();
+        bus.add_device(ram_base, memsz as u64, None, Box::new(Ram::new(memsz)));
+        bus.add_device(CLINT_BASE, CLINT_SIZE, None, Box::new(Clint::new()));
+        bus.add_device(
+            PLIC_BASE,
+            PLIC_SIZE,
+            None,
+            Box::new(Plic::new(PLIC_SOURCES, PLIC_CONTEXTS)),
+        );
 
-        file_obj.read(&mut buf)?;
         let mut rv64sg_emulator = Rv64SGEmulator {
-            memory: buf,
+            bus,
             preserved_memory: None,
             registers: [0; 32],
             f_registers: [0; 32],
             csrs: [0; 4096],
             mode: MachineMode::M,
+            mip_software: 0,
             pc: entry,
+            symbols: HashMap::new(),
+            htif: None,
+            sbi: None,
+            linux: None,
+            semihosting: None,
+            device_tree: None,
+            exit_reason: None,
+            debugger: Debugger::new(),
+            trace: None,
+            report_not_implemented: true,
+            steps: 0,
+            instruction_limit: None,
+            fetch_tlb: Tlb::new(),
+            data_tlb: Tlb::new(),
         };
 
         rv64sg_emulator.registers[2] = sp;
 
-        Ok(rv64sg_emulator)
+        rv64sg_emulator
     }
 }
 
-fn print_not_implement(what: String) {
-    println!("Error: not implemented\n{}", what);
+fn illegal_instruction(instruction: &Vec<u8>) -> Trap {
+    Exception::IllegalInstruction(extract_instruction_bits(instruction)).into()
 }
 
 impl Rv64SGEmulator {
-    fn fetch_instraction(&self) -> Vec<u8> {
-        let mut instruction = vec![0; 4];
-        instruction.clone_from_slice(&self.memory[self.pc as usize..self.pc as usize + 4]);
+    fn print_not_implement(&self, instruction: &Vec<u8>, what: String) {
+        if !self.report_not_implemented {
+            return;
+        }
+        let bits = extract_instruction_bits(instruction) as u32;
+        println!(
+            "Error: not implemented: {:#x}: {}\n{}",
+            self.pc,
+            disassemble(bits, self.pc),
+            what
+        );
+    }
+
+    // C拡張の命令の場合は後半の2バイトを読まずに0で埋める。
+    // 後半が別のページにある場合でも不要なページフォールトを起こさないため。
+    fn fetch_instraction(&mut self) -> Result<Vec<u8>, Trap> {
+        let mut instruction = self
+            .read_memory(self.pc, 2, AccessType::Instruction)?
+            .to_le_bytes()[..4]
+            .to_vec();
+
+        if instruction[0] & 0x3 == 0x3 {
+            let upper = self.read_memory(self.pc.wrapping_add(2), 2, AccessType::Instruction)?;
+            instruction[2] = upper as u8;
+            instruction[3] = (upper >> 8) as u8;
+        }
 
-        instruction
+        Ok(instruction)
     }
 
     // どの命令を実行するか判定し実行する関数
     // 将来的には最初にC拡張か判定し次に他の命令か判定する。
-    // 例外が発生した場合、すぐにNoneを返す。
-    // またこの関数内で例外が発生した場合（不正な命令等）set_exception_causeに理由を引数にしてすぐに返す。
+    // 例外が発生した場合（不正な命令等）、その原因をErrとしてすぐに返す。
     // printlnとかはデバッグが終わったら消す。
-    fn decode_and_exec(&mut self, instruction: Vec<u8>) -> Option<()> {
+    fn decode_and_exec(&mut self, instruction: Vec<u8>) -> Result<(), Trap> {
         if self.c_decode_and_exec(&instruction)? {
-            return Some(());
+            return Ok(());
         }
 
         match instruction[0] & 0x7f {
@@ -92,24 +299,33 @@ impl Rv64SGEmulator {
                 5 => self.lhu(&instruction),
                 6 => self.lwu(&instruction),
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x3, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x3, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x7 => match extract_funct3(&instruction) {
                 2 => self.f_lw(&instruction),
                 3 => self.f_ld(&instruction),
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x7, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x7, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0xf => match extract_funct3(&instruction) {
                 0 => self.fence(&instruction),
                 1 => self.fence_i(&instruction),
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0xf, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0xf, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x13 => match extract_funct3(&instruction) {
@@ -117,11 +333,11 @@ impl Rv64SGEmulator {
                 1 => match instruction[3] >> 2 {
                     0 => self.slli(&instruction),
                     b_26_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 26-31bit: {:x}",
-                            0x13, 1, b_26_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 26-31bit: {:x}", 0x13, 1, b_26_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 2 => self.slti(&instruction),
@@ -131,47 +347,54 @@ impl Rv64SGEmulator {
                     0 => self.srli(&instruction),
                     0x10 => self.srai(&instruction),
                     b_26_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 26-31bit: {:x}",
-                            0x13, 5, b_26_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 26-31bit: {:x}", 0x13, 5, b_26_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 6 => self.ori(&instruction),
                 7 => self.andi(&instruction),
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x13, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x13, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x17 => self.auipc(&instruction),
             0x1b => match extract_funct3(&instruction) {
                 0 => self.addiw(&instruction),
-                1 => match instruction[3] >> 2 {
+                // shamt[5]が1の場合は予約されているので、funct7全体で判定する。
+                1 => match instruction[3] >> 1 {
                     0 => self.slliw(&instruction),
-                    b_26_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 26-31bit: {:x}",
-                            0x1b, 1, b_26_31
-                        ));
-                        self.set_exception_cause(2)
+                    b_25_31 => {
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x1b, 1, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
-                5 => match instruction[3] >> 2 {
+                5 => match instruction[3] >> 1 {
                     0 => self.srliw(&instruction),
-                    0x10 => self.sraiw(&instruction),
-                    b_26_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 26-31bit: {:x}",
-                            0x1b, 5, b_26_31
-                        ));
-                        self.set_exception_cause(2)
+                    0x20 => self.sraiw(&instruction),
+                    b_25_31 => {
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x1b, 5, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x1b, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x1b, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x23 => match extract_funct3(&instruction) {
@@ -180,16 +403,22 @@ impl Rv64SGEmulator {
                 2 => self.sw(&instruction),
                 3 => self.sd(&instruction),
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x23, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x23, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x27 => match extract_funct3(&instruction) {
                 2 => self.f_sw(&instruction),
                 3 => self.f_sd(&instruction),
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x27, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x27, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x2f => match extract_funct3(&instruction) {
@@ -206,16 +435,18 @@ impl Rv64SGEmulator {
                     0x18 => self.a_mominu_w(&instruction),
                     0x1c => self.a_momaxu_w(&instruction),
                     b_27_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 27-31bit: {:x}",
-                            0x2f, 2, b_27_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 27-31bit: {:x}", 0x2f, 2, b_27_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 3 => match instruction[3] >> 3 {
                     0 => self.a_moadd_d(&instruction),
                     0x1 => self.a_moswap_d(&instruction),
+                    0x2 => self.a_lr_d(&instruction),
+                    0x3 => self.a_sc_d(&instruction),
                     0x4 => self.a_moxor_d(&instruction),
                     0x8 => self.a_moor_d(&instruction),
                     0xc => self.a_moand_d(&instruction),
@@ -224,16 +455,19 @@ impl Rv64SGEmulator {
                     0x18 => self.a_mominu_d(&instruction),
                     0x1c => self.a_momaxu_d(&instruction),
                     b_27_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 27-31bit: {:x}",
-                            0x2f, 3, b_27_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 27-31bit: {:x}", 0x2f, 3, b_27_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x2f, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x2f, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x33 => match extract_funct3(&instruction) {
@@ -242,55 +476,55 @@ impl Rv64SGEmulator {
                     1 => self.mul(&instruction),
                     0x20 => self.sub(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x33, 0, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x33, 0, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 1 => match instruction[3] >> 1 {
                     0 => self.sll(&instruction),
                     1 => self.mulh(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x33, 1, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x33, 1, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 2 => match instruction[3] >> 1 {
                     0 => self.slt(&instruction),
                     1 => self.mulhsu(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x33, 2, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x33, 2, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 3 => match instruction[3] >> 1 {
                     0 => self.sltu(&instruction),
                     1 => self.mulhu(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x33, 3, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x33, 3, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 4 => match instruction[3] >> 1 {
                     0 => self.xor(&instruction),
                     1 => self.div(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x33, 4, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x33, 4, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 5 => match instruction[3] >> 1 {
@@ -298,38 +532,41 @@ impl Rv64SGEmulator {
                     1 => self.divu(&instruction),
                     0x20 => self.sra(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x33, 5, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x33, 5, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 6 => match instruction[3] >> 1 {
                     0 => self.or(&instruction),
                     1 => self.rem(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x33, 6, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x33, 6, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 7 => match instruction[3] >> 1 {
                     0 => self.and(&instruction),
                     1 => self.remu(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x33, 7, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x33, 7, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x33, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x33, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x37 => self.lui(&instruction),
@@ -339,31 +576,31 @@ impl Rv64SGEmulator {
                     1 => self.mulw(&instruction),
                     0x20 => self.subw(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x3b, 0, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x3b, 0, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 1 => match instruction[3] >> 1 {
                     0 => self.sllw(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x3b, 1, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x3b, 1, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 4 => match instruction[3] >> 1 {
                     1 => self.divw(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x3b, 4, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x3b, 4, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 5 => match instruction[3] >> 1 {
@@ -371,68 +608,83 @@ impl Rv64SGEmulator {
                     1 => self.divuw(&instruction),
                     0x20 => self.sraw(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x3b, 5, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x3b, 5, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 6 => match instruction[3] >> 1 {
                     1 => self.remw(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x3b, 6, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x3b, 6, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 7 => match instruction[3] >> 1 {
                     1 => self.remuw(&instruction),
                     b_25_31 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} 25-31bit: {:x}",
-                            0x3b, 7, b_25_31
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} 25-31bit: {:x}", 0x3b, 7, b_25_31),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x3b, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x3b, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x43 => match (instruction[3] & 0x6) >> 1 {
                 0 => self.f_madd_s(&instruction),
                 1 => self.f_madd_d(&instruction),
                 b_25_26 => {
-                    print_not_implement(format!("op: {:x} 25-26bit: {:x}", 0x43, b_25_26));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} 25-26bit: {:x}", 0x43, b_25_26),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x47 => match (instruction[3] & 0x6) >> 1 {
                 0 => self.f_msub_s(&instruction),
                 1 => self.f_msub_d(&instruction),
                 b_25_26 => {
-                    print_not_implement(format!("op: {:x} 25-26bit: {:x}", 0x47, b_25_26));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} 25-26bit: {:x}", 0x47, b_25_26),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x4b => match (instruction[3] & 0x6) >> 1 {
                 0 => self.f_nmsub_s(&instruction),
                 1 => self.f_nmsub_d(&instruction),
                 b_25_26 => {
-                    print_not_implement(format!("op: {:x} 25-26bit: {:x}", 0x4b, b_25_26));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} 25-26bit: {:x}", 0x4b, b_25_26),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x4f => match (instruction[3] & 0x6) >> 1 {
                 0 => self.f_nmadd_s(&instruction),
                 1 => self.f_nmadd_d(&instruction),
                 b_25_26 => {
-                    print_not_implement(format!("op: {:x} 25-26bit: {:x}", 0x4f, b_25_26));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} 25-26bit: {:x}", 0x4f, b_25_26),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x53 => match extract_funct7(&instruction) {
@@ -448,11 +700,11 @@ impl Rv64SGEmulator {
                     1 => self.f_sgnjn_s(&instruction),
                     2 => self.f_sgnjx_s(&instruction),
                     funct3 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, funct3, 0x10
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} funct7: {:x}", 0x53, funct3, 0x10),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x11 => match extract_funct3(&instruction) {
@@ -460,63 +712,63 @@ impl Rv64SGEmulator {
                     1 => self.f_sgnjn_d(&instruction),
                     2 => self.f_sgnjx_d(&instruction),
                     funct3 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, funct3, 0x11
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} funct7: {:x}", 0x53, funct3, 0x11),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x14 => match extract_funct3(&instruction) {
                     0 => self.f_min_s(&instruction),
                     1 => self.f_max_s(&instruction),
                     funct3 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, funct3, 0x14
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} funct7: {:x}", 0x53, funct3, 0x14),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x15 => match extract_funct3(&instruction) {
                     0 => self.f_min_d(&instruction),
                     1 => self.f_max_d(&instruction),
                     funct3 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, funct3, 0x15
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} funct7: {:x}", 0x53, funct3, 0x15),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x20 => match extract_rs2(&instruction) {
                     1 => self.f_cvt_s_d(&instruction),
                     rs2 => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct7: {:x}",
-                            0x53, 0x20, rs2
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} rs2: {:x} funct7: {:x}", 0x53, 0x20, rs2),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x21 => match extract_rs2(&instruction) {
                     0 => self.f_cvt_d_s(&instruction),
                     rs2 => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct7: {:x}",
-                            0x53, 0x21, rs2
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} rs2: {:x} funct7: {:x}", 0x53, 0x21, rs2),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x2d => match extract_rs2(&instruction) {
                     0 => self.f_sqrt_d(&instruction),
                     rs2 => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct7: {:x}",
-                            0x53, 0x2d, rs2
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} rs2: {:x} funct7: {:x}", 0x53, 0x2d, rs2),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x50 => match extract_funct3(&instruction) {
@@ -524,11 +776,11 @@ impl Rv64SGEmulator {
                     1 => self.f_lt_s(&instruction),
                     2 => self.f_eq_s(&instruction),
                     funct3 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, funct3, 0x50
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} funct7: {:x}", 0x53, funct3, 0x50),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x51 => match extract_funct3(&instruction) {
@@ -536,11 +788,11 @@ impl Rv64SGEmulator {
                     1 => self.f_lt_d(&instruction),
                     2 => self.f_eq_d(&instruction),
                     funct3 => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, funct3, 0x51
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} funct7: {:x}", 0x53, funct3, 0x51),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x60 => match extract_rs2(&instruction) {
@@ -549,11 +801,11 @@ impl Rv64SGEmulator {
                     2 => self.f_cvt_l_s(&instruction),
                     3 => self.f_cvt_lu_s(&instruction),
                     rs2 => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct7: {:x}",
-                            0x53, rs2, 0x60
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} rs2: {:x} funct7: {:x}", 0x53, rs2, 0x60),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x61 => match extract_rs2(&instruction) {
@@ -562,11 +814,11 @@ impl Rv64SGEmulator {
                     2 => self.f_cvt_l_d(&instruction),
                     3 => self.f_cvt_lu_d(&instruction),
                     rs2 => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct7: {:x}",
-                            0x53, rs2, 0x61
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} rs2: {:x} funct7: {:x}", 0x53, rs2, 0x61),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x68 => match extract_rs2(&instruction) {
@@ -575,11 +827,11 @@ impl Rv64SGEmulator {
                     2 => self.f_cvt_s_l(&instruction),
                     3 => self.f_cvt_s_lu(&instruction),
                     rs2 => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct7: {:x}",
-                            0x53, rs2, 0x68
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} rs2: {:x} funct7: {:x}", 0x53, rs2, 0x68),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x69 => match extract_rs2(&instruction) {
@@ -588,58 +840,73 @@ impl Rv64SGEmulator {
                     2 => self.f_cvt_d_l(&instruction),
                     3 => self.f_cvt_d_lu(&instruction),
                     rs2 => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct7: {:x}",
-                            0x53, rs2, 0x69
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} rs2: {:x} funct7: {:x}", 0x53, rs2, 0x69),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x70 => match (extract_rs2(&instruction), extract_funct3(&instruction)) {
                     (0, 0) => self.f_mv_x_w(&instruction),
                     (0, 1) => self.f_class_s(&instruction),
                     (rs2, funct3) => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, rs2, funct3, 0x70
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!(
+                                "op: {:x} rs2: {:x} funct3: {:x} funct7: {:x}",
+                                0x53, rs2, funct3, 0x70
+                            ),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x71 => match (extract_rs2(&instruction), extract_funct3(&instruction)) {
                     (0, 0) => self.f_mv_x_d(&instruction),
                     (0, 1) => self.f_class_d(&instruction),
                     (rs2, funct3) => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, rs2, funct3, 0x71
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!(
+                                "op: {:x} rs2: {:x} funct3: {:x} funct7: {:x}",
+                                0x53, rs2, funct3, 0x71
+                            ),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x78 => match (extract_rs2(&instruction), extract_funct3(&instruction)) {
                     (0, 0) => self.f_mv_w_x(&instruction),
                     (rs2, funct3) => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, rs2, funct3, 0x78
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!(
+                                "op: {:x} rs2: {:x} funct3: {:x} funct7: {:x}",
+                                0x53, rs2, funct3, 0x78
+                            ),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 0x79 => match (extract_rs2(&instruction), extract_funct3(&instruction)) {
                     (0, 0) => self.f_mv_d_x(&instruction),
                     (rs2, funct3) => {
-                        print_not_implement(format!(
-                            "op: {:x} rs2: {:x} funct3: {:x} funct7: {:x}",
-                            0x53, rs2, funct3, 0x79
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!(
+                                "op: {:x} rs2: {:x} funct3: {:x} funct7: {:x}",
+                                0x53, rs2, funct3, 0x79
+                            ),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 funct7 => {
-                    print_not_implement(format!("op: {:x} funct7: {:x}", 0x53, funct7));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct7: {:x}", 0x53, funct7),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x63 => match extract_funct3(&instruction) {
@@ -650,15 +917,21 @@ impl Rv64SGEmulator {
                 6 => self.bltu(&instruction),
                 7 => self.bgeu(&instruction),
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x63, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x63, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x67 => match extract_funct3(&instruction) {
                 0 => self.jalr(&instruction),
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x67, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x67, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x73 => match extract_funct3(&instruction) {
@@ -669,43 +942,53 @@ impl Rv64SGEmulator {
                     instruction[3],
                 ) {
                     (0x73, 0, 0, 0) => self.ecall(&instruction),
+                    (0x73, 0, 0x10, 0) => self.ebreak(&instruction),
                     (0x73, 0, 0x20, 0x30) => self.mret(&instruction),
+                    (0x73, 0, 0x20, 0x10) => self.sret(&instruction),
+                    (0x73, 0, 0x50, 0x10) => self.wfi(&instruction),
+                    _ if extract_funct7(&instruction) == 0x09 && extract_rd(&instruction) == 0 => {
+                        self.sfence_vma(&instruction)
+                    }
                     inst => {
-                        print_not_implement(format!(
-                            "op: {:x} funct3: {:x} inst: {:?}",
-                            0x73, 0, inst
-                        ));
-                        self.set_exception_cause(2)
+                        self.print_not_implement(
+                            &instruction,
+                            format!("op: {:x} funct3: {:x} inst: {:?}", 0x73, 0, inst),
+                        );
+                        Err(illegal_instruction(&instruction))
                     }
                 },
                 1 => self.csrrw(&instruction),
                 2 => self.csrrs(&instruction),
+                3 => self.csrrc(&instruction),
                 5 => self.csrrwi(&instruction),
+                6 => self.csrrsi(&instruction),
                 7 => self.csrrci(&instruction),
                 funct3 => {
-                    print_not_implement(format!("op: {:x} funct3: {:x}", 0x73, funct3));
-                    self.set_exception_cause(2)
+                    self.print_not_implement(
+                        &instruction,
+                        format!("op: {:x} funct3: {:x}", 0x73, funct3),
+                    );
+                    Err(illegal_instruction(&instruction))
                 }
             },
             0x6f => self.jal(&instruction),
             op => {
-                print_not_implement(format!("op: {:x}", op));
-                self.set_exception_cause(2)
+                self.print_not_implement(&instruction, format!("op: {:x}", op));
+                Err(illegal_instruction(&instruction))
             }
         }
     }
 
     // C拡張版のdecode_and_run
-    // 命令の実行に成功した場合はSome(true)
-    // 命令の実行に失敗した場合またはC拡張のフォーマット(2bit)で存在しない命令の場合はNone
-    // 命令がC拡張でない場合はSome(false)を返す。
-    fn c_decode_and_exec(&mut self, instruction: &Vec<u8>) -> Option<bool> {
+    // 命令の実行に成功した場合はOk(true)
+    // 命令の実行に失敗した場合またはC拡張のフォーマット(2bit)で存在しない命令の場合はErr
+    // 命令がC拡張でない場合はOk(false)を返す。
+    fn c_decode_and_exec(&mut self, instruction: &Vec<u8>) -> Result<bool, Trap> {
         match instruction[0] & 0x3 {
             0 => match instruction[1] >> 5 {
                 0 => match (instruction[0], instruction[1]) {
                     (0, 0) => {
-                        self.set_exception_cause(2)?;
-                        return None;
+                        return Err(illegal_instruction(instruction));
                     }
                     _ => self.c_addi4spn(&instruction)?,
                 },
@@ -714,9 +997,11 @@ impl Rv64SGEmulator {
                 6 => self.c_sw(&instruction)?,
                 7 => self.c_sd(&instruction)?,
                 b_13_15 => {
-                    print_not_implement(format!("c_op: {:x} b_13_15: {}", 0, b_13_15));
-                    self.set_exception_cause(2)?;
-                    return None;
+                    self.print_not_implement(
+                        instruction,
+                        format!("c_op: {:x} b_13_15: {}", 0, b_13_15),
+                    );
+                    return Err(illegal_instruction(instruction));
                 }
             },
             1 => {
@@ -736,37 +1021,32 @@ impl Rv64SGEmulator {
                             0 => self.c_srli(&instruction)?,
                             1 => self.c_srai(&instruction)?,
                             2 => self.c_andi(&instruction)?,
-                            3 => {
-                                match (instruction[1] & 0x10) >> 4 {
-                                    0 => match (instruction[0] & 0x60) >> 5 {
-                                        0 => self.c_sub(&instruction)?,
-                                        1 => self.c_xor(&instruction)?,
-                                        2 => self.c_or(&instruction)?,
-                                        3 => self.c_and(&instruction)?,
-                                        b_5_6 => {
-                                            print_not_implement(format!("c_op: {:x} b_13_15: {} b_12: {} b_10_11: {} b_5_6: {}", 1, 4, 0, 3, b_5_6));
-                                            self.set_exception_cause(2)?;
-                                            return None;
-                                        }
-                                    },
-                                    _ => match (instruction[0] & 0x60) >> 5 {
-                                        0 => self.c_subw(&instruction)?,
-                                        1 => self.c_addw(&instruction)?,
-                                        b_5_6 => {
-                                            print_not_implement(format!("c_op: {:x} b_13_15: {} b_12: {} b_10_11: {} b_5_6: {}", 1, 4, 1, 3, b_5_6));
-                                            self.set_exception_cause(2)?;
-                                            return None;
-                                        }
-                                    },
-                                }
-                            }
+                            3 => match (instruction[1] & 0x10) >> 4 {
+                                0 => match (instruction[0] & 0x60) >> 5 {
+                                    0 => self.c_sub(&instruction)?,
+                                    1 => self.c_xor(&instruction)?,
+                                    2 => self.c_or(&instruction)?,
+                                    3 => self.c_and(&instruction)?,
+                                    b_5_6 => {
+                                        self.print_not_implement(instruction, format!("c_op: {:x} b_13_15: {} b_12: {} b_10_11: {} b_5_6: {}", 1, 4, 0, 3, b_5_6));
+                                        return Err(illegal_instruction(instruction));
+                                    }
+                                },
+                                _ => match (instruction[0] & 0x60) >> 5 {
+                                    0 => self.c_subw(&instruction)?,
+                                    1 => self.c_addw(&instruction)?,
+                                    b_5_6 => {
+                                        self.print_not_implement(instruction, format!("c_op: {:x} b_13_15: {} b_12: {} b_10_11: {} b_5_6: {}", 1, 4, 1, 3, b_5_6));
+                                        return Err(illegal_instruction(instruction));
+                                    }
+                                },
+                            },
                             b_10_11 => {
-                                print_not_implement(format!(
-                                    "c_op: {:x} b_13_15: {} b_10_11: {}",
-                                    1, 4, b_10_11
-                                ));
-                                self.set_exception_cause(2)?;
-                                return None;
+                                self.print_not_implement(
+                                    instruction,
+                                    format!("c_op: {:x} b_13_15: {} b_10_11: {}", 1, 4, b_10_11),
+                                );
+                                return Err(illegal_instruction(instruction));
                             }
                         }
                     }
@@ -774,9 +1054,11 @@ impl Rv64SGEmulator {
                     6 => self.c_beqz(&instruction)?,
                     7 => self.c_bnez(&instruction)?,
                     b_13_15 => {
-                        print_not_implement(format!("c_op: {:x} b_13_15: {}", 1, b_13_15));
-                        self.set_exception_cause(2)?;
-                        return None;
+                        self.print_not_implement(
+                            instruction,
+                            format!("c_op: {:x} b_13_15: {}", 1, b_13_15),
+                        );
+                        return Err(illegal_instruction(instruction));
                     }
                 }
             }
@@ -791,193 +1073,194 @@ impl Rv64SGEmulator {
                     },
                     1 => match (instruction[0] & 0x7c) >> 2 {
                         0 => match (instruction[0] >> 7) + ((instruction[1] & 0xf) << 1) {
-                            // 未実装
                             0 => self.c_ebreak(&instruction)?,
                             _ => self.c_jalr(&instruction)?,
                             b_7_11 => {
-                                print_not_implement(format!(
-                                    "c_op: {:x} b_13_15: {} b_12: {} b_7_11: {} b_2_6: {}",
-                                    2, 4, 1, b_7_11, 0
-                                ));
-                                self.set_exception_cause(2)?;
-                                return None;
+                                self.print_not_implement(
+                                    instruction,
+                                    format!(
+                                        "c_op: {:x} b_13_15: {} b_12: {} b_7_11: {} b_2_6: {}",
+                                        2, 4, 1, b_7_11, 0
+                                    ),
+                                );
+                                return Err(illegal_instruction(instruction));
                             }
                         },
                         _ => self.c_add(&instruction)?,
                     },
                     b_12 => {
-                        print_not_implement(format!("c_op: {:x} b_13_15: {} b_12: {}", 2, 4, b_12));
-                        self.set_exception_cause(2)?;
-                        return None;
+                        self.print_not_implement(
+                            instruction,
+                            format!("c_op: {:x} b_13_15: {} b_12: {}", 2, 4, b_12),
+                        );
+                        return Err(illegal_instruction(instruction));
                     }
                 },
                 6 => self.c_swsp(&instruction)?,
                 7 => self.c_sdsp(&instruction)?,
                 b_13_15 => {
-                    print_not_implement(format!("c_op: {:x} b_13_15: {}", 2, b_13_15));
-                    self.set_exception_cause(2)?;
-                    return None;
+                    self.print_not_implement(
+                        instruction,
+                        format!("c_op: {:x} b_13_15: {}", 2, b_13_15),
+                    );
+                    return Err(illegal_instruction(instruction));
                 }
             },
             3 => {
-                return Some(false);
+                return Ok(false);
             }
             c_op => {
-                print_not_implement(format!("c_op: {:x}", c_op));
-                self.set_exception_cause(2)?;
-                return None;
+                self.print_not_implement(instruction, format!("c_op: {:x}", c_op));
+                return Err(illegal_instruction(instruction));
             }
         }
 
-        Some(true)
+        Ok(true)
     }
 
-    // address + sizeがメモリの大きさを超えるか判定する関数
-    // 超えた場合にtrue 超えなかった場合はfalseを返す。
-    fn is_over_memory(&mut self, address: usize, size: usize) -> bool {
-        if address + size >= self.memory.len() {
-            true
-        } else {
-            false
-        }
+    // base..base + sizeの物理アドレスにデバイスを接続する。
+    // irqを指定した場合はPLICのその番号の割り込み源につながる。
+    pub fn add_device(&mut self, base: u64, size: u64, irq: Option<u32>, device: Box<dyn Device>) {
+        self.bus.add_device(base, size, irq, device);
     }
 
-    fn is_exit(&self, end_point: u64) -> bool {
-        if self.pc == end_point {
-            true
-        } else {
-            false
-        }
+    // 読み込んだ後にpcを変更する。
+    pub fn set_entry(&mut self, entry: u64) {
+        self.pc = entry;
     }
 
-    pub fn exec_program(&mut self, end_point: u64) {
-        self.initialize_csrs();
-        loop {
-            println!("pc: {:x}", self.pc);
-            let instruction = self.fetch_instraction();
+    // limitステップ実行したら終了する。割り込みや例外も1ステップとして数える。
+    pub fn set_instruction_limit(&mut self, limit: u64) {
+        self.instruction_limit = Some(limit);
+    }
 
-            match self.decode_and_exec(instruction) {
-                Some(_) => {}
-                None => self.call_exception(),
-            }
+    // 命令フェッチとデータアクセスそれぞれのTLBのヒット数・ミス数
+    pub fn tlb_stats(&self) -> (TlbStats, TlbStats) {
+        (self.fetch_tlb.stats, self.data_tlb.stats)
+    }
 
-            if self.is_exit(end_point) {
-                println!("0x3000: {:x}", self.memory[0x3000]);
-                return;
+    // ゲストがHTIFを通して終了するまで実行する。
+    pub fn exec_program(&mut self) -> ExitStatus {
+        self.initialize_csrs();
+        loop {
+            if let Some(reason) = self.step() {
+                return self.exit_status(reason);
             }
         }
     }
-}
 
-// Rv64i
-impl Rv64SGEmulator {
-    // pcの値を変更する関数
-    // この関数を使うことでメモリの外側に出たときの原因を自動的に設定できる。
-    fn progress_pc(&mut self, pc: u64) -> Option<()> {
-        self.pc = pc;
-        if self.is_over_memory(self.pc as usize, 4) {
-            self.set_exception_cause(2)
+    // 割り込みか1命令を処理し、ゲストが終了を要求した場合はその理由を返す関数
+    fn step(&mut self) -> Option<ExitReason> {
+        self.bus.tick();
+        self.update_mip();
+        self.update_sbi_timer();
+
+        if let Some(interrupt) = self.pending_interrupt() {
+            self.trace_trap(&interrupt.into());
+            self.call_exception(interrupt.into());
         } else {
-            Some(())
-        }
-    }
+            let result = self.fetch_instraction().and_then(|instruction| {
+                if self.trace.is_some() {
+                    self.trace_and_exec(instruction)
+                } else {
+                    self.decode_and_exec(instruction)
+                }
+            });
 
-    fn load_memory_8bit(&mut self, offset: usize) -> Option<u64> {
-        if self.is_over_memory(offset, 1) {
-            self.set_exception_cause(5)?;
+            if let Err(trap) = result {
+                self.trace_trap(&trap);
+                self.call_exception(trap);
+            }
         }
 
-        Some(self.memory[offset] as u64)
+        self.steps += 1;
+        let reason = self
+            .check_tohost()
+            .or_else(|| self.exit_reason.take())
+            .or_else(|| {
+                self.instruction_limit
+                    .filter(|limit| self.steps >= *limit)
+                    .map(|_| ExitReason::InstructionLimit)
+            });
+        if reason.is_some() {
+            self.flush_trace();
+        }
+        reason
     }
 
-    fn load_memory_16bit(&mut self, offset: usize) -> Option<u64> {
-        if self.is_over_memory(offset, 2) {
-            self.set_exception_cause(5)?;
+    fn exit_status(&self, reason: ExitReason) -> ExitStatus {
+        ExitStatus {
+            reason,
+            console: self
+                .htif
+                .as_ref()
+                .map(|htif| htif.console().to_vec())
+                .unwrap_or_default(),
         }
-
-        Some((self.memory[offset] as u64) + ((self.memory[offset + 1] as u64) << 8))
     }
+}
 
-    fn load_memory_32bit(&mut self, offset: usize) -> Option<u64> {
-        if self.is_over_memory(offset, 4) {
-            self.set_exception_cause(5)?;
+// Rv64i
+impl Rv64SGEmulator {
+    // pcの値を変更する関数
+    // 飛び先が2バイト境界に揃っていない場合はpcを変更せずに例外を返す。
+    // メモリの外側に出た場合は次の命令フェッチで例外になる。
+    fn progress_pc(&mut self, pc: u64) -> Result<(), Trap> {
+        if pc & 1 != 0 {
+            return Err(Exception::InstructionAddressMisaligned(pc).into());
         }
 
-        Some(
-            (self.memory[offset] as u64)
-                + ((self.memory[offset + 1] as u64) << 8)
-                + ((self.memory[offset + 2] as u64) << 16)
-                + ((self.memory[offset + 3] as u64) << 24),
-        )
+        self.pc = pc;
+        Ok(())
     }
 
-    fn load_memory_64bit(&mut self, offset: usize) -> Option<u64> {
-        if self.is_over_memory(offset, 8) {
-            self.set_exception_cause(5)?;
-        }
+    // 物理アドレスからsizeバイト読み出す関数
+    // デバイスが無いアドレスの場合はNoneを返す。
+    fn load_physical_memory(&mut self, address: u64, size: usize) -> Option<u64> {
+        self.bus.read(address, size).ok()
+    }
 
-        Some(
-            (self.memory[offset] as u64)
-                + ((self.memory[offset + 1] as u64) << 8)
-                + ((self.memory[offset + 2] as u64) << 16)
-                + ((self.memory[offset + 3] as u64) << 24)
-                + ((self.memory[offset + 4] as u64) << 32)
-                + ((self.memory[offset + 5] as u64) << 40)
-                + ((self.memory[offset + 6] as u64) << 48)
-                + ((self.memory[offset + 7] as u64) << 56),
-        )
+    // 物理アドレスにsizeバイト書き込む関数
+    // デバイスが無いアドレスの場合はNoneを返す。
+    fn save_physical_memory(&mut self, address: u64, size: usize, value: u64) -> Option<()> {
+        self.bus.write(address, size, value).ok()
     }
 
-    fn save_memory_8bit(&mut self, offset: usize, value: u64) -> Option<()> {
-        if self.is_over_memory(offset, 1) {
-            self.set_exception_cause(7)?;
-        }
+    fn load_memory_8bit(&mut self, offset: usize) -> Result<u64, Trap> {
+        self.read_memory(offset as u64, 1, AccessType::Load)
+    }
 
-        self.memory[offset] = value as u8;
-        Some(())
+    fn load_memory_16bit(&mut self, offset: usize) -> Result<u64, Trap> {
+        self.read_memory(offset as u64, 2, AccessType::Load)
     }
 
-    fn save_memory_16bit(&mut self, offset: usize, value: u64) -> Option<()> {
-        if self.is_over_memory(offset, 2) {
-            self.set_exception_cause(7)?;
-        }
+    fn load_memory_32bit(&mut self, offset: usize) -> Result<u64, Trap> {
+        self.read_memory(offset as u64, 4, AccessType::Load)
+    }
 
-        self.memory[offset] = value as u8;
-        self.memory[offset + 1] = (value >> 8) as u8;
-        Some(())
+    fn load_memory_64bit(&mut self, offset: usize) -> Result<u64, Trap> {
+        self.read_memory(offset as u64, 8, AccessType::Load)
     }
 
-    fn save_memory_32bit(&mut self, offset: usize, value: u64) -> Option<()> {
-        if self.is_over_memory(offset, 4) {
-            self.set_exception_cause(7)?;
-        }
+    fn save_memory_8bit(&mut self, offset: usize, value: u64) -> Result<(), Trap> {
+        self.write_memory(offset as u64, 1, value)
+    }
 
-        self.memory[offset] = value as u8;
-        self.memory[offset + 1] = (value >> 8) as u8;
-        self.memory[offset + 2] = (value >> 16) as u8;
-        self.memory[offset + 3] = (value >> 24) as u8;
-        Some(())
+    fn save_memory_16bit(&mut self, offset: usize, value: u64) -> Result<(), Trap> {
+        self.write_memory(offset as u64, 2, value)
     }
 
-    fn save_memory_64bit(&mut self, offset: usize, value: u64) -> Option<()> {
-        if self.is_over_memory(offset, 8) {
-            self.set_exception_cause(7)?;
-        }
+    fn save_memory_32bit(&mut self, offset: usize, value: u64) -> Result<(), Trap> {
+        self.write_memory(offset as u64, 4, value)
+    }
 
-        self.memory[offset] = value as u8;
-        self.memory[offset + 1] = (value >> 8) as u8;
-        self.memory[offset + 2] = (value >> 16) as u8;
-        self.memory[offset + 3] = (value >> 24) as u8;
-        self.memory[offset + 4] = (value >> 32) as u8;
-        self.memory[offset + 5] = (value >> 40) as u8;
-        self.memory[offset + 6] = (value >> 48) as u8;
-        self.memory[offset + 7] = (value >> 56) as u8;
-        Some(())
+    fn save_memory_64bit(&mut self, offset: usize, value: u64) -> Result<(), Trap> {
+        self.write_memory(offset as u64, 8, value)
     }
 }
 
 impl Rv64SGEmulator {
-    fn lb(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn lb(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_offset_11_0(instruction));
@@ -991,7 +1274,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn lh(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn lh(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_offset_11_0(instruction));
@@ -1005,7 +1288,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn lw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn lw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_offset_11_0(instruction));
@@ -1019,7 +1302,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn ld(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn ld(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_offset_11_0(instruction));
@@ -1032,7 +1315,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn lbu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn lbu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_offset_11_0(instruction));
@@ -1045,7 +1328,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn lhu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn lhu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_imm_11_0(instruction));
@@ -1058,7 +1341,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn lwu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn lwu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_offset_11_0(instruction));
@@ -1071,15 +1354,15 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn fence(&mut self, _: &Vec<u8>) -> Option<()> {
+    fn fence(&mut self, _: &Vec<u8>) -> Result<(), Trap> {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn fence_i(&mut self, _: &Vec<u8>) -> Option<()> {
+    fn fence_i(&mut self, _: &Vec<u8>) -> Result<(), Trap> {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn addi(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn addi(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let imm = extend_sign_12bit(extract_imm_11_0(&instruction));
@@ -1091,7 +1374,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn srli(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn srli(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let shamt = extract_shamt(instruction);
@@ -1103,7 +1386,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn srai(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn srai(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let shamt = extract_shamt(instruction);
@@ -1115,7 +1398,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn xor(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn xor(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1127,7 +1410,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn srl(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn srl(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1139,7 +1422,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sra(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sra(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1152,7 +1435,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn or(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn or(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1164,7 +1447,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn slli(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn slli(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let shamt = extract_shamt(instruction);
@@ -1176,7 +1459,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn slti(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn slti(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let imm = extend_sign_12bit(extract_imm_11_0(instruction));
@@ -1193,7 +1476,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sltiu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sltiu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let imm = extend_sign_12bit(extract_imm_11_0(instruction));
@@ -1209,7 +1492,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn xori(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn xori(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let imm = extend_sign_12bit(extract_imm_11_0(instruction));
@@ -1221,7 +1504,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn andi(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn andi(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let imm = extend_sign_12bit(extract_imm_11_0(instruction));
@@ -1233,7 +1516,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn ori(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn ori(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let imm = extract_imm_11_0(instruction);
@@ -1245,7 +1528,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn auipc(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn auipc(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let imm = extend_sign_32bit(extract_imm_31_12(instruction));
 
@@ -1256,7 +1539,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn addiw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn addiw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let imm = extend_sign_12bit(extract_imm_11_0(instruction));
@@ -1269,7 +1552,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn slliw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn slliw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let shamt = extract_shamt(instruction);
@@ -1282,7 +1565,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn srliw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn srliw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let shamt = extract_shamt(instruction);
@@ -1295,7 +1578,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sraiw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sraiw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let shamt = extract_shamt(instruction);
@@ -1308,7 +1591,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn addw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn addw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1322,7 +1605,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn subw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn subw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1336,7 +1619,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sllw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sllw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1350,7 +1633,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn srlw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn srlw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1364,7 +1647,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sraw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sraw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1378,7 +1661,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sb(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sb(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_12bit(extract_offset_11_5_4_0(instruction));
@@ -1390,7 +1673,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sh(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sh(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_12bit(extract_offset_11_5_4_0(instruction));
@@ -1402,7 +1685,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_12bit(extract_offset_11_5_4_0(instruction));
@@ -1414,7 +1697,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sd(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sd(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_12bit(extract_offset_11_5_4_0(instruction));
@@ -1426,7 +1709,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn add(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn add(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1438,7 +1721,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sub(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sub(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1450,7 +1733,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sll(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sll(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1462,7 +1745,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn slt(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn slt(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1479,7 +1762,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn sltu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn sltu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1495,7 +1778,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn and(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn and(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1507,7 +1790,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn bge(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn bge(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_13bit(extract_offset_12_10_5_4_1_11(instruction));
@@ -1520,7 +1803,7 @@ impl Rv64SGEmulator {
         }
     }
 
-    fn bltu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn bltu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_13bit(extract_offset_12_10_5_4_1_11(instruction));
@@ -1532,7 +1815,7 @@ impl Rv64SGEmulator {
         }
     }
 
-    fn bgeu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn bgeu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_13bit(extract_offset_12_10_5_4_1_11(instruction));
@@ -1544,7 +1827,7 @@ impl Rv64SGEmulator {
         }
     }
 
-    fn jalr(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn jalr(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_offset_11_0(instruction));
@@ -1555,10 +1838,10 @@ impl Rv64SGEmulator {
             self.registers[rd] = t;
         }
 
-        Some(())
+        Ok(())
     }
 
-    fn lui(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn lui(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let imm = extend_sign_32bit(extract_imm_31_12(instruction));
 
@@ -1569,7 +1852,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn beq(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn beq(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_13bit(extract_offset_12_10_5_4_1_11(instruction));
@@ -1581,7 +1864,7 @@ impl Rv64SGEmulator {
         }
     }
 
-    fn bne(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn bne(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_13bit(extract_offset_12_10_5_4_1_11(instruction));
@@ -1593,7 +1876,7 @@ impl Rv64SGEmulator {
         }
     }
 
-    fn blt(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn blt(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_13bit(extract_offset_12_10_5_4_1_11(instruction));
@@ -1606,17 +1889,37 @@ impl Rv64SGEmulator {
         }
     }
 
-    fn ecall(&mut self, _: &Vec<u8>) -> Option<()> {
-        let cause = match self.mode {
-            MachineMode::U => 8,
-            MachineMode::S => 9,
-            MachineMode::M => 11,
+    // 組み込みのSBIが有効な場合、S-modeからのecallはM-modeに移らずに処理する。
+    fn ecall(&mut self, _: &Vec<u8>) -> Result<(), Trap> {
+        if self.mode == MachineMode::S && self.sbi.is_some() {
+            let pc = self.sbi_call();
+            return self.progress_pc(pc);
+        }
+        if self.linux.is_some() && (self.mode == MachineMode::U || self.is_proxy_kernel()) {
+            self.linux_syscall();
+            return self.progress_pc(self.pc.wrapping_add(4));
+        }
+
+        let exception = match self.mode {
+            MachineMode::U => Exception::EnvironmentCallFromUMode,
+            MachineMode::S => Exception::EnvironmentCallFromSMode,
+            MachineMode::M => Exception::EnvironmentCallFromMMode,
         };
 
-        self.set_exception_cause(cause)
+        Err(exception.into())
+    }
+
+    // セミホスティングの命令列の場合は要求を処理し、後ろのsrai x0, x0, 7から続ける。
+    fn ebreak(&mut self, _: &Vec<u8>) -> Result<(), Trap> {
+        if self.is_semihosting_call() {
+            self.semihosting_call()?;
+            return self.progress_pc(self.pc.wrapping_add(4));
+        }
+
+        Err(Exception::Breakpoint(self.pc).into())
     }
 
-    fn mret(&mut self, _: &Vec<u8>) -> Option<()> {
+    fn mret(&mut self, _: &Vec<u8>) -> Result<(), Trap> {
         let pc = self.read_csr(M_EPC)?;
         let mut mstatus = self.read_csr(M_STATUS)?;
         mstatus = (mstatus & 0xfffffffffffffff7) | ((mstatus & 0x80) >> 4);
@@ -1625,16 +1928,76 @@ impl Rv64SGEmulator {
         mstatus = (mstatus & 0xffffffffffffe7ff) | ((MachineMode::U as u64) << 11);
         self.write_csr(M_STATUS, mstatus)?;
         self.mode = MachineMode::from_u64(mode).unwrap();
+        // M-mode以外に戻る場合はMPRVを0にする。
+        if self.mode != MachineMode::M {
+            self.csrs[M_STATUS] &= 0xfffffffffffdffff;
+        }
+        self.progress_pc(pc)
+    }
+
+    // sstatus.SPPのモードに戻る。
+    // U-modeやmstatus.TSRが立っているS-modeからは実行できない。
+    fn sret(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
+        let mstatus = self.csrs[M_STATUS];
+        if self.mode == MachineMode::U || (self.mode == MachineMode::S && mstatus & 0x400000 != 0) {
+            return Err(illegal_instruction(instruction));
+        }
+
+        let pc = self.csrs[S_EPC];
+        let mode = if mstatus & 0x100 != 0 {
+            MachineMode::S
+        } else {
+            MachineMode::U
+        };
+        let mut mstatus = (mstatus & 0xfffffffffffffffd) | ((mstatus & 0x20) >> 4);
+        mstatus = (mstatus | 0x20) & 0xfffffffffffffeff;
+        // sretは必ずM-mode以外に戻るので、MPRVを0にする。
+        mstatus &= 0xfffffffffffdffff;
+        self.csrs[M_STATUS] = mstatus;
+        self.mode = mode;
         self.progress_pc(pc)
     }
 
-    fn csrrw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    // rs1がx0以外の場合はそのアドレス、rs2がx0以外の場合はそのASIDのTLBエントリだけを無効化する。
+    // U-modeやmstatus.TVMが立っているS-modeからは実行できない。
+    fn sfence_vma(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
+        if self.mode == MachineMode::U
+            || (self.mode == MachineMode::S && self.csrs[M_STATUS] & 0x100000 != 0)
+        {
+            return Err(illegal_instruction(instruction));
+        }
+
+        let rs1 = extract_rs1(instruction);
+        let rs2 = extract_rs2(instruction);
+        let vaddr = (rs1 != 0).then(|| self.registers[rs1]);
+        let asid = (rs2 != 0).then(|| self.registers[rs2] & 0xffff);
+        self.flush_tlb(vaddr, asid);
+
+        self.progress_pc(self.pc.wrapping_add(4))
+    }
+
+    // 割り込みはexec_programで毎命令確認しているので何もしない。
+    // U-modeやmstatus.TWが立っているS-modeからは実行できない。
+    fn wfi(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
+        if self.mode == MachineMode::U
+            || (self.mode == MachineMode::S && self.csrs[M_STATUS] & 0x200000 != 0)
+        {
+            return Err(illegal_instruction(instruction));
+        }
+
+        self.progress_pc(self.pc.wrapping_add(4))
+    }
+
+    fn csrrw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rv_csr = extract_csr(instruction);
 
-        let t = self.read_csr(rv_csr)?;
-        self.write_csr(rv_csr, self.registers[rs1])?;
+        let t = self
+            .read_csr(rv_csr)
+            .map_err(|_| illegal_instruction(instruction))?;
+        self.write_csr(rv_csr, self.registers[rs1])
+            .map_err(|_| illegal_instruction(instruction))?;
 
         if rd != 0 {
             self.registers[rd] = t;
@@ -1643,13 +2006,39 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn csrrs(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    // csrrs/csrrcはrs1がx0、csrrsi/csrrciはuimmが0の場合はCSRを読むだけで書き込まない。
+    fn csrrs(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rv_csr = extract_csr(instruction);
 
-        let t = self.read_csr(rv_csr)?;
-        self.write_csr(rv_csr, t | self.registers[rs1])?;
+        let t = self
+            .read_csr(rv_csr)
+            .map_err(|_| illegal_instruction(instruction))?;
+        if rs1 != 0 {
+            self.write_csr(rv_csr, self.csr_rmw_base(rv_csr, t) | self.registers[rs1])
+                .map_err(|_| illegal_instruction(instruction))?;
+        }
+
+        if rd != 0 {
+            self.registers[rd] = t;
+        }
+
+        self.progress_pc(self.pc.wrapping_add(4))
+    }
+
+    fn csrrc(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
+        let rd = extract_rd(instruction);
+        let rs1 = extract_rs1(instruction);
+        let rv_csr = extract_csr(instruction);
+
+        let t = self
+            .read_csr(rv_csr)
+            .map_err(|_| illegal_instruction(instruction))?;
+        if rs1 != 0 {
+            self.write_csr(rv_csr, self.csr_rmw_base(rv_csr, t) & !self.registers[rs1])
+                .map_err(|_| illegal_instruction(instruction))?;
+        }
 
         if rd != 0 {
             self.registers[rd] = t;
@@ -1658,26 +2047,34 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn csrrwi(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn csrrwi(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let zimm = extract_zimm(instruction);
         let rv_csr = extract_csr(instruction);
 
         if rd != 0 {
-            self.registers[rd] = self.read_csr(rv_csr)?;
+            self.registers[rd] = self
+                .read_csr(rv_csr)
+                .map_err(|_| illegal_instruction(instruction))?;
         }
 
-        self.write_csr(rv_csr, zimm)?;
+        self.write_csr(rv_csr, zimm)
+            .map_err(|_| illegal_instruction(instruction))?;
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn csrrci(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn csrrsi(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let zimm = extract_zimm(instruction);
         let rv_csr = extract_csr(instruction);
 
-        let t = self.read_csr(rv_csr)?;
-        self.write_csr(rv_csr, t & (!zimm))?;
+        let t = self
+            .read_csr(rv_csr)
+            .map_err(|_| illegal_instruction(instruction))?;
+        if zimm != 0 {
+            self.write_csr(rv_csr, self.csr_rmw_base(rv_csr, t) | zimm)
+                .map_err(|_| illegal_instruction(instruction))?;
+        }
 
         if rd != 0 {
             self.registers[rd] = t;
@@ -1686,7 +2083,27 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn jal(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn csrrci(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
+        let rd = extract_rd(instruction);
+        let zimm = extract_zimm(instruction);
+        let rv_csr = extract_csr(instruction);
+
+        let t = self
+            .read_csr(rv_csr)
+            .map_err(|_| illegal_instruction(instruction))?;
+        if zimm != 0 {
+            self.write_csr(rv_csr, self.csr_rmw_base(rv_csr, t) & !zimm)
+                .map_err(|_| illegal_instruction(instruction))?;
+        }
+
+        if rd != 0 {
+            self.registers[rd] = t;
+        }
+
+        self.progress_pc(self.pc.wrapping_add(4))
+    }
+
+    fn jal(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(&instruction);
         let mut offset = (((instruction[3] as u64) & 0x80) << (20 - 8))
             + (((instruction[2] as u64) & 0x0f) << (16 - 1))
@@ -1706,7 +2123,7 @@ impl Rv64SGEmulator {
 
 //Rv64m
 impl Rv64SGEmulator {
-    fn mul(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn mul(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1718,7 +2135,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn mulh(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn mulh(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1732,7 +2149,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn mulhsu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn mulhsu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1746,7 +2163,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn mulhu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn mulhu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1760,7 +2177,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn div(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn div(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1776,7 +2193,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn divu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn divu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1792,7 +2209,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn rem(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn rem(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1808,7 +2225,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn remu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn remu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1824,7 +2241,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn mulw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn mulw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1838,7 +2255,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn divw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn divw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1854,7 +2271,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn divuw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn divuw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1873,7 +2290,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn remw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn remw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1891,7 +2308,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn remuw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn remuw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -1913,7 +2330,7 @@ impl Rv64SGEmulator {
 
 //Rv64f + d
 impl Rv64SGEmulator {
-    fn f_lw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_lw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_offset_11_0(instruction));
@@ -1924,7 +2341,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_ld(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_ld(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let offset = extend_sign_12bit(extract_offset_11_0(instruction));
@@ -1935,7 +2352,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_12bit(extract_offset_11_5_4_0(instruction));
@@ -1943,12 +2360,12 @@ impl Rv64SGEmulator {
         self.save_memory_32bit(
             self.registers[rs1].wrapping_add(offset) as usize,
             self.f_registers[rs2],
-        );
+        )?;
 
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sd(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sd(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
         let offset = extend_sign_12bit(extract_offset_11_5_4_0(instruction));
@@ -1961,7 +2378,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_madd_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_madd_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -1974,7 +2391,7 @@ impl Rv64SGEmulator {
             .fused_mul_add(
                 F64::from_bits(self.f_registers[rs2]),
                 F64::from_bits(self.f_registers[rs3]),
-                rm_to_swrm(rm).unwrap(),
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
             )
             .to_bits();
         flag.get();
@@ -1984,7 +2401,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_madd_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_madd_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2010,7 +2427,11 @@ impl Rv64SGEmulator {
         };
         self.f_registers[rd] = nan_boxing(
             rs1_value
-                .fused_mul_add(rs2_value, rs3_value, rm_to_swrm(rm).unwrap())
+                .fused_mul_add(
+                    rs2_value,
+                    rs3_value,
+                    rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                )
                 .to_bits() as u64,
         );
         flag.get();
@@ -2020,7 +2441,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_msub_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_msub_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2046,7 +2467,11 @@ impl Rv64SGEmulator {
         };
         self.f_registers[rd] = nan_boxing(
             rs1_value
-                .fused_mul_add(rs2_value, rs3_value.neg(), rm_to_swrm(rm).unwrap())
+                .fused_mul_add(
+                    rs2_value,
+                    rs3_value.neg(),
+                    rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                )
                 .to_bits() as u64,
         );
         flag.get();
@@ -2056,7 +2481,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_msub_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_msub_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2069,7 +2494,7 @@ impl Rv64SGEmulator {
             .fused_mul_add(
                 F64::from_bits(self.f_registers[rs2]),
                 F64::from_bits(self.f_registers[rs3]).neg(),
-                rm_to_swrm(rm).unwrap(),
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
             )
             .to_bits();
         flag.get();
@@ -2079,7 +2504,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_nmsub_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_nmsub_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2106,7 +2531,11 @@ impl Rv64SGEmulator {
         self.f_registers[rd] = nan_boxing(
             rs1_value
                 .neg()
-                .fused_mul_add(rs2_value, rs3_value, rm_to_swrm(rm).unwrap())
+                .fused_mul_add(
+                    rs2_value,
+                    rs3_value,
+                    rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                )
                 .to_bits() as u64,
         );
         flag.get();
@@ -2116,7 +2545,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_nmsub_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_nmsub_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2130,7 +2559,7 @@ impl Rv64SGEmulator {
             .fused_mul_add(
                 F64::from_bits(self.f_registers[rs2]),
                 F64::from_bits(self.f_registers[rs3]),
-                rm_to_swrm(rm).unwrap(),
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
             )
             .to_bits();
         flag.get();
@@ -2140,7 +2569,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_nmadd_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_nmadd_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2167,7 +2596,11 @@ impl Rv64SGEmulator {
         self.f_registers[rd] = nan_boxing(
             rs1_value
                 .neg()
-                .fused_mul_add(rs2_value, rs3_value.neg(), rm_to_swrm(rm).unwrap())
+                .fused_mul_add(
+                    rs2_value,
+                    rs3_value.neg(),
+                    rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                )
                 .to_bits() as u64,
         );
         flag.get();
@@ -2177,7 +2610,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_nmadd_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_nmadd_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2191,7 +2624,7 @@ impl Rv64SGEmulator {
             .fused_mul_add(
                 F64::from_bits(self.f_registers[rs2]),
                 F64::from_bits(self.f_registers[rs3]).neg(),
-                rm_to_swrm(rm).unwrap(),
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
             )
             .to_bits();
         flag.get();
@@ -2201,7 +2634,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_add_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_add_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2219,8 +2652,14 @@ impl Rv64SGEmulator {
         } else {
             F32::quiet_nan()
         };
-        self.f_registers[rd] =
-            nan_boxing(rs1_value.add(rs2_value, rm_to_swrm(rm).unwrap()).to_bits() as u64);
+        self.f_registers[rd] = nan_boxing(
+            rs1_value
+                .add(
+                    rs2_value,
+                    rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                )
+                .to_bits() as u64,
+        );
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2228,7 +2667,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_add_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_add_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2239,7 +2678,7 @@ impl Rv64SGEmulator {
         self.f_registers[rd] = F64::from_bits(self.f_registers[rs1])
             .add(
                 F64::from_bits(self.f_registers[rs2]),
-                rm_to_swrm(rm).unwrap(),
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
             )
             .to_bits();
         flag.get();
@@ -2249,7 +2688,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sub_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sub_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2267,8 +2706,14 @@ impl Rv64SGEmulator {
         } else {
             F32::quiet_nan()
         };
-        self.f_registers[rd] =
-            nan_boxing(rs1_value.sub(rs2_value, rm_to_swrm(rm).unwrap()).to_bits() as u64);
+        self.f_registers[rd] = nan_boxing(
+            rs1_value
+                .sub(
+                    rs2_value,
+                    rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                )
+                .to_bits() as u64,
+        );
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2276,7 +2721,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sub_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sub_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2287,7 +2732,7 @@ impl Rv64SGEmulator {
         self.f_registers[rd] = F64::from_bits(self.f_registers[rs1])
             .sub(
                 F64::from_bits(self.f_registers[rs2]),
-                rm_to_swrm(rm).unwrap(),
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
             )
             .to_bits();
         flag.get();
@@ -2297,7 +2742,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_mul_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_mul_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2315,8 +2760,14 @@ impl Rv64SGEmulator {
         } else {
             F32::quiet_nan()
         };
-        self.f_registers[rd] =
-            nan_boxing(rs1_value.mul(rs2_value, rm_to_swrm(rm).unwrap()).to_bits() as u64);
+        self.f_registers[rd] = nan_boxing(
+            rs1_value
+                .mul(
+                    rs2_value,
+                    rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                )
+                .to_bits() as u64,
+        );
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2324,7 +2775,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_mul_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_mul_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2335,7 +2786,7 @@ impl Rv64SGEmulator {
         self.f_registers[rd] = F64::from_bits(self.f_registers[rs1])
             .mul(
                 F64::from_bits(self.f_registers[rs2]),
-                rm_to_swrm(rm).unwrap(),
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
             )
             .to_bits();
         flag.get();
@@ -2345,7 +2796,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_div_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_div_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2356,7 +2807,7 @@ impl Rv64SGEmulator {
         self.f_registers[rd] = F64::from_bits(self.f_registers[rs1])
             .div(
                 F64::from_bits(self.f_registers[rs2]),
-                rm_to_swrm(rm).unwrap(),
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
             )
             .to_bits();
         flag.get();
@@ -2366,7 +2817,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sgnj_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sgnj_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2388,7 +2839,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sgnjn_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sgnjn_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2410,7 +2861,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sgnjx_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sgnjx_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2432,7 +2883,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sgnj_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sgnj_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2444,7 +2895,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sgnjn_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sgnjn_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2456,7 +2907,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sgnjx_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sgnjx_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2469,7 +2920,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_min_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_min_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2511,7 +2962,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_max_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_max_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2552,7 +3003,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_min_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_min_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2586,7 +3037,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_max_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_max_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2619,7 +3070,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_s_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_s_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2628,7 +3079,7 @@ impl Rv64SGEmulator {
         flag.set();
         self.f_registers[rd] = nan_boxing(
             F64::from_bits(self.f_registers[rs1])
-                .to_f32(rm_to_swrm(rm).unwrap())
+                .to_f32(rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?)
                 .to_bits() as u64,
         );
         flag.get();
@@ -2638,7 +3089,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_d_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_d_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2646,7 +3097,7 @@ impl Rv64SGEmulator {
         let mut flag = ExceptionFlags::default();
         flag.set();
         self.f_registers[rd] = F32::from_bits(self.f_registers[rs1] as u32)
-            .to_f64(rm_to_swrm(rm).unwrap())
+            .to_f64(rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?)
             .to_bits();
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
@@ -2655,7 +3106,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_sqrt_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_sqrt_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2663,7 +3114,7 @@ impl Rv64SGEmulator {
         let mut flag = ExceptionFlags::default();
         flag.set();
         self.f_registers[rd] = F64::from_bits(self.f_registers[rs1])
-            .sqrt(rm_to_swrm(rm).unwrap())
+            .sqrt(rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?)
             .to_bits();
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
@@ -2672,7 +3123,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_eq_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_eq_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2706,7 +3157,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_le_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_le_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2738,7 +3189,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_lt_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_lt_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2770,7 +3221,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_le_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_le_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2794,7 +3245,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_lt_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_lt_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2818,7 +3269,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_eq_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_eq_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -2839,15 +3290,17 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_w_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_w_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
 
         let mut flag = ExceptionFlags::default();
         flag.set();
-        self.registers[rd] = F32::from_bits(self.f_registers[rs1] as u32)
-            .to_i32(rm_to_swrm(rm).unwrap(), true) as u64;
+        self.registers[rd] = F32::from_bits(self.f_registers[rs1] as u32).to_i32(
+            rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+            true,
+        ) as u64;
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2855,17 +3308,18 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_wu_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_wu_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
 
         let mut flag = ExceptionFlags::default();
         flag.set();
-        self.registers[rd] = extend_sign_32bit(
-            F32::from_bits(self.f_registers[rs1] as u32).to_u32(rm_to_swrm(rm).unwrap(), true)
-                as u64,
-        );
+        self.registers[rd] =
+            extend_sign_32bit(F32::from_bits(self.f_registers[rs1] as u32).to_u32(
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                true,
+            ) as u64);
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2873,15 +3327,17 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_l_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_l_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
 
         let mut flag = ExceptionFlags::default();
         flag.set();
-        self.registers[rd] = F32::from_bits(self.f_registers[rs1] as u32)
-            .to_i64(rm_to_swrm(rm).unwrap(), true) as u64;
+        self.registers[rd] = F32::from_bits(self.f_registers[rs1] as u32).to_i64(
+            rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+            true,
+        ) as u64;
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2889,15 +3345,17 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_lu_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_lu_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
 
         let mut flag = ExceptionFlags::default();
         flag.set();
-        self.registers[rd] =
-            F32::from_bits(self.f_registers[rs1] as u32).to_u64(rm_to_swrm(rm).unwrap(), true);
+        self.registers[rd] = F32::from_bits(self.f_registers[rs1] as u32).to_u64(
+            rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+            true,
+        );
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2905,7 +3363,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_w_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_w_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2913,8 +3371,10 @@ impl Rv64SGEmulator {
         if rd != 0 {
             let mut flag = ExceptionFlags::default();
             flag.set();
-            self.registers[rd] =
-                F64::from_bits(self.f_registers[rs1]).to_i32(rm_to_swrm(rm).unwrap(), true) as u64;
+            self.registers[rd] = F64::from_bits(self.f_registers[rs1]).to_i32(
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                true,
+            ) as u64;
             flag.get();
             let fflags = self.read_csr(FFLAGS)?;
             self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2923,7 +3383,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_wu_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_wu_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2931,9 +3391,10 @@ impl Rv64SGEmulator {
         if rd != 0 {
             let mut flag = ExceptionFlags::default();
             flag.set();
-            self.registers[rd] = extend_sign_32bit(
-                F64::from_bits(self.f_registers[rs1]).to_u32(rm_to_swrm(rm).unwrap(), true) as u64,
-            );
+            self.registers[rd] = extend_sign_32bit(F64::from_bits(self.f_registers[rs1]).to_u32(
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                true,
+            ) as u64);
             flag.get();
             let fflags = self.read_csr(FFLAGS)?;
             self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2942,7 +3403,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_l_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_l_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2950,8 +3411,10 @@ impl Rv64SGEmulator {
         if rd != 0 {
             let mut flag = ExceptionFlags::default();
             flag.set();
-            self.registers[rd] =
-                F64::from_bits(self.f_registers[rs1]).to_i64(rm_to_swrm(rm).unwrap(), true) as u64;
+            self.registers[rd] = F64::from_bits(self.f_registers[rs1]).to_i64(
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                true,
+            ) as u64;
             flag.get();
             let fflags = self.read_csr(FFLAGS)?;
             self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2960,7 +3423,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_lu_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_lu_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2968,8 +3431,10 @@ impl Rv64SGEmulator {
         if rd != 0 {
             let mut flag = ExceptionFlags::default();
             flag.set();
-            self.registers[rd] =
-                F64::from_bits(self.f_registers[rs1]).to_u64(rm_to_swrm(rm).unwrap(), true);
+            self.registers[rd] = F64::from_bits(self.f_registers[rs1]).to_u64(
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+                true,
+            );
             flag.get();
             let fflags = self.read_csr(FFLAGS)?;
             self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -2978,7 +3443,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_s_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_s_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -2986,7 +3451,11 @@ impl Rv64SGEmulator {
         let mut flag = ExceptionFlags::default();
         flag.set();
         self.f_registers[rd] = nan_boxing(
-            F32::from_i32(self.registers[rs1] as i32, rm_to_swrm(rm).unwrap()).to_bits() as u64,
+            F32::from_i32(
+                self.registers[rs1] as i32,
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+            )
+            .to_bits() as u64,
         );
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
@@ -2995,7 +3464,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_s_wu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_s_wu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -3003,7 +3472,11 @@ impl Rv64SGEmulator {
         let mut flag = ExceptionFlags::default();
         flag.set();
         self.f_registers[rd] = nan_boxing(
-            F32::from_u32(self.registers[rs1] as u32, rm_to_swrm(rm).unwrap()).to_bits() as u64,
+            F32::from_u32(
+                self.registers[rs1] as u32,
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+            )
+            .to_bits() as u64,
         );
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
@@ -3012,7 +3485,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_s_l(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_s_l(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -3020,7 +3493,11 @@ impl Rv64SGEmulator {
         let mut flag = ExceptionFlags::default();
         flag.get();
         self.f_registers[rd] = nan_boxing(
-            F32::from_i64(self.registers[rs1] as i64, rm_to_swrm(rm).unwrap()).to_bits() as u64,
+            F32::from_i64(
+                self.registers[rs1] as i64,
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+            )
+            .to_bits() as u64,
         );
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
@@ -3029,7 +3506,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_s_lu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_s_lu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
@@ -3037,7 +3514,11 @@ impl Rv64SGEmulator {
         let mut flag = ExceptionFlags::default();
         flag.set();
         self.f_registers[rd] = nan_boxing(
-            F32::from_u64(self.registers[rs1], rm_to_swrm(rm).unwrap()).to_bits() as u64,
+            F32::from_u64(
+                self.registers[rs1],
+                rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+            )
+            .to_bits() as u64,
         );
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
@@ -3046,15 +3527,18 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_d_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_d_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
 
         let mut flag = ExceptionFlags::default();
         flag.set();
-        self.f_registers[rd] =
-            F64::from_i32(self.registers[rs1] as i32, rm_to_swrm(rm).unwrap()).to_bits();
+        self.f_registers[rd] = F64::from_i32(
+            self.registers[rs1] as i32,
+            rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+        )
+        .to_bits();
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -3062,15 +3546,18 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_d_wu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_d_wu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
 
         let mut flag = ExceptionFlags::default();
         flag.set();
-        self.f_registers[rd] =
-            F64::from_u32(self.registers[rs1] as u32, rm_to_swrm(rm).unwrap()).to_bits();
+        self.f_registers[rd] = F64::from_u32(
+            self.registers[rs1] as u32,
+            rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+        )
+        .to_bits();
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -3078,15 +3565,18 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_d_l(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_d_l(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
 
         let mut flag = ExceptionFlags::default();
         flag.set();
-        self.f_registers[rd] =
-            F64::from_i64(self.registers[rs1] as i64, rm_to_swrm(rm).unwrap()).to_bits();
+        self.f_registers[rd] = F64::from_i64(
+            self.registers[rs1] as i64,
+            rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+        )
+        .to_bits();
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -3094,15 +3584,18 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_cvt_d_lu(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_cvt_d_lu(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rm = extract_rm(instruction, self.read_csr(FRM)?);
         let rs1 = extract_rs1(instruction);
 
         let mut flag = ExceptionFlags::default();
         flag.set();
-        self.f_registers[rd] =
-            F64::from_u64(self.registers[rs1], rm_to_swrm(rm).unwrap()).to_bits();
+        self.f_registers[rd] = F64::from_u64(
+            self.registers[rs1],
+            rm_to_swrm(rm).ok_or_else(|| illegal_instruction(instruction))?,
+        )
+        .to_bits();
         flag.get();
         let fflags = self.read_csr(FFLAGS)?;
         self.write_csr(FFLAGS, fflags | swef_to_fflags(flag))?;
@@ -3110,7 +3603,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_mv_x_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_mv_x_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
 
@@ -3121,7 +3614,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_class_s(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_class_s(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
 
@@ -3177,7 +3670,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_mv_x_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_mv_x_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
 
@@ -3188,7 +3681,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_class_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_class_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
 
@@ -3239,7 +3732,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_mv_w_x(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_mv_w_x(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
 
@@ -3248,7 +3741,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn f_mv_d_x(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn f_mv_d_x(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
 
@@ -3260,7 +3753,7 @@ impl Rv64SGEmulator {
 
 // Rv64a
 impl Rv64SGEmulator {
-    fn a_moadd_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_moadd_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3278,7 +3771,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_moswap_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_moswap_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3296,7 +3789,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_lr_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_lr_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
 
@@ -3312,7 +3805,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_sc_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_sc_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3345,7 +3838,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_moxor_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_moxor_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3363,7 +3856,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_moor_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_moor_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3381,7 +3874,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_moand_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_moand_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3399,7 +3892,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_momin_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_momin_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3419,7 +3912,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_momax_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_momax_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3439,7 +3932,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_mominu_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_mominu_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3459,7 +3952,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_momaxu_w(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_momaxu_w(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3479,7 +3972,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_moadd_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_moadd_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3497,7 +3990,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_moswap_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_moswap_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3512,7 +4005,46 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_moxor_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_lr_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
+        let rd = extract_rd(instruction);
+        let rs1 = extract_rs1(instruction);
+
+        let t = self.load_memory_64bit(self.registers[rs1] as usize)?;
+        self.preserved_memory = Some((
+            self.registers[rs1] as usize,
+            self.registers[rs1] as usize + 8,
+        ));
+
+        if rd != 0 {
+            self.registers[rd] = t;
+        }
+
+        self.progress_pc(self.pc.wrapping_add(4))
+    }
+
+    fn a_sc_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
+        let rd = extract_rd(instruction);
+        let rs1 = extract_rs1(instruction);
+        let rs2 = extract_rs2(instruction);
+
+        let flag = self.preserved_memory.is_some_and(|preserved_memory| {
+            preserved_memory.0 <= self.registers[rs1] as usize
+                && preserved_memory.1 >= self.registers[rs1] as usize + 8
+        });
+
+        if flag {
+            self.save_memory_64bit(self.registers[rs1] as usize, self.registers[rs2])?;
+        }
+
+        if rd != 0 {
+            self.registers[rd] = if flag { 0 } else { 1 };
+        }
+        self.preserved_memory = None;
+
+        self.progress_pc(self.pc.wrapping_add(4))
+    }
+
+    fn a_moxor_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3527,7 +4059,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_moor_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_moor_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3542,7 +4074,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_moand_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_moand_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3557,7 +4089,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_momin_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_momin_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3576,7 +4108,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_momax_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_momax_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3595,7 +4127,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_mominu_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_mominu_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3614,7 +4146,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(4))
     }
 
-    fn a_momaxu_d(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn a_momaxu_d(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs1 = extract_rs1(instruction);
         let rs2 = extract_rs2(instruction);
@@ -3636,7 +4168,7 @@ impl Rv64SGEmulator {
 
 // Rv64c
 impl Rv64SGEmulator {
-    fn c_addi4spn(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_addi4spn(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_2_4_rd(instruction);
         let uimm = c_extract_uimm_5_4_9_6_2_3(instruction);
 
@@ -3645,7 +4177,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_lw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_lw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_2_4_rd(instruction);
         let rs1 = c_extract_7_9_rs1(instruction);
         let uimm = c_extract_uimm_5_3_2_6(instruction);
@@ -3657,7 +4189,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_ld(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_ld(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_2_4_rd(instruction);
         let rs1 = c_extract_7_9_rs1(instruction);
         let uimm = c_extract_uimm_5_3_7_6(instruction);
@@ -3668,7 +4200,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_sw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_sw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = c_extract_7_9_rs1(instruction);
         let rs2 = c_extract_2_4_rs2(instruction);
         let uimm = c_extract_uimm_5_3_2_6(instruction);
@@ -3681,7 +4213,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_sd(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_sd(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = c_extract_7_9_rs1(instruction);
         let rs2 = c_extract_2_4_rs2(instruction);
         let uimm = c_extract_uimm_5_3_7_6(instruction);
@@ -3694,11 +4226,11 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_nop(&mut self, _: &Vec<u8>) -> Option<()> {
+    fn c_nop(&mut self, _: &Vec<u8>) -> Result<(), Trap> {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_addi(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_addi(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let imm = extend_sign_6bit(c_extract_imm_5_4_0(instruction));
 
@@ -3709,7 +4241,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_addiw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_addiw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let imm = extend_sign_6bit(c_extract_imm_5_4_0(instruction));
 
@@ -3721,7 +4253,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_li(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_li(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let imm = c_extract_imm_5_4_0(instruction);
 
@@ -3732,14 +4264,14 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_addi16sp(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_addi16sp(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let imm = extend_sign_10bit(c_extract_imm_9_4_5_8_7_5(instruction));
 
         self.registers[2] = self.registers[2].wrapping_add(imm);
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_lui(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_lui(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let imm = extend_sign_18bit(c_extract_imm_17_16_12(instruction));
 
@@ -3750,7 +4282,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_srli(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_srli(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_7_9_rd(instruction);
         let uimm = c_extract_uimm_5_4_0(instruction);
 
@@ -3759,7 +4291,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_srai(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_srai(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_7_9_rd(instruction);
         let uimm = c_extract_uimm_5_4_0(instruction);
 
@@ -3768,7 +4300,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_andi(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_andi(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_7_9_rd(instruction);
         let imm = extend_sign_6bit(c_extract_imm_5_4_0(instruction));
 
@@ -3777,7 +4309,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_sub(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_sub(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_7_9_rd(instruction);
         let rs2 = c_extract_2_4_rs2(instruction);
 
@@ -3786,7 +4318,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_xor(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_xor(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_7_9_rd(instruction);
         let rs2 = c_extract_2_4_rs2(instruction);
 
@@ -3795,7 +4327,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_or(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_or(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_7_9_rd(instruction);
         let rs2 = c_extract_2_4_rs2(instruction);
 
@@ -3804,7 +4336,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_and(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_and(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_7_9_rd(instruction);
         let rs2 = c_extract_2_4_rs2(instruction);
 
@@ -3813,7 +4345,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_subw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_subw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_7_9_rd(instruction);
         let rs2 = c_extract_2_4_rs2(instruction);
 
@@ -3824,7 +4356,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_addw(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_addw(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = c_extract_7_9_rd(instruction);
         let rs2 = c_extract_2_4_rs2(instruction);
 
@@ -3835,13 +4367,13 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_j(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_j(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let offset = extend_sign_12bit(c_extract_offset_11_4_9_8_10_6_7_3_1_5(instruction));
 
         self.progress_pc(self.pc.wrapping_add(offset))
     }
 
-    fn c_beqz(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_beqz(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = c_extract_7_9_rs1(instruction);
         let offset = extend_sign_9bit(c_extract_offset_8_4_3_7_6_2_1_5(instruction));
 
@@ -3852,7 +4384,7 @@ impl Rv64SGEmulator {
         }
     }
 
-    fn c_bnez(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_bnez(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = c_extract_7_9_rs1(instruction);
         let offset = extend_sign_9bit(c_extract_offset_8_4_3_7_6_2_1_5(instruction));
 
@@ -3863,7 +4395,7 @@ impl Rv64SGEmulator {
         }
     }
 
-    fn c_slli(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_slli(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let uimm = c_extract_uimm_5_4_0(instruction);
 
@@ -3874,7 +4406,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_lwsp(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_lwsp(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let uimm = c_extract_uimm_5_4_2_7_6(instruction);
 
@@ -3887,7 +4419,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_ldsp(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_ldsp(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let uimm = c_extract_uimm_5_4_3_8_6(instruction);
 
@@ -3899,13 +4431,13 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_jr(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_jr(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = c_extract_7_11_rs1(instruction);
 
         self.progress_pc(self.registers[rs1])
     }
 
-    fn c_mv(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_mv(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs2 = c_extract_2_6_rs2(instruction);
 
@@ -3916,21 +4448,21 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    // 未実装
-    fn c_ebreak(&mut self, _: &Vec<u8>) -> Option<()> {
-        None
+    // セミホスティングの呼び出しは32bitのebreakのみなので、常にブレークポイント例外になる。
+    fn c_ebreak(&mut self, _: &Vec<u8>) -> Result<(), Trap> {
+        Err(Exception::Breakpoint(self.pc).into())
     }
 
-    fn c_jalr(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_jalr(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs1 = c_extract_7_11_rs1(instruction);
 
         let t = self.pc.wrapping_add(2);
         self.progress_pc(self.registers[rs1])?;
         self.registers[1] = t;
-        Some(())
+        Ok(())
     }
 
-    fn c_add(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_add(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rd = extract_rd(instruction);
         let rs2 = c_extract_2_6_rs2(instruction);
 
@@ -3941,7 +4473,7 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_swsp(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_swsp(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs2 = c_extract_2_6_rs2(instruction);
         let uimm = c_extract_uimm_5_2_7_6(instruction);
 
@@ -3953,13 +4485,10 @@ impl Rv64SGEmulator {
         self.progress_pc(self.pc.wrapping_add(2))
     }
 
-    fn c_sdsp(&mut self, instruction: &Vec<u8>) -> Option<()> {
+    fn c_sdsp(&mut self, instruction: &Vec<u8>) -> Result<(), Trap> {
         let rs2 = c_extract_2_6_rs2(instruction);
         let uimm = c_extract_uimm_5_3_8_6(instruction);
 
-        println!("uimm: {:x}", uimm);
-        println!("sp: {:x}", self.registers[2]);
-
         self.save_memory_64bit(
             self.registers[2].wrapping_add(uimm) as usize,
             self.registers[rs2],
@@ -4000,14 +4529,31 @@ pub const FFLAGS: usize = 0x001;
 pub const FRM: usize = 0x002;
 pub const FCSR: usize = 0x003;
 
+pub const TIME: usize = 0xc01;
+
+pub const S_STATUS: usize = 0x100;
+pub const S_IE: usize = 0x104;
+pub const S_TVEC: usize = 0x105;
+pub const S_EPC: usize = 0x141;
+pub const S_CAUSE: usize = 0x142;
+pub const S_TVAL: usize = 0x143;
+pub const S_IP: usize = 0x144;
+pub const S_ATP: usize = 0x180;
+
 pub const M_STATUS: usize = 0x300;
 pub const M_EDELEG: usize = 0x302;
 pub const M_IDELEG: usize = 0x303;
+pub const M_IE: usize = 0x304;
 pub const M_TVEC: usize = 0x305;
 pub const M_EPC: usize = 0x341;
 pub const M_CAUSE: usize = 0x342;
+pub const M_TVAL: usize = 0x343;
+pub const M_IP: usize = 0x344;
 pub const M_HARTID: usize = 0xf14;
 
+// sstatusから見えるmstatusのビット
+const S_STATUS_MASK: u64 = 0x80000003000de762;
+
 pub struct CsrStatus {
     readable: bool,
     writreable: bool,
@@ -4041,110 +4587,240 @@ impl Rv64SGEmulator {
         self.csrs[M_HARTID] = 0;
     }
 
-    fn read_csr(&mut self, rv_csr: usize) -> Option<u64> {
-        let csr_status = CsrStatus::from_usize(&self.mode, rv_csr).unwrap();
+    // csrrs/csrrcなどで書き戻す値の元にする値
+    // mip/sipはデバイスが立てている割り込みを含めず、ソフトウェアが書き込んだ値を使う。
+    fn csr_rmw_base(&self, rv_csr: usize, value: u64) -> u64 {
+        match rv_csr {
+            M_IP => self.mip_software,
+            S_IP => self.mip_software & self.csrs[M_IDELEG],
+            _ => value,
+        }
+    }
+
+    // 権限が無いCSRにアクセスした場合はIllegalInstructionを返す。
+    // tvalは呼び出し側で命令の値に置き換える。
+    fn read_csr(&mut self, rv_csr: usize) -> Result<u64, Trap> {
+        let csr_status =
+            CsrStatus::from_usize(&self.mode, rv_csr).ok_or(Exception::IllegalInstruction(0))?;
 
         if !csr_status.readable && rv_csr >= self.csrs.len() {
-            self.set_exception_cause(2)?;
+            return Err(Exception::IllegalInstruction(0).into());
         }
+        self.check_satp_access(rv_csr)?;
 
         match rv_csr {
-            FRM => Some((self.csrs[FCSR] & 0xe0) >> 5),
-            FFLAGS => Some(self.csrs[FCSR] & 0x1f),
-            rv_csr => Some(self.csrs[rv_csr]),
+            FRM => Ok((self.csrs[FCSR] & 0xe0) >> 5),
+            FFLAGS => Ok(self.csrs[FCSR] & 0x1f),
+            S_STATUS => Ok(self.csrs[M_STATUS] & S_STATUS_MASK),
+            S_IE => Ok(self.csrs[M_IE] & self.csrs[M_IDELEG]),
+            S_IP => Ok(self.csrs[M_IP] & self.csrs[M_IDELEG]),
+            TIME => Ok(self
+                .bus
+                .find_device::<Clint>()
+                .map_or(0, |clint| clint.mtime())),
+            rv_csr => Ok(self.csrs[rv_csr]),
         }
     }
 
-    fn write_csr(&mut self, rv_csr: usize, value: u64) -> Option<()> {
-        let csr_status = CsrStatus::from_usize(&self.mode, rv_csr).unwrap();
+    fn write_csr(&mut self, rv_csr: usize, value: u64) -> Result<(), Trap> {
+        let csr_status =
+            CsrStatus::from_usize(&self.mode, rv_csr).ok_or(Exception::IllegalInstruction(0))?;
 
         if !csr_status.writreable && rv_csr >= self.csrs.len() {
-            self.set_exception_cause(2)?;
+            return Err(Exception::IllegalInstruction(0).into());
         }
+        self.check_satp_access(rv_csr)?;
+        self.trace_csr_write(rv_csr);
 
         match rv_csr {
             FCSR => {
                 self.csrs[FCSR] = value & 0xff;
-                Some(())
+                Ok(())
             }
             FRM => {
                 self.csrs[FCSR] = (self.csrs[FCSR] & 0x1f) + ((value & 0x7) << 5);
-                Some(())
+                Ok(())
             }
             FFLAGS => {
                 self.csrs[FCSR] = (self.csrs[FCSR] & 0xe0) + (value & 0x1f);
-                Some(())
+                Ok(())
             }
             M_STATUS => {
+                // MPPに存在しないモード(2)を書いた場合はspikeと同じくU-modeにする。
+                let value = if (value >> 11) & 0x3 == 2 {
+                    value & !0x1800
+                } else {
+                    value
+                };
                 self.csrs[rv_csr] = value & 0x8000003f007fffea;
-                Some(())
+                Ok(())
             }
             M_EDELEG => {
-                self.csrs[M_EDELEG] = value & 0xffff0000ff00bbff;
-                Some(())
+                // M-modeからのecallは委譲できない。
+                self.csrs[M_EDELEG] = value & 0xffff0000ff00b3ff;
+                Ok(())
+            }
+            S_ATP => {
+                if is_supported_satp(value) {
+                    self.csrs[S_ATP] = value;
+                    self.flush_tlb(None, None);
+                }
+                Ok(())
+            }
+            M_IDELEG => {
+                self.csrs[M_IDELEG] = value & 0x222;
+                Ok(())
+            }
+            M_IE => {
+                self.csrs[M_IE] = value & 0xaaa;
+                Ok(())
+            }
+            // MSIP/MTIPはCLINTが書き換えるので、S-modeの割り込みのみ書き込める。
+            M_IP => {
+                self.set_mip_software(value & 0x222);
+                Ok(())
+            }
+            S_IE => {
+                let mask = self.csrs[M_IDELEG];
+                self.csrs[M_IE] = (self.csrs[M_IE] & !mask) | (value & mask & 0x222);
+                Ok(())
             }
-            M_TVEC => {
+            // sipから書き込めるのはSSIPのみ
+            S_IP => {
+                let mask = self.csrs[M_IDELEG] & 0x2;
+                self.set_mip_software((self.mip_software & !mask) | (value & mask));
+                Ok(())
+            }
+            S_STATUS => {
+                let mstatus = (self.csrs[M_STATUS] & !S_STATUS_MASK) | (value & S_STATUS_MASK);
+                self.csrs[M_STATUS] = mstatus & 0x8000003f007fffea;
+                Ok(())
+            }
+            M_TVEC | S_TVEC => {
                 if !((value & 0x3) > 1) {
-                    self.csrs[M_TVEC] = value;
+                    self.csrs[rv_csr] = value;
                 }
 
-                Some(())
+                Ok(())
             }
-            M_EPC => {
-                self.csrs[M_EPC] = value & 0xfffffffffffffffe;
-                Some(())
+            M_EPC | S_EPC => {
+                self.csrs[rv_csr] = value & 0xfffffffffffffffe;
+                Ok(())
             }
             rv_csr => {
                 self.csrs[rv_csr] = value;
-                Some(())
+                Ok(())
             }
         }
     }
 
-    // 命令の中で例外が起こったときに呼ばれる関数
-    // これはモードに関係なく実行することができるが命令の正規の実行時には呼ばない。
-    // Noneを常時返す。
-    fn set_exception_cause(&mut self, cause: u64) -> Option<()> {
-        self.csrs[M_CAUSE] = cause;
-        None
+    // mstatus.TVMが立っている場合、S-modeからsatpにアクセスできない。
+    fn check_satp_access(&self, rv_csr: usize) -> Result<(), Trap> {
+        if rv_csr == S_ATP && self.mode == MachineMode::S && self.csrs[M_STATUS] & 0x100000 != 0 {
+            return Err(Exception::IllegalInstruction(0).into());
+        }
+
+        Ok(())
     }
 
-    fn call_exception(&mut self) {
+    // デバイスが立てている割り込みをmipに反映する。
+    // mipはソフトウェアが書き込んだ値とデバイスの割り込みの論理和で、
+    // PLICが下げたSEIPはソフトウェアが立てていなければmipからも消える。
+    fn update_mip(&mut self) {
+        self.csrs[M_IP] = self.mip_software | self.bus.pending_interrupts();
+    }
+
+    pub(super) fn set_mip_software(&mut self, value: u64) {
+        self.mip_software = value;
+        self.update_mip();
+    }
+
+    // 今受け付けるべき割り込みを優先度順に探す関数
+    // M-modeで処理する割り込みはM-mode未満かmstatus.MIEが立っている場合、
+    // S-modeに委譲された割り込みはS-mode未満かS-modeでmstatus.SIEが立っている場合に受け付ける。
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.csrs[M_IP] & self.csrs[M_IE];
+        if pending == 0 {
+            return None;
+        }
+
+        let mstatus = self.csrs[M_STATUS];
+        let m_enabled = self.mode != MachineMode::M || mstatus & 0x8 != 0;
+        let s_enabled =
+            self.mode == MachineMode::U || (self.mode == MachineMode::S && mstatus & 0x2 != 0);
+        let m_pending = if m_enabled {
+            pending & !self.csrs[M_IDELEG]
+        } else {
+            0
+        };
+        let s_pending = if s_enabled {
+            pending & self.csrs[M_IDELEG]
+        } else {
+            0
+        };
+
+        [
+            Interrupt::MachineExternal,
+            Interrupt::MachineSoftware,
+            Interrupt::MachineTimer,
+            Interrupt::SupervisorExternal,
+            Interrupt::SupervisorSoftware,
+            Interrupt::SupervisorTimer,
+        ]
+        .into_iter()
+        .find(|interrupt| (m_pending | s_pending) & (1 << interrupt.cause()) != 0)
+    }
+
+    // 例外・割り込みが起こったときに呼ばれる関数
+    // 移行先のモードのepc/cause/tvalを設定し、tvecに飛ぶ。
+    fn call_exception(&mut self, trap: Trap) {
         let current_mode = self.mode;
-        let mcause = self.csrs[M_CAUSE];
+        let cause = trap.cause();
 
-        if mcause >> 63 == 0 {
-            if !(self.mode == MachineMode::M) && self.csrs[M_EDELEG] == mcause {
-                self.mode = MachineMode::S;
-            } else {
-                self.mode = MachineMode::M;
-            }
+        // M-mode以外で起こり、medeleg/midelegの該当ビットが立っている場合はS-modeで処理する。
+        let deleg = if trap.is_interrupt() {
+            self.csrs[M_IDELEG]
+        } else {
+            self.csrs[M_EDELEG]
+        };
+        if !(self.mode == MachineMode::M) && (deleg >> (cause & 0x3f)) & 1 == 1 {
+            self.mode = MachineMode::S;
         } else {
+            self.mode = MachineMode::M;
         }
 
-        match self.mode {
-            MachineMode::M => {
-                self.write_csr(M_EPC, self.pc).unwrap();
+        let (epc, cause_csr, tval, tvec) = match self.mode {
+            MachineMode::M => (M_EPC, M_CAUSE, M_TVAL, M_TVEC),
+            _ => (S_EPC, S_CAUSE, S_TVAL, S_TVEC),
+        };
 
-                let mtvec = self.read_csr(M_TVEC).unwrap();
-                let mut mstatus = self.read_csr(M_STATUS).unwrap() & 0xffffffffffffe6ff;
-                mstatus = (mstatus & 0xffffffffffffff77) | ((mstatus & 0x8) << 4);
-                mstatus = mstatus
-                    | ((current_mode as u64) << 11)
-                    | (((((current_mode.to_usize() + 1) & 0x2) >> 1) as u64) << 8);
+        self.csrs[epc] = self.pc & 0xfffffffffffffffe;
+        self.csrs[cause_csr] = cause;
+        self.csrs[tval] = trap.tval();
 
-                self.write_csr(M_STATUS, mstatus).unwrap();
+        if self.mode == MachineMode::M {
+            // MPIEにMIEを退避してMIEを0にし、MPPに元のモードを入れる。SPPは変えない。
+            let mut mstatus = self.csrs[M_STATUS] & !0x1888;
+            mstatus |= (self.csrs[M_STATUS] & 0x8) << 4;
+            mstatus |= (current_mode as u64) << 11;
 
-                if mtvec & 0x3 == 1 && mcause >> 63 == 1 {
-                    self.progress_pc(
-                        (mtvec & 0xfffffffffffffffc) + 4 * (mcause & 0x7fffffffffffffff),
-                    )
-                    .unwrap();
-                } else {
-                    self.progress_pc(mtvec & 0xfffffffffffffffc);
-                }
+            self.csrs[M_STATUS] = mstatus;
+        } else {
+            // SPIEにSIEを退避してSIEを0にし、SPPに元のモードを入れる。
+            let mut mstatus = self.csrs[M_STATUS] & 0xfffffffffffffedd;
+            mstatus |= (self.csrs[M_STATUS] & 0x2) << 4;
+            if current_mode == MachineMode::S {
+                mstatus |= 0x100;
             }
-            _ => {}
+
+            self.csrs[M_STATUS] = mstatus;
+        }
+
+        let tvec = self.csrs[tvec];
+        if tvec & 0x3 == 1 && trap.is_interrupt() {
+            self.pc = (tvec & 0xfffffffffffffffc) + 4 * (cause & 0x7fffffffffffffff);
+        } else {
+            self.pc = tvec & 0xfffffffffffffffc;
         }
     }
 }
@@ -0,0 +1,377 @@
+use std::{
+    io::{self, BufRead, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{
+    debug::{StopReason, WatchKind, ABI_NAMES, CSR_NAMES, FPR_ABI_NAMES, SIGKILL},
+    disasm::{disassemble, instruction_length},
+    ExitReason, ExitStatus, Rv64SGEmulator,
+};
+
+const SIGINT: i32 = 2;
+
+// disasで表示する命令数と、xで表示するバイト数の既定値
+const DEFAULT_DISAS_COUNT: u64 = 10;
+const DEFAULT_EXAMINE_SIZE: u64 = 64;
+
+const HELP: &str = "\
+s, step [n]              n命令実行する
+c, continue              ブレークポイントかCtrl-Cまで実行する
+r, regs                  整数レジスタを表示する
+fr, fregs                浮動小数点レジスタを表示する
+csrs                     CSRを表示する
+csr <name|num>           CSRを1つ表示する
+set <reg> <value>        レジスタ(pc, x0-x31, f0-f31, ABI名, CSR名)を変更する
+x <addr> [len]           メモリを表示する
+w <addr> <value> [size]  メモリにsizeバイト(既定は8)書き込む
+disas [addr] [n]         addr(既定はpc)からn命令表示する
+b, break <addr|symbol>   ブレークポイントを設定する
+delete <addr|symbol>     ブレークポイントを削除する
+bl, breakpoints          ブレークポイントの一覧を表示する
+watch <addr> [len]       書き込みのウォッチポイントを設定する
+q, quit                  エミュレータを終了する
+空行は直前のコマンドを繰り返す。";
+
+// SIGINTを受け取ったかどうか
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+}
+
+extern "C" fn handle_sigint(_: i32) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// Ctrl-Cで実行中のゲストを止めてモニタに戻れるようにする。
+pub fn install_interrupt_handler() {
+    // 標準ライブラリがリンクしているlibcのsignalを使う。
+    unsafe {
+        signal(SIGINT, handle_sigint as extern "C" fn(i32) as usize);
+    }
+}
+
+// 0xで始まる場合は16進数、それ以外は10進数として読む。負の数は2の補数にする。
+fn parse_number(text: &str) -> Option<u64> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let value = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => text.parse().ok()?,
+    };
+
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+// 変更できるレジスタ
+enum Register {
+    Pc,
+    X(usize),
+    F(usize),
+    Csr(usize),
+}
+
+fn parse_register(name: &str) -> Option<Register> {
+    if name == "pc" {
+        return Some(Register::Pc);
+    }
+    let index = |prefix: &str| {
+        name.strip_prefix(prefix)
+            .and_then(|index| index.parse::<usize>().ok())
+            .filter(|index| *index < 32)
+    };
+    if let Some(index) = index("x") {
+        return Some(Register::X(index));
+    }
+    if let Some(index) = index("f") {
+        return Some(Register::F(index));
+    }
+
+    ABI_NAMES
+        .iter()
+        .position(|abi_name| *abi_name == name)
+        .map(Register::X)
+        .or_else(|| {
+            FPR_ABI_NAMES
+                .iter()
+                .position(|abi_name| *abi_name == name)
+                .map(Register::F)
+        })
+        .or_else(|| parse_csr(name).map(Register::Csr))
+}
+
+fn parse_csr(name: &str) -> Option<usize> {
+    CSR_NAMES
+        .iter()
+        .find(|(csr_name, _)| *csr_name == name)
+        .map(|(_, csr)| *csr)
+        .or_else(|| parse_number(name).map(|csr| csr as usize))
+        .filter(|csr| *csr < 4096)
+}
+
+impl Rv64SGEmulator {
+    // 標準入出力でモニタを操作しながら実行する関数
+    // 最初の命令の前で止まり、入力が終わった場合はそのまま最後まで実行する。
+    pub fn exec_with_monitor(
+        &mut self,
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+    ) -> io::Result<ExitStatus> {
+        self.initialize_csrs();
+        writeln!(output, "monitor: stopped at {:#x} (help: h)", self.pc)?;
+
+        let mut last = String::new();
+        loop {
+            write!(output, "(monitor) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(self.exec_program());
+            }
+            if !line.trim().is_empty() {
+                last = line.trim().to_string();
+            }
+
+            if let Some(reason) = self.monitor_command(&last, output)? {
+                return Ok(self.exit_status(reason));
+            }
+        }
+    }
+
+    // 1行のコマンドを実行する。ゲストが終了した場合はその理由を返す。
+    fn monitor_command(
+        &mut self,
+        line: &str,
+        output: &mut dyn Write,
+    ) -> io::Result<Option<ExitReason>> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arg = |n: usize| words.get(n).copied();
+        let number = |n: usize| arg(n).and_then(parse_number);
+
+        match arg(0).unwrap_or_default() {
+            "s" | "step" => {
+                let count = number(1).unwrap_or(1);
+                for _ in 0..count {
+                    if let Some(stop) = self.debug_step() {
+                        return self.report_stop(stop, output);
+                    }
+                }
+                self.print_location(output)?;
+            }
+            "c" | "continue" => {
+                INTERRUPTED.store(false, Ordering::Relaxed);
+                let stop = self.debug_continue(|| INTERRUPTED.swap(false, Ordering::Relaxed));
+                return self.report_stop(stop, output);
+            }
+            "r" | "regs" => self.print_registers(output)?,
+            "fr" | "fregs" => {
+                for (i, name) in FPR_ABI_NAMES.iter().enumerate() {
+                    let value = self.f_registers[i];
+                    writeln!(
+                        output,
+                        "{:<4} {:#018x} {}",
+                        name,
+                        value,
+                        f64::from_bits(value)
+                    )?;
+                }
+            }
+            "csrs" => {
+                for (name, csr) in CSR_NAMES {
+                    if let Some(value) = self.debug_read_csr(csr) {
+                        writeln!(output, "{:<10} {:#018x}", name, value)?;
+                    }
+                }
+            }
+            "csr" => match arg(1).and_then(parse_csr) {
+                Some(csr) => match self.debug_read_csr(csr) {
+                    Some(value) => writeln!(output, "{:#018x}", value)?,
+                    None => writeln!(output, "cannot read csr {:#x}", csr)?,
+                },
+                None => writeln!(output, "usage: csr <name|num>")?,
+            },
+            "set" => match (arg(1).and_then(parse_register), number(2)) {
+                (Some(register), Some(value)) => {
+                    let written = match register {
+                        Register::Pc => {
+                            self.pc = value;
+                            true
+                        }
+                        Register::X(0) => false,
+                        Register::X(index) => {
+                            self.registers[index] = value;
+                            true
+                        }
+                        Register::F(index) => {
+                            self.f_registers[index] = value;
+                            true
+                        }
+                        Register::Csr(csr) => self.debug_write_csr(csr, value).is_some(),
+                    };
+                    if !written {
+                        writeln!(output, "cannot write {}", words[1])?;
+                    }
+                }
+                _ => writeln!(output, "usage: set <reg> <value>")?,
+            },
+            "x" => match number(1) {
+                Some(address) => {
+                    let size = number(2).unwrap_or(DEFAULT_EXAMINE_SIZE);
+                    self.print_memory(address, size, output)?;
+                }
+                None => writeln!(output, "usage: x <addr> [len]")?,
+            },
+            "w" => match (number(1), number(2)) {
+                (Some(address), Some(value)) => {
+                    let size = number(3).unwrap_or(8).min(8) as usize;
+                    if self
+                        .debug_write_memory(address, &value.to_le_bytes()[..size])
+                        .is_none()
+                    {
+                        writeln!(output, "cannot write {:#x}", address)?;
+                    }
+                }
+                _ => writeln!(output, "usage: w <addr> <value> [size]")?,
+            },
+            "disas" => {
+                let mut address = number(1).unwrap_or(self.pc);
+                for _ in 0..number(2).unwrap_or(DEFAULT_DISAS_COUNT) {
+                    match self.monitor_disassemble(address) {
+                        Some((length, text)) => {
+                            let marker = if address == self.pc { "=>" } else { "  " };
+                            writeln!(output, "{} {:#018x}: {}", marker, address, text)?;
+                            address += length;
+                        }
+                        None => {
+                            writeln!(output, "cannot read {:#x}", address)?;
+                            break;
+                        }
+                    }
+                }
+            }
+            "b" | "break" | "delete" => match arg(1).and_then(|arg| self.parse_location(arg)) {
+                Some(address) if words[0] == "delete" => {
+                    if !self.debugger.remove_breakpoint(address) {
+                        writeln!(output, "no breakpoint at {:#x}", address)?;
+                    }
+                }
+                Some(address) => {
+                    self.debugger.add_breakpoint(address);
+                    writeln!(output, "breakpoint at {:#x}", address)?;
+                }
+                None => writeln!(output, "usage: {} <addr|symbol>", words[0])?,
+            },
+            "bl" | "breakpoints" => {
+                for address in self.debugger.breakpoints() {
+                    writeln!(output, "{:#x}", address)?;
+                }
+            }
+            "watch" => match arg(1).and_then(|arg| self.parse_location(arg)) {
+                Some(address) => {
+                    let length = number(2).unwrap_or(8);
+                    self.debugger
+                        .add_watchpoint(address, length, WatchKind::Write);
+                    writeln!(output, "watchpoint at {:#x}", address)?;
+                }
+                None => writeln!(output, "usage: watch <addr> [len]")?,
+            },
+            "q" | "quit" => return Ok(Some(ExitReason::Fail(SIGKILL))),
+            "h" | "help" => writeln!(output, "{}", HELP)?,
+            "" => {}
+            command => writeln!(output, "unknown command: {}", command)?,
+        }
+
+        Ok(None)
+    }
+
+    // アドレスの代わりにシンボル名も使える。
+    fn parse_location(&self, text: &str) -> Option<u64> {
+        self.symbols
+            .get(text)
+            .copied()
+            .or_else(|| parse_number(text))
+    }
+
+    fn report_stop(
+        &mut self,
+        stop: StopReason,
+        output: &mut dyn Write,
+    ) -> io::Result<Option<ExitReason>> {
+        match stop {
+            StopReason::Exited(reason) => {
+                writeln!(output, "exited: {:?}", reason)?;
+                return Ok(Some(reason));
+            }
+            StopReason::Breakpoint => writeln!(output, "breakpoint at {:#x}", self.pc)?,
+            StopReason::Watchpoint(_, address) => writeln!(output, "watchpoint {:#x}", address)?,
+            StopReason::Interrupted => writeln!(output, "interrupted")?,
+            StopReason::Step => {}
+        }
+
+        self.print_location(output)?;
+        Ok(None)
+    }
+
+    fn print_location(&mut self, output: &mut dyn Write) -> io::Result<()> {
+        match self.monitor_disassemble(self.pc) {
+            Some((_, text)) => writeln!(output, "{:#018x}: {}", self.pc, text),
+            None => writeln!(output, "{:#018x}: cannot read", self.pc),
+        }
+    }
+
+    // pcと整数レジスタを4つずつ表示する。
+    pub(super) fn print_registers(&self, output: &mut dyn Write) -> io::Result<()> {
+        writeln!(output, "pc   {:#018x}", self.pc)?;
+        for (i, name) in ABI_NAMES.iter().enumerate() {
+            write!(output, "{:<4} {:#018x}", name, self.registers[i])?;
+            write!(output, "{}", if i % 4 == 3 { "\n" } else { "  " })?;
+        }
+
+        Ok(())
+    }
+
+    // addressの命令の長さと、命令のビット列を逆アセンブルの結果とともに返す。
+    fn monitor_disassemble(&mut self, address: u64) -> Option<(u64, String)> {
+        let low = self.debug_fetch_memory(address, 2)?;
+        let low = u16::from_le_bytes([low[0], low[1]]) as u32;
+        if instruction_length(low) == 2 {
+            return Some((2, format!("{:04x}      {}", low, disassemble(low, address))));
+        }
+
+        let high = self.debug_fetch_memory(address.wrapping_add(2), 2)?;
+        let instruction = low | (u16::from_le_bytes([high[0], high[1]]) as u32) << 16;
+        Some((
+            4,
+            format!("{:08x}  {}", instruction, disassemble(instruction, address)),
+        ))
+    }
+
+    // 16バイトごとにアドレスとともに表示する。
+    fn print_memory(&mut self, address: u64, size: u64, output: &mut dyn Write) -> io::Result<()> {
+        for line in (0..size).step_by(16) {
+            let line_address = address.wrapping_add(line);
+            let length = (size - line).min(16);
+            match self.debug_read_memory(line_address, length) {
+                Some(bytes) => {
+                    let hex: Vec<String> =
+                        bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                    writeln!(output, "{:#018x}: {}", line_address, hex.join(" "))?;
+                }
+                None => {
+                    writeln!(output, "cannot read {:#x}", line_address)?;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
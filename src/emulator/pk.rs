@@ -0,0 +1,80 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{
+    linux::{LinuxUser, AT_FDCWD},
+    Rv64SGEmulator,
+};
+
+// riscv-pk独自のシステムコール番号
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_OPEN: u64 = 1024;
+
+// magic memoryはシステムコール番号と引数7つの配列
+const MAGIC_MEM_WORDS: u64 = 8;
+
+// スタックとして使うためにbrkで広げない領域の大きさ
+const PK_STACK_SIZE: u64 = 0x1_0000;
+
+impl Rv64SGEmulator {
+    // riscv-pkの代わりにnewlibのシステムコールをホストで処理できるようにする関数
+    // ecallはどのモードからでもシステムコールとして扱い、tohostに書き込まれたmagic memoryのアドレスも受け付ける。
+    // brkの領域はリンカが定義する_endからスタックの手前までにする。
+    pub fn enable_proxy_kernel(&mut self) {
+        let sp = self.registers[2];
+        let limit = sp.saturating_sub(PK_STACK_SIZE);
+        let brk = self
+            .symbols
+            .get("_end")
+            .or_else(|| self.symbols.get("end"))
+            .map_or(limit, |end| (end + 0xf) & !0xf);
+
+        self.linux = Some(LinuxUser::proxy_kernel(brk.min(limit), limit));
+    }
+
+    pub(super) fn is_proxy_kernel(&self) -> bool {
+        self.linux
+            .as_ref()
+            .is_some_and(|linux| linux.is_proxy_kernel())
+    }
+
+    // riscv-pkのシステムコールを実行する関数
+    // 番号はLinuxと共通のものが多いので、pk独自のもの以外はLinuxのシステムコールとして処理する。
+    pub(super) fn pk_syscall(&mut self, number: u64, args: &[u64; 6]) -> Result<u64, i64> {
+        match number {
+            // pkはフラグをそのままホストに渡すので、Linuxと同じ値として扱う。
+            SYS_OPEN => self.sys_openat(AT_FDCWD as u64, args[0], args[1], args[2]),
+            SYS_GETTIMEOFDAY => {
+                let time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let mut timeval = time.as_secs().to_le_bytes().to_vec();
+                timeval.extend((time.subsec_micros() as u64).to_le_bytes());
+                self.write_guest(args[0], &timeval)?;
+                Ok(0)
+            }
+            number => self.syscall(number, args),
+        }
+    }
+
+    // tohostに書き込まれたmagic memoryのシステムコールを処理する関数
+    // 結果はmagic memoryの先頭に書き戻す。
+    pub(super) fn magic_mem_syscall(&mut self, address: u64) {
+        let words = match self.read_guest(address, 8 * MAGIC_MEM_WORDS) {
+            Ok(bytes) => bytes
+                .chunks(8)
+                .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+                .collect::<Vec<_>>(),
+            Err(_) => {
+                eprintln!("pk: magic memory {:#x} is outside of memory", address);
+                return;
+            }
+        };
+
+        let args = [words[1], words[2], words[3], words[4], words[5], words[6]];
+        let result = match self.pk_syscall(words[0], &args) {
+            Ok(value) => value,
+            Err(errno) => (-errno) as u64,
+        };
+        let _ = self.write_guest(address, &result.to_le_bytes());
+    }
+}
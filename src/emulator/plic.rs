@@ -0,0 +1,209 @@
+use std::any::Any;
+
+use super::{
+    bus::{AccessFault, Device},
+    fdt::{FdtWriter, CPU_INTC_PHANDLE, PLIC_PHANDLE},
+};
+
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+pub const PLIC_SIZE: u64 = 0x0400_0000;
+// QEMUのvirtボードと同じ割り込み源の数(0番は使わない)
+pub const PLIC_SOURCES: usize = 96;
+// ハート0のM-modeとS-mode
+pub const PLIC_CONTEXTS: usize = 2;
+
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+// Platform-Level Interrupt Controller
+// 割り込み源はレベルトリガで、claimされてからcompleteされるまでは再びpendingにならない。
+// context 2n, 2n + 1がそれぞれハートnのM-mode, S-modeに対応する。
+pub struct Plic {
+    priority: Vec<u32>,
+    pending: Vec<bool>,
+    claimed: Vec<bool>,
+    enable: Vec<Vec<bool>>,
+    threshold: Vec<u32>,
+}
+
+impl Plic {
+    pub fn new(sources: usize, contexts: usize) -> Self {
+        Plic {
+            priority: vec![0; sources],
+            pending: vec![false; sources],
+            claimed: vec![false; sources],
+            enable: vec![vec![false; sources]; contexts],
+            threshold: vec![0; contexts],
+        }
+    }
+
+    // デバイスの割り込み線の状態を反映する。
+    pub fn set_level(&mut self, source: usize, level: bool) {
+        if source == 0 || source >= self.pending.len() {
+            return;
+        }
+
+        self.pending[source] = level && !self.claimed[source];
+    }
+
+    // contextで受け付けられる最も優先度の高い割り込み源
+    // 優先度が同じ場合は番号の小さいものを選ぶ。
+    fn best_source(&self, context: usize) -> Option<usize> {
+        (1..self.pending.len())
+            .filter(|&source| {
+                self.pending[source]
+                    && self.enable[context][source]
+                    && self.priority[source] > self.threshold[context]
+            })
+            .fold(None, |best: Option<usize>, source| match best {
+                Some(best) if self.priority[best] >= self.priority[source] => Some(best),
+                _ => Some(source),
+            })
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_source(context) {
+            Some(source) => {
+                self.pending[source] = false;
+                self.claimed[source] = true;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: usize) {
+        if source < self.claimed.len() && self.enable[context][source] {
+            self.claimed[source] = false;
+        }
+    }
+
+    // 32個ずつのビット列として読み出す。
+    fn read_bits(bits: &[bool], word: usize) -> u32 {
+        (0..32)
+            .filter(|bit| bits.get(word * 32 + bit).copied().unwrap_or(false))
+            .fold(0, |value, bit| value | (1 << bit))
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, AccessFault> {
+        if size != 4 || offset & 0x3 != 0 {
+            return Err(AccessFault);
+        }
+
+        let contexts = self.threshold.len() as u64;
+        let value = match offset {
+            PRIORITY..PENDING => self
+                .priority
+                .get((offset / 4) as usize)
+                .copied()
+                .unwrap_or(0),
+            PENDING..ENABLE => Self::read_bits(&self.pending, ((offset - PENDING) / 4) as usize),
+            ENABLE..CONTEXT => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+                match self.enable.get(context) {
+                    Some(enable) => Self::read_bits(enable, word),
+                    None => 0,
+                }
+            }
+            _ => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    _ if context >= contexts => 0,
+                    0 => self.threshold[context as usize],
+                    4 => self.claim(context as usize),
+                    _ => 0,
+                }
+            }
+        };
+
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), AccessFault> {
+        if size != 4 || offset & 0x3 != 0 {
+            return Err(AccessFault);
+        }
+
+        let value = value as u32;
+        let contexts = self.threshold.len() as u64;
+        match offset {
+            PRIORITY..PENDING => {
+                // 0番の割り込み源は存在しない。
+                let source = (offset / 4) as usize;
+                if source != 0 && source < self.priority.len() {
+                    self.priority[source] = value & 0x7;
+                }
+            }
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+                if let Some(enable) = self.enable.get_mut(context) {
+                    for bit in 0..32 {
+                        let source = word * 32 + bit;
+                        if source != 0 && source < enable.len() {
+                            enable[source] = value & (1 << bit) != 0;
+                        }
+                    }
+                }
+            }
+            _ => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    _ if context >= contexts => {}
+                    0 => self.threshold[context as usize] = value & 0x7,
+                    4 => self.complete(context as usize, value as usize),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // context 0がMEIP、context 1がSEIPを立てる。
+    fn pending_interrupts(&self) -> u64 {
+        let mut mip = 0;
+        if self.best_source(0).is_some() {
+            mip |= 0x800;
+        }
+        if self.threshold.len() > 1 && self.best_source(1).is_some() {
+            mip |= 0x200;
+        }
+
+        mip
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("plic")
+    }
+
+    // 偶数番のcontextはMEIP(11)、奇数番のcontextはSEIP(9)につながる。
+    fn fdt_properties(&self, fdt: &mut FdtWriter) {
+        let interrupts: Vec<u32> = (0..self.threshold.len())
+            .flat_map(|context| [CPU_INTC_PHANDLE, if context % 2 == 0 { 11 } else { 9 }])
+            .collect();
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_cells("interrupts-extended", &interrupts);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_u32("riscv,ndev", self.pending.len() as u32 - 1);
+        fdt.property_u32("phandle", PLIC_PHANDLE);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
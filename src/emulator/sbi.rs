@@ -0,0 +1,280 @@
+use std::io::{self, Write};
+
+use super::{
+    clint::Clint, ExitReason, MachineMode, Rv64SGEmulator, Uart, M_EDELEG, M_IDELEG, M_STATUS,
+    S_ATP,
+};
+
+const EXT_LEGACY_SET_TIMER: u64 = 0x00;
+const EXT_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const EXT_LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4d45;
+const EXT_IPI: u64 = 0x0073_5049;
+const EXT_RFENCE: u64 = 0x5246_4e43;
+const EXT_HSM: u64 = 0x0048_534d;
+const EXT_SRST: u64 = 0x5352_5354;
+
+const EXTENSIONS: [u64; 10] = [
+    EXT_LEGACY_SET_TIMER,
+    EXT_LEGACY_CONSOLE_PUTCHAR,
+    EXT_LEGACY_CONSOLE_GETCHAR,
+    EXT_LEGACY_SHUTDOWN,
+    EXT_BASE,
+    EXT_TIME,
+    EXT_IPI,
+    EXT_RFENCE,
+    EXT_HSM,
+    EXT_SRST,
+];
+
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_FAILED: i64 = -1;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// SBI仕様のバージョン(2.0)
+const SPEC_VERSION: u64 = 2 << 24;
+// 登録済みの実装IDと重ならない値にする。
+const IMPL_ID: u64 = 0x7564;
+const IMPL_VERSION: u64 = 1;
+
+const HSM_STATE_STARTED: u64 = 0;
+const HSM_SUSPEND_RETENTIVE: u64 = 0x0000_0000;
+const HSM_SUSPEND_NON_RETENTIVE: u64 = 0x8000_0000;
+
+const SRST_TYPE_SHUTDOWN: u64 = 0;
+const SRST_TYPE_COLD_REBOOT: u64 = 1;
+const SRST_TYPE_WARM_REBOOT: u64 = 2;
+
+// OpenSBIと同じく、S-modeで処理できる例外を委譲する。
+const DELEGATED_EXCEPTIONS: u64 = 0xb109;
+const DELEGATED_INTERRUPTS: u64 = 0x222;
+
+const MIP_SSIP: u64 = 0x2;
+const MIP_STIP: u64 = 0x20;
+
+// M-modeのファームウェアの代わりにS-modeからのecallを処理する。
+// ハートは1つだけなので、hartidは常に0になる。
+pub struct Sbi {
+    // set_timerで設定された時刻。mtimeがこれを超えるとSTIPを立てる。
+    timer: u64,
+}
+
+impl Sbi {
+    fn new() -> Self {
+        Sbi { timer: u64::MAX }
+    }
+}
+
+// hart_mask/hart_mask_baseがハート0を含むか判定する関数
+// 存在しないハートが含まれている場合はErrを返す。
+fn includes_hart0(hart_mask: u64, hart_mask_base: u64) -> Result<bool, i64> {
+    if hart_mask_base == u64::MAX {
+        return Ok(true);
+    }
+    if hart_mask_base != 0 && hart_mask != 0 {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+    if hart_mask & !1 != 0 {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+
+    Ok(hart_mask & 1 == 1)
+}
+
+impl Rv64SGEmulator {
+    // 組み込みのSBIを有効にし、OpenSBIが起動した後と同じようにS-modeから実行を始める。
+    pub fn enable_sbi(&mut self) {
+        self.sbi = Some(Sbi::new());
+        self.mode = MachineMode::S;
+        self.csrs[M_EDELEG] = DELEGATED_EXCEPTIONS;
+        self.csrs[M_IDELEG] = DELEGATED_INTERRUPTS;
+    }
+
+    // 1命令実行するごとに呼ばれ、set_timerの時刻を過ぎていればSTIPを立てる。
+    pub(super) fn update_sbi_timer(&mut self) {
+        let timer = match &self.sbi {
+            Some(sbi) => sbi.timer,
+            None => return,
+        };
+
+        if self.mtime() >= timer {
+            self.set_mip_software(self.mip_software | MIP_STIP);
+        }
+    }
+
+    fn mtime(&mut self) -> u64 {
+        self.bus
+            .find_device::<Clint>()
+            .map_or(0, |clint| clint.mtime())
+    }
+
+    // S-modeからのecallを処理し、次に実行するpcを返す関数
+    // a7が拡張ID、a6が関数IDで、結果はa0(エラー)とa1(値)に返す。
+    // レガシー拡張はa0にのみ値を返す。
+    pub(super) fn sbi_call(&mut self) -> u64 {
+        let eid = self.registers[17];
+        let fid = self.registers[16];
+        let args = [
+            self.registers[10],
+            self.registers[11],
+            self.registers[12],
+            self.registers[13],
+            self.registers[14],
+        ];
+        let next_pc = self.pc.wrapping_add(4);
+
+        let result = match eid {
+            EXT_LEGACY_SET_TIMER => {
+                self.sbi_set_timer(args[0]);
+                self.registers[10] = 0;
+                return next_pc;
+            }
+            EXT_LEGACY_CONSOLE_PUTCHAR => {
+                self.sbi_putchar(args[0] as u8);
+                self.registers[10] = 0;
+                return next_pc;
+            }
+            EXT_LEGACY_CONSOLE_GETCHAR => {
+                self.registers[10] = self.sbi_getchar().map_or(u64::MAX, |byte| byte as u64);
+                return next_pc;
+            }
+            EXT_LEGACY_SHUTDOWN => {
+                self.exit_reason = Some(ExitReason::Pass);
+                return next_pc;
+            }
+            EXT_BASE => self.sbi_base(fid, args[0]),
+            EXT_TIME => match fid {
+                0 => {
+                    self.sbi_set_timer(args[0]);
+                    Ok(0)
+                }
+                _ => Err(SBI_ERR_NOT_SUPPORTED),
+            },
+            EXT_IPI => match fid {
+                0 => includes_hart0(args[0], args[1]).map(|hart0| {
+                    if hart0 {
+                        self.set_mip_software(self.mip_software | MIP_SSIP);
+                    }
+                    0
+                }),
+                _ => Err(SBI_ERR_NOT_SUPPORTED),
+            },
+            EXT_RFENCE => self.sbi_rfence(fid, &args),
+            EXT_HSM if fid == 3 && args[0] == HSM_SUSPEND_NON_RETENTIVE => {
+                return self.sbi_resume(args[1], args[2]);
+            }
+            EXT_HSM => self.sbi_hsm(fid, args[0]),
+            EXT_SRST => match fid {
+                0 => self.sbi_system_reset(args[0], args[1]),
+                _ => Err(SBI_ERR_NOT_SUPPORTED),
+            },
+            _ => Err(SBI_ERR_NOT_SUPPORTED),
+        };
+
+        let (error, value) = match result {
+            Ok(value) => (SBI_SUCCESS, value),
+            Err(error) => (error, 0),
+        };
+        self.registers[10] = error as u64;
+        self.registers[11] = value;
+        next_pc
+    }
+
+    fn sbi_base(&mut self, fid: u64, arg: u64) -> Result<u64, i64> {
+        match fid {
+            0 => Ok(SPEC_VERSION),
+            1 => Ok(IMPL_ID),
+            2 => Ok(IMPL_VERSION),
+            3 => Ok(EXTENSIONS.contains(&arg) as u64),
+            // mvendorid, marchid, mimpid
+            4 => Ok(self.csrs[0xf11]),
+            5 => Ok(self.csrs[0xf12]),
+            6 => Ok(self.csrs[0xf13]),
+            _ => Err(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    // 新しい時刻を設定するとともに、保留中のタイマ割り込みを取り消す。
+    fn sbi_set_timer(&mut self, time: u64) {
+        if let Some(sbi) = &mut self.sbi {
+            sbi.timer = time;
+        }
+        self.set_mip_software(self.mip_software & !MIP_STIP);
+    }
+
+    // UARTが接続されている場合はその出力に、無い場合は標準出力に書き込む。
+    fn sbi_putchar(&mut self, byte: u8) {
+        match self.bus.find_device_mut::<Uart>() {
+            Some(uart) => uart.transmit(byte),
+            None => {
+                let _ = io::stdout().write_all(&[byte]);
+                let _ = io::stdout().flush();
+            }
+        }
+    }
+
+    fn sbi_getchar(&mut self) -> Option<u8> {
+        self.bus.find_device_mut::<Uart>()?.take_received()
+    }
+
+    // 命令キャッシュは無いのでfence.iは何もしない。
+    // sfence.vmaは範囲に関係なく全体を無効化する。
+    fn sbi_rfence(&mut self, fid: u64, args: &[u64; 5]) -> Result<u64, i64> {
+        let hart0 = includes_hart0(args[0], args[1])?;
+        match fid {
+            0 => {}
+            1 if hart0 => self.flush_tlb(None, None),
+            2 if hart0 => self.flush_tlb(None, Some(args[4] & 0xffff)),
+            1 | 2 => {}
+            _ => return Err(SBI_ERR_NOT_SUPPORTED),
+        }
+
+        Ok(0)
+    }
+
+    fn sbi_hsm(&mut self, fid: u64, arg: u64) -> Result<u64, i64> {
+        match fid {
+            // hart_start: ハート0は既に動いている。
+            0 if arg == 0 => Err(SBI_ERR_ALREADY_AVAILABLE),
+            0 => Err(SBI_ERR_INVALID_PARAM),
+            // hart_stop: 他に動いているハートが無いので止められない。
+            1 => Err(SBI_ERR_FAILED),
+            // hart_get_status
+            2 if arg == 0 => Ok(HSM_STATE_STARTED),
+            2 => Err(SBI_ERR_INVALID_PARAM),
+            // hart_suspend(retentive): 割り込みは毎命令確認しているので、wfiと同じくすぐに戻る。
+            3 if arg == HSM_SUSPEND_RETENTIVE => Ok(0),
+            3 => Err(SBI_ERR_INVALID_PARAM),
+            _ => Err(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    // non-retentiveなhart_suspendから戻る関数
+    // hart_startと同じく、satpとsstatus.SIEを0にしてresume_addrからS-modeで再開する。
+    fn sbi_resume(&mut self, resume_addr: u64, opaque: u64) -> u64 {
+        self.registers[10] = 0;
+        self.registers[11] = opaque;
+        self.csrs[S_ATP] = 0;
+        self.csrs[M_STATUS] &= !0x2;
+        self.flush_tlb(None, None);
+        resume_addr
+    }
+
+    // シャットダウン・再起動はどちらもエミュレータを終了させる。
+    // 理由がシステム障害(1)の場合は失敗として扱う。
+    fn sbi_system_reset(&mut self, reset_type: u64, reason: u64) -> Result<u64, i64> {
+        let exit = match (reset_type, reason) {
+            (SRST_TYPE_SHUTDOWN, 0) => ExitReason::Pass,
+            (SRST_TYPE_SHUTDOWN, reason) => ExitReason::Fail(reason),
+            (SRST_TYPE_COLD_REBOOT | SRST_TYPE_WARM_REBOOT, _) => ExitReason::Reboot,
+            _ => return Err(SBI_ERR_INVALID_PARAM),
+        };
+        self.exit_reason = Some(exit);
+
+        Ok(0)
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{self, Write},
+    time::Instant,
+};
+
+use super::{linux::HostFile, mmu::AccessType, trap::Trap, ExitReason, Rv64SGEmulator};
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+// ebreakの前後に置かれる命令
+const SEMIHOSTING_ENTRY: u64 = 0x01f0_1013; // slli x0, x0, 0x1f
+const SEMIHOSTING_EXIT: u64 = 0x4070_5013; // srai x0, x0, 7
+
+// ":tt"はモードによって標準入出力になる。
+const CONSOLE_NAME: &[u8] = b":tt";
+
+// SYS_READで一度にホストのファイルから読み込む大きさ
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+const EIO: u64 = 5;
+
+pub struct Semihosting {
+    files: HashMap<u64, HostFile>,
+    cmdline: String,
+    started: Instant,
+    // SYS_ERRNOで返す最後のエラー
+    errno: u64,
+}
+
+impl Semihosting {
+    fn new(cmdline: &str) -> Self {
+        Semihosting {
+            files: HashMap::new(),
+            cmdline: cmdline.to_string(),
+            started: Instant::now(),
+            errno: 0,
+        }
+    }
+
+    // ハンドルは0を使わずに1から割り当てる。
+    fn allocate_handle(&mut self, file: HostFile) -> u64 {
+        let handle = (1..)
+            .find(|handle| !self.files.contains_key(handle))
+            .unwrap();
+        self.files.insert(handle, file);
+        handle
+    }
+
+    // 失敗した場合はerrnoを記録して-1を返す。
+    fn result(&mut self, result: io::Result<u64>) -> u64 {
+        result.unwrap_or_else(|err| {
+            self.errno = err.raw_os_error().map_or(EIO, |errno| errno as u64);
+            u64::MAX
+        })
+    }
+}
+
+fn bad_handle() -> io::Error {
+    io::Error::from_raw_os_error(9) // EBADF
+}
+
+// SYS_OPENのモード(fopenのモード文字列の番号)からファイルを開く。
+fn open(name: &[u8], mode: u64) -> io::Result<HostFile> {
+    if name == CONSOLE_NAME {
+        return Ok(match mode {
+            0..=3 => HostFile::Stdin,
+            4..=7 => HostFile::Stdout,
+            _ => HostFile::Stderr,
+        });
+    }
+
+    let mut options = OpenOptions::new();
+    match mode {
+        0 | 1 => options.read(true),
+        2 | 3 => options.read(true).write(true),
+        4 | 5 => options.write(true).create(true).truncate(true),
+        6 | 7 => options.read(true).write(true).create(true).truncate(true),
+        8 | 9 => options.append(true).create(true),
+        10 | 11 => options.read(true).append(true).create(true),
+        _ => return Err(io::Error::from_raw_os_error(22)), // EINVAL
+    };
+
+    let path = String::from_utf8_lossy(name).into_owned();
+    Ok(HostFile::File(options.open(path)?))
+}
+
+impl Rv64SGEmulator {
+    // セミホスティングを有効にする関数
+    // cmdlineはSYS_GET_CMDLINEでゲストに渡す文字列
+    pub fn enable_semihosting(&mut self, cmdline: &str) {
+        self.semihosting = Some(Semihosting::new(cmdline));
+    }
+
+    // ebreakがslli x0, x0, 0x1fとsrai x0, x0, 7に挟まれているか判定する関数
+    // 前後の命令が読めない場合は通常のebreakとして扱う。
+    pub(super) fn is_semihosting_call(&mut self) -> bool {
+        if self.semihosting.is_none() {
+            return false;
+        }
+
+        let entry = self.read_memory(self.pc.wrapping_sub(4), 4, AccessType::Instruction);
+        let exit = self.read_memory(self.pc.wrapping_add(4), 4, AccessType::Instruction);
+        entry == Ok(SEMIHOSTING_ENTRY) && exit == Ok(SEMIHOSTING_EXIT)
+    }
+
+    fn read_semihosting_bytes(&mut self, address: u64, size: u64) -> Result<Vec<u8>, Trap> {
+        (0..size)
+            .map(|i| {
+                self.read_memory(address.wrapping_add(i), 1, AccessType::Load)
+                    .map(|byte| byte as u8)
+            })
+            .collect()
+    }
+
+    fn write_semihosting_bytes(&mut self, address: u64, data: &[u8]) -> Result<(), Trap> {
+        for (i, byte) in data.iter().enumerate() {
+            self.write_memory(address.wrapping_add(i as u64), 1, *byte as u64)?;
+        }
+
+        Ok(())
+    }
+
+    // パラメータブロックのn番目の値を読む。RV64では各値は8バイトになる。
+    fn semihosting_param(&mut self, block: u64, n: u64) -> Result<u64, Trap> {
+        self.read_memory(block.wrapping_add(8 * n), 8, AccessType::Load)
+    }
+
+    // セミホスティングの要求を処理する関数
+    // a0が操作の番号、a1が引数(パラメータブロックのアドレス)で、結果をa0に返す。
+    // ゲストのメモリにアクセスできない場合は例外になる。
+    pub(super) fn semihosting_call(&mut self) -> Result<(), Trap> {
+        let operation = self.registers[10];
+        let arg = self.registers[11];
+
+        let result = match operation {
+            SYS_OPEN => {
+                let name = self.semihosting_param(arg, 0)?;
+                let mode = self.semihosting_param(arg, 1)?;
+                let length = self.semihosting_param(arg, 2)?;
+                let name = self.read_semihosting_bytes(name, length)?;
+                let semihosting = self.semihosting.as_mut().unwrap();
+                let handle = open(&name, mode).map(|file| semihosting.allocate_handle(file));
+                semihosting.result(handle)
+            }
+            SYS_CLOSE => {
+                let handle = self.semihosting_param(arg, 0)?;
+                let semihosting = self.semihosting.as_mut().unwrap();
+                let closed = semihosting.files.remove(&handle).map(|_| 0);
+                semihosting.result(closed.ok_or_else(bad_handle))
+            }
+            SYS_WRITEC => {
+                let byte = self.read_semihosting_bytes(arg, 1)?;
+                let _ = io::stdout().write_all(&byte).and(io::stdout().flush());
+                0
+            }
+            SYS_WRITE0 => {
+                let mut string = Vec::new();
+                loop {
+                    let byte = self.read_memory(
+                        arg.wrapping_add(string.len() as u64),
+                        1,
+                        AccessType::Load,
+                    )?;
+                    if byte == 0 {
+                        break;
+                    }
+                    string.push(byte as u8);
+                }
+                let _ = io::stdout().write_all(&string).and(io::stdout().flush());
+                0
+            }
+            // 書き込めなかったバイト数を返す。
+            SYS_WRITE => {
+                let handle = self.semihosting_param(arg, 0)?;
+                let buf = self.semihosting_param(arg, 1)?;
+                let length = self.semihosting_param(arg, 2)?;
+                let data = self.read_semihosting_bytes(buf, length)?;
+                let semihosting = self.semihosting.as_mut().unwrap();
+                let written = match semihosting.files.get_mut(&handle) {
+                    Some(file) => file.write(&data).map(|written| written as u64),
+                    None => Err(bad_handle()),
+                };
+                match written {
+                    Ok(written) => length - written,
+                    Err(err) => {
+                        semihosting.result(Err(err));
+                        length
+                    }
+                }
+            }
+            // 読めなかったバイト数を返す。
+            // ゲストが指定した大きさをそのまま確保しないよう、READ_CHUNK_SIZEずつ読み込む。
+            SYS_READ => {
+                let handle = self.semihosting_param(arg, 0)?;
+                let buf = self.semihosting_param(arg, 1)?;
+                let length = self.semihosting_param(arg, 2)?;
+                let mut data = vec![0; length.min(READ_CHUNK_SIZE) as usize];
+                let mut total = 0;
+                while total < length {
+                    let size = (length - total).min(READ_CHUNK_SIZE) as usize;
+                    let semihosting = self.semihosting.as_mut().unwrap();
+                    let read = match semihosting.files.get_mut(&handle) {
+                        Some(file) => file.read(&mut data[..size]),
+                        None => Err(bad_handle()),
+                    };
+                    match read {
+                        Ok(read) => {
+                            self.write_semihosting_bytes(buf.wrapping_add(total), &data[..read])?;
+                            total += read as u64;
+                            if read < size {
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            semihosting.result(Err(err));
+                            break;
+                        }
+                    }
+                }
+                length - total
+            }
+            // 起動してからの時間を1/100秒単位で返す。
+            SYS_CLOCK => {
+                let semihosting = self.semihosting.as_ref().unwrap();
+                (semihosting.started.elapsed().as_millis() / 10) as u64
+            }
+            SYS_ERRNO => self.semihosting.as_ref().unwrap().errno,
+            // バッファに収まらない場合は-1を返す。
+            SYS_GET_CMDLINE => {
+                let buf = self.semihosting_param(arg, 0)?;
+                let length = self.semihosting_param(arg, 1)?;
+                let mut cmdline = self
+                    .semihosting
+                    .as_ref()
+                    .unwrap()
+                    .cmdline
+                    .as_bytes()
+                    .to_vec();
+                if cmdline.len() as u64 >= length {
+                    u64::MAX
+                } else {
+                    let size = cmdline.len() as u64;
+                    cmdline.push(0);
+                    self.write_semihosting_bytes(buf, &cmdline)?;
+                    self.write_memory(arg.wrapping_add(8), 8, size)?;
+                    0
+                }
+            }
+            // 正常終了の場合は副コードを終了コードとし、それ以外は理由を失敗として返す。
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                let reason = self.semihosting_param(arg, 0)?;
+                let subcode = self.semihosting_param(arg, 1)?;
+                self.exit_reason = Some(match (reason, subcode) {
+                    (ADP_STOPPED_APPLICATION_EXIT, 0) => ExitReason::Pass,
+                    (ADP_STOPPED_APPLICATION_EXIT, code) => ExitReason::Fail(code),
+                    (reason, _) => ExitReason::Fail(reason),
+                });
+                0
+            }
+            operation => {
+                eprintln!("semihosting: unsupported operation {:#x}", operation);
+                u64::MAX
+            }
+        };
+
+        self.registers[10] = result;
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use super::Rv64SGEmulator;
+
+impl Rv64SGEmulator {
+    // riscv-arch-testのbegin_signatureからend_signatureまでのメモリをRISCOFのシグネチャの形式で書き出す関数
+    // spikeの+signature-granularityと同じく、granularityバイトずつ上位のバイトから16進数で1行に表示し、
+    // 最後の行が足りない場合は上位を0で埋める。
+    pub fn write_signature(
+        &mut self,
+        output: &mut dyn Write,
+        granularity: usize,
+    ) -> io::Result<()> {
+        if granularity == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "signature: granularity must not be 0",
+            ));
+        }
+        let symbol = |name: &str| {
+            self.symbols.get(name).copied().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("signature: {} is not found", name),
+                )
+            })
+        };
+        let begin = symbol("begin_signature")?;
+        let end = symbol("end_signature")?;
+        if end < begin {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "signature: end_signature {:#x} is before begin_signature {:#x}",
+                    end, begin
+                ),
+            ));
+        }
+
+        let signature = self
+            .bus
+            .read_bytes(begin, (end - begin) as usize)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("signature: {:#x}-{:#x} is outside of memory", begin, end),
+                )
+            })?;
+        for line in signature.chunks(granularity) {
+            for i in (0..granularity).rev() {
+                write!(output, "{:02x}", line.get(i).copied().unwrap_or(0))?;
+            }
+            writeln!(output)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn dump_signature(&mut self, filename: &str, granularity: usize) -> io::Result<()> {
+        let mut output = BufWriter::new(File::create(filename)?);
+        self.write_signature(&mut output, granularity)?;
+        output.flush()
+    }
+}
@@ -0,0 +1,98 @@
+const TLB_SIZE: usize = 256;
+
+// TLBに入れるのは葉のPTEとその段数
+// スーパーページの場合もvpnはそのページの先頭の4KiBページの番号を持つ。
+#[derive(Clone, Copy)]
+pub struct TlbEntry {
+    asid: u64,
+    vpn: u64,
+    pub pte: u64,
+    pub level: u64,
+}
+
+impl TlbEntry {
+    fn contains(&self, vpn: u64) -> bool {
+        self.vpn >> (9 * self.level) == vpn >> (9 * self.level)
+    }
+
+    fn is_global(&self) -> bool {
+        self.pte & 0x20 != 0
+    }
+
+    // 仮想ページ番号に対応する物理アドレスのページの先頭
+    pub fn page_address(&self, vpn: u64) -> u64 {
+        let mask = (1u64 << (9 * self.level)) - 1;
+        ((((self.pte >> 10) & 0xfff_ffff_ffff) & !mask) | (vpn & mask)) << 12
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TlbStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl TlbStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+// ダイレクトマップ方式のTLB
+// ASIDとVPNが一致した場合のみヒットする。
+pub struct Tlb {
+    entries: Vec<Option<TlbEntry>>,
+    pub stats: TlbStats,
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Tlb {
+            entries: vec![None; TLB_SIZE],
+            stats: TlbStats::default(),
+        }
+    }
+
+    pub fn lookup(&mut self, asid: u64, vpn: u64) -> Option<TlbEntry> {
+        let entry = self.entries[vpn as usize % TLB_SIZE]
+            .filter(|entry| (entry.asid == asid || entry.is_global()) && entry.contains(vpn));
+
+        match entry {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+
+        entry
+    }
+
+    pub fn insert(&mut self, asid: u64, vpn: u64, pte: u64, level: u64) {
+        self.entries[vpn as usize % TLB_SIZE] = Some(TlbEntry {
+            asid,
+            vpn,
+            pte,
+            level,
+        });
+    }
+
+    // sfence.vmaの仕様に従って無効化する。
+    // vpnがNoneの場合は全てのアドレス、asidがNoneの場合は全てのASIDが対象になる。
+    // ASIDを指定した場合はグローバルなエントリは残す。
+    pub fn flush(&mut self, vpn: Option<u64>, asid: Option<u64>) {
+        for slot in self.entries.iter_mut() {
+            let flush = match slot {
+                Some(entry) => {
+                    vpn.is_none_or(|vpn| entry.contains(vpn))
+                        && asid.is_none_or(|asid| entry.asid == asid && !entry.is_global())
+                }
+                None => false,
+            };
+
+            if flush {
+                *slot = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use super::{
+    debug::CSR_NAMES,
+    disasm::{disassemble, expand_compressed, instruction_length},
+    helpers::extract_instruction_bits,
+    mmu::AccessType,
+    trap::{Exception, Trap},
+    Rv64SGEmulator, FCSR, FFLAGS,
+};
+
+// spikeのログのコア番号
+const HART_ID: u32 = 0;
+
+// 命令を実行した結果を記録するspikeの--log-commitsと同じ形式のトレース
+// 命令ごとに逆アセンブルした行と、書き込んだレジスタ・CSRとメモリアクセスの行を出力する。
+// outputがNoneの場合は直前の命令の結果を記録するだけにする。
+pub struct Trace {
+    output: Option<BufWriter<File>>,
+    // 実行中の命令が読み書きしたメモリ(仮想アドレス、サイズ、書き込んだ値)
+    loads: Vec<(u64, usize)>,
+    stores: Vec<(u64, usize, u64)>,
+    // 実行中の命令が書き込んだCSR
+    csr_writes: Vec<usize>,
+    // 最後に完了した命令
+    commit: Option<Commit>,
+}
+
+impl Trace {
+    pub(super) fn new(output: Option<BufWriter<File>>) -> Self {
+        Trace {
+            output,
+            loads: Vec::new(),
+            stores: Vec::new(),
+            csr_writes: Vec::new(),
+            commit: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Register {
+    X(usize),
+    F(usize),
+    Csr(usize),
+}
+
+impl Register {
+    // spikeと同じくレジスタ番号 << 4 | 種類(x: 0, f: 1, CSR: 4)の順に並べる。
+    fn key(&self) -> usize {
+        match *self {
+            Register::X(n) => n << 4,
+            Register::F(n) => n << 4 | 1,
+            Register::Csr(n) => n << 4 | 4,
+        }
+    }
+
+    // "x5"、"f10"、"c768_mstatus"のような名前から作る。
+    fn parse(label: &str) -> Option<Self> {
+        let number = |digits: &str| digits.parse().ok();
+        match label.split_at(1) {
+            ("x", n) => number(n).filter(|n| *n < 32).map(Register::X),
+            ("f", n) => number(n).filter(|n| *n < 32).map(Register::F),
+            ("c", n) => number(n.split('_').next()?).map(Register::Csr),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::X(n) => write!(f, "x{}", n),
+            Register::F(n) => write!(f, "f{}", n),
+            Register::Csr(n) => match CSR_NAMES.iter().find(|(_, number)| *number == n) {
+                Some((name, _)) => write!(f, "c{}_{}", n, name),
+                None => write!(f, "c{}", n),
+            },
+        }
+    }
+}
+
+// 完了した1命令の結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub mode: u64,
+    pub pc: u64,
+    pub instruction: u32,
+    // 書き込んだレジスタとその値(Register::keyの順)
+    pub writes: Vec<(Register, u64)>,
+    pub loads: Vec<u64>,
+    // 書き込んだアドレス、サイズ、値
+    pub stores: Vec<(u64, usize, u64)>,
+}
+
+fn parse_hex(word: &str) -> Option<u64> {
+    u64::from_str_radix(word.strip_prefix("0x")?, 16).ok()
+}
+
+impl Commit {
+    // spikeの--log-commitsの行を読む関数
+    // "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000 mem 0x... 0x..."の形式で、
+    // 特権モードの後にpc、命令、書き込んだレジスタと値が続き、memの後はアドレスとストアの場合は値が続く。
+    // 逆アセンブルや例外の行など、この形式でない行の場合はNoneを返す。
+    pub fn parse(line: &str) -> Option<Self> {
+        let (_, rest) = line.strip_prefix("core")?.split_once(':')?;
+        let mut words = rest.split_whitespace().peekable();
+        let mode = words.next()?.parse().ok().filter(|mode| *mode <= 3)?;
+        let pc = parse_hex(words.next()?)?;
+        let instruction = words.next()?.strip_prefix('(')?.strip_suffix(')')?;
+        let instruction = parse_hex(instruction)? as u32;
+
+        let mut commit = Commit {
+            mode,
+            pc,
+            instruction,
+            writes: Vec::new(),
+            loads: Vec::new(),
+            stores: Vec::new(),
+        };
+        while let Some(word) = words.next() {
+            if word == "mem" {
+                let address = parse_hex(words.next()?)?;
+                match words.next_if(|word| word.starts_with("0x")) {
+                    // 値の桁数がストアのサイズになる。
+                    Some(value) => {
+                        let size = (value.len() - 2) / 2;
+                        commit.stores.push((address, size, parse_hex(value)?));
+                    }
+                    None => commit.loads.push(address),
+                }
+            } else {
+                let register = Register::parse(word)?;
+                commit.writes.push((register, parse_hex(words.next()?)?));
+            }
+        }
+
+        Some(commit)
+    }
+}
+
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "core {:3}: {} {:#018x} (", HART_ID, self.mode, self.pc)?;
+        if instruction_length(self.instruction) == 2 {
+            write!(f, "{:#06x})", self.instruction)?;
+        } else {
+            write!(f, "{:#010x})", self.instruction)?;
+        }
+        for (register, value) in self.writes.iter() {
+            write!(f, " {:<3} {:#018x}", register.to_string(), value)?;
+        }
+        for address in self.loads.iter() {
+            write!(f, " mem {:#018x}", address)?;
+        }
+        for (address, size, value) in self.stores.iter() {
+            write!(
+                f,
+                " mem {:#018x} {:#0width$x}",
+                address,
+                value,
+                width = 2 + 2 * size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// rdに書き込む命令の場合はそのレジスタを返す。C拡張の命令は展開してから判定する。
+fn destination(instruction: u32) -> Option<Register> {
+    let instruction = if instruction_length(instruction) == 2 {
+        expand_compressed(instruction as u16)?
+    } else {
+        instruction
+    };
+    let rd = ((instruction >> 7) & 0x1f) as usize;
+
+    match instruction & 0x7f {
+        0x03 | 0x13 | 0x17 | 0x1b | 0x2f | 0x33 | 0x37 | 0x3b | 0x67 | 0x6f => {
+            Some(Register::X(rd))
+        }
+        0x73 if (instruction >> 12) & 0x7 != 0 => Some(Register::X(rd)),
+        0x07 | 0x43 | 0x47 | 0x4b | 0x4f => Some(Register::F(rd)),
+        // 比較、整数への変換、fmv.x.w/fclassは整数レジスタに書き込む。
+        0x53 => match instruction >> 27 {
+            0x14 | 0x18 | 0x1c => Some(Register::X(rd)),
+            _ => Some(Register::F(rd)),
+        },
+        _ => None,
+    }
+}
+
+// spikeと同じ名前
+fn trap_name(trap: &Trap) -> String {
+    let name = match trap {
+        Trap::Interrupt(interrupt) => return format!("interrupt #{}", interrupt.cause()),
+        Trap::Exception(Exception::InstructionAddressMisaligned(_)) => "misaligned_fetch",
+        Trap::Exception(Exception::InstructionAccessFault(_)) => "instruction_access_fault",
+        Trap::Exception(Exception::IllegalInstruction(_)) => "illegal_instruction",
+        Trap::Exception(Exception::Breakpoint(_)) => "breakpoint",
+        Trap::Exception(Exception::LoadAddressMisaligned(_)) => "load_address_misaligned",
+        Trap::Exception(Exception::LoadAccessFault(_)) => "load_access_fault",
+        Trap::Exception(Exception::StoreAddressMisaligned(_)) => "store_address_misaligned",
+        Trap::Exception(Exception::StoreAccessFault(_)) => "store_access_fault",
+        Trap::Exception(Exception::EnvironmentCallFromUMode) => "user_ecall",
+        Trap::Exception(Exception::EnvironmentCallFromSMode) => "supervisor_ecall",
+        Trap::Exception(Exception::EnvironmentCallFromMMode) => "machine_ecall",
+        Trap::Exception(Exception::InstructionPageFault(_)) => "instruction_page_fault",
+        Trap::Exception(Exception::LoadPageFault(_)) => "load_page_fault",
+        Trap::Exception(Exception::StorePageFault(_)) => "store_page_fault",
+    };
+    format!("trap_{}", name)
+}
+
+impl Rv64SGEmulator {
+    // pathにトレースを書き出すようにする。
+    pub fn enable_trace(&mut self, path: &str) -> io::Result<()> {
+        self.trace = Some(Trace::new(Some(BufWriter::new(File::create(path)?))));
+        Ok(())
+    }
+
+    pub(super) fn flush_trace(&mut self) {
+        if let Some(output) = self.trace.as_mut().and_then(|trace| trace.output.as_mut()) {
+            let _ = output.flush();
+        }
+    }
+
+    // ロード・ストアのたびに呼ばれる。命令フェッチは記録しない。
+    pub(super) fn trace_load(&mut self, vaddr: u64, size: usize, access: AccessType) {
+        if let Some(trace) = self.trace.as_mut() {
+            if access != AccessType::Instruction {
+                trace.loads.push((vaddr, size));
+            }
+        }
+    }
+
+    pub(super) fn trace_store(&mut self, vaddr: u64, size: usize, value: u64) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.stores.push((vaddr, size, value));
+        }
+    }
+
+    pub(super) fn trace_csr_write(&mut self, csr: usize) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.csr_writes.push(csr);
+        }
+    }
+
+    // 例外と割り込みをspikeと同じ形式で記録する。
+    pub(super) fn trace_trap(&mut self, trap: &Trap) {
+        let pc = self.pc;
+        if let Some(output) = self.trace.as_mut().and_then(|trace| trace.output.as_mut()) {
+            let _ = writeln!(
+                output,
+                "core {:3}: exception {}, epc {:#018x}",
+                HART_ID,
+                trap_name(trap),
+                pc
+            );
+            // ecall以外の例外はtvalも表示する。
+            if let Trap::Exception(exception) = trap {
+                if !matches!(
+                    exception,
+                    Exception::EnvironmentCallFromUMode
+                        | Exception::EnvironmentCallFromSMode
+                        | Exception::EnvironmentCallFromMMode
+                ) {
+                    let _ = writeln!(
+                        output,
+                        "core {:3}:           tval {:#018x}",
+                        HART_ID,
+                        exception.tval()
+                    );
+                }
+            }
+        }
+    }
+
+    // 命令を実行し、例外が起きずに完了した場合は書き込んだレジスタとメモリを記録する関数
+    pub(super) fn trace_and_exec(&mut self, instruction: Vec<u8>) -> Result<(), Trap> {
+        let bits = extract_instruction_bits(&instruction) as u32;
+        let pc = self.pc;
+        let mode = self.mode as u64;
+        let registers = self.registers;
+        let f_registers = self.f_registers;
+        let fflags = self.csrs[FCSR] & 0x1f;
+
+        let trace = self.trace.as_mut().unwrap();
+        trace.loads.clear();
+        trace.stores.clear();
+        trace.csr_writes.clear();
+        trace.commit = None;
+        if let Some(output) = trace.output.as_mut() {
+            let _ = writeln!(
+                output,
+                "core {:3}: {:#018x} ({:#010x}) {}",
+                HART_ID,
+                pc,
+                bits,
+                disassemble(bits, pc)
+            );
+        }
+
+        self.decode_and_exec(instruction)?;
+
+        let mut writes = Vec::new();
+        let destination = destination(bits);
+        // x0への書き込みは表示しない。
+        for (i, (before, after)) in registers.iter().zip(self.registers).enumerate().skip(1) {
+            if destination == Some(Register::X(i)) || *before != after {
+                writes.push((Register::X(i), after));
+            }
+        }
+        for (i, (before, after)) in f_registers.iter().zip(self.f_registers).enumerate() {
+            if destination == Some(Register::F(i)) || *before != after {
+                writes.push((Register::F(i), after));
+            }
+        }
+
+        // 浮動小数点命令が立てたフラグ
+        let mut csrs = std::mem::take(&mut self.trace.as_mut().unwrap().csr_writes);
+        if self.csrs[FCSR] & 0x1f != fflags {
+            csrs.push(FFLAGS);
+        }
+        csrs.sort();
+        csrs.dedup();
+        for csr in csrs {
+            let value = self.debug_read_csr(csr).unwrap_or_default();
+            writes.push((Register::Csr(csr), value));
+        }
+        writes.sort_by_key(|(register, _)| register.key());
+
+        let trace = self.trace.as_mut().unwrap();
+        let commit = Commit {
+            mode,
+            pc,
+            instruction: bits,
+            writes,
+            loads: trace.loads.iter().map(|(address, _)| *address).collect(),
+            stores: trace
+                .stores
+                .iter()
+                .map(|(address, size, value)| {
+                    (*address, *size, value & (u64::MAX >> (64 - 8 * size)))
+                })
+                .collect(),
+        };
+        if let Some(output) = trace.output.as_mut() {
+            let _ = writeln!(output, "{}", commit);
+        }
+        trace.commit = Some(commit);
+
+        Ok(())
+    }
+
+    // 直前に完了した命令の結果を取り出す。
+    pub(super) fn take_commit(&mut self) -> Option<Commit> {
+        self.trace.as_mut().and_then(|trace| trace.commit.take())
+    }
+}
@@ -0,0 +1,123 @@
+// 例外と割り込みの種類
+// 例外はmtval/stvalに書き込む値を持つ。
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u64),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+}
+
+impl Exception {
+    pub fn cause(&self) -> u64 {
+        match self {
+            Self::InstructionAddressMisaligned(_) => 0,
+            Self::InstructionAccessFault(_) => 1,
+            Self::IllegalInstruction(_) => 2,
+            Self::Breakpoint(_) => 3,
+            Self::LoadAddressMisaligned(_) => 4,
+            Self::LoadAccessFault(_) => 5,
+            Self::StoreAddressMisaligned(_) => 6,
+            Self::StoreAccessFault(_) => 7,
+            Self::EnvironmentCallFromUMode => 8,
+            Self::EnvironmentCallFromSMode => 9,
+            Self::EnvironmentCallFromMMode => 11,
+            Self::InstructionPageFault(_) => 12,
+            Self::LoadPageFault(_) => 13,
+            Self::StorePageFault(_) => 15,
+        }
+    }
+
+    pub fn tval(&self) -> u64 {
+        match *self {
+            Self::InstructionAddressMisaligned(tval)
+            | Self::InstructionAccessFault(tval)
+            | Self::IllegalInstruction(tval)
+            | Self::Breakpoint(tval)
+            | Self::LoadAddressMisaligned(tval)
+            | Self::LoadAccessFault(tval)
+            | Self::StoreAddressMisaligned(tval)
+            | Self::StoreAccessFault(tval)
+            | Self::InstructionPageFault(tval)
+            | Self::LoadPageFault(tval)
+            | Self::StorePageFault(tval) => tval,
+            Self::EnvironmentCallFromUMode
+            | Self::EnvironmentCallFromSMode
+            | Self::EnvironmentCallFromMMode => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+}
+
+impl Interrupt {
+    pub fn cause(&self) -> u64 {
+        match self {
+            Self::SupervisorSoftware => 1,
+            Self::MachineSoftware => 3,
+            Self::SupervisorTimer => 5,
+            Self::MachineTimer => 7,
+            Self::SupervisorExternal => 9,
+            Self::MachineExternal => 11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trap {
+    Exception(Exception),
+    Interrupt(Interrupt),
+}
+
+impl Trap {
+    // mcause/scauseに書き込む値
+    // 割り込みの場合は最上位ビットが立つ。
+    pub fn cause(&self) -> u64 {
+        match self {
+            Self::Exception(exception) => exception.cause(),
+            Self::Interrupt(interrupt) => (1 << 63) | interrupt.cause(),
+        }
+    }
+
+    pub fn tval(&self) -> u64 {
+        match self {
+            Self::Exception(exception) => exception.tval(),
+            Self::Interrupt(_) => 0,
+        }
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        matches!(self, Self::Interrupt(_))
+    }
+}
+
+impl From<Exception> for Trap {
+    fn from(exception: Exception) -> Self {
+        Self::Exception(exception)
+    }
+}
+
+impl From<Interrupt> for Trap {
+    fn from(interrupt: Interrupt) -> Self {
+        Self::Interrupt(interrupt)
+    }
+}
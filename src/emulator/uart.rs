@@ -0,0 +1,277 @@
+use std::{
+    any::Any,
+    collections::VecDeque,
+    fs::File,
+    io::{self, IsTerminal, Read, Write},
+    process::{Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use super::{
+    bus::{AccessFault, Device},
+    fdt::FdtWriter,
+};
+
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+pub const UART_IRQ: u32 = 10;
+
+const CLOCK_FREQUENCY: u32 = 3_686_400;
+
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDI: u8 = 0x1;
+const IER_THRI: u8 = 0x2;
+
+const IIR_NO_INTERRUPT: u8 = 0x1;
+const IIR_THRI: u8 = 0x2;
+const IIR_RDI: u8 = 0x4;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const LCR_DLAB: u8 = 0x80;
+
+const LSR_DR: u8 = 0x1;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+// ホストの端末をrawモードにし、Drop時に元の設定に戻す。
+// ISIGは残すのでCtrl-Cでエミュレータを終了できる。
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> Option<Self> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        let saved = String::from_utf8_lossy(&saved.stdout).trim().to_string();
+
+        Command::new("stty")
+            .args(["-icanon", "-echo", "-icrnl", "min", "1"])
+            .stdin(Stdio::inherit())
+            .status()
+            .ok()
+            .filter(|status| status.success())?;
+
+        Some(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = Command::new("stty")
+            .arg(&self.saved)
+            .stdin(Stdio::inherit())
+            .status();
+    }
+}
+
+// NS16550A互換のUART
+// 送信は書き込まれた時点で完了し、受信は別スレッドで読んだバイトをtickで取り込む。
+pub struct Uart {
+    input: Option<Receiver<u8>>,
+    output: Box<dyn Write>,
+    rx: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    fifo_enabled: bool,
+    // THRが空いたことによる割り込みはIIRを読むかTHRに書き込むまで保持される。
+    thre_pending: bool,
+    _raw_mode: Option<RawMode>,
+}
+
+impl Uart {
+    pub fn new(input: Option<Box<dyn Read + Send>>, output: Box<dyn Write>) -> Self {
+        let input = input.map(|mut input| {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let mut byte = [0];
+                while let Ok(1) = input.read(&mut byte) {
+                    if sender.send(byte[0]).is_err() {
+                        break;
+                    }
+                }
+            });
+            receiver
+        });
+
+        Uart {
+            input,
+            output,
+            rx: VecDeque::new(),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            fifo_enabled: false,
+            thre_pending: false,
+            _raw_mode: None,
+        }
+    }
+
+    // ホストの標準入出力につなぐ。
+    pub fn stdio() -> Self {
+        let mut uart = Uart::new(Some(Box::new(io::stdin())), Box::new(io::stdout()));
+        uart._raw_mode = RawMode::enable();
+        uart
+    }
+
+    // 出力をファイルやパイプに書き込む。入力は無い。
+    pub fn file(path: &str) -> io::Result<Self> {
+        Ok(Uart::new(None, Box::new(File::create(path)?)))
+    }
+
+    // SBIのコンソールから直接1バイト送信する。
+    pub fn transmit(&mut self, byte: u8) {
+        let _ = self.output.write_all(&[byte]);
+        let _ = self.output.flush();
+    }
+
+    // SBIのコンソールから受信済みのバイトを1つ取り出す。
+    pub fn take_received(&mut self) -> Option<u8> {
+        self.receive();
+        self.rx.pop_front()
+    }
+
+    fn receive(&mut self) {
+        if let Some(input) = &self.input {
+            self.rx.extend(input.try_iter());
+        }
+    }
+
+    fn iir(&self) -> u8 {
+        let id = if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thre_pending {
+            IIR_THRI
+        } else {
+            IIR_NO_INTERRUPT
+        };
+
+        if self.fifo_enabled {
+            id | IIR_FIFO_ENABLED
+        } else {
+            id
+        }
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, AccessFault> {
+        if size != 1 {
+            return Err(AccessFault);
+        }
+
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR_THR_DLL if dlab => self.dll,
+            RBR_THR_DLL => self.rx.pop_front().unwrap_or(0),
+            IER_DLM if dlab => self.dlm,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let iir = self.iir();
+                if iir & 0xf == IIR_THRI {
+                    self.thre_pending = false;
+                }
+                iir
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let dr = if self.rx.is_empty() { 0 } else { LSR_DR };
+                dr | LSR_THRE | LSR_TEMT
+            }
+            // DCD, DSR, CTSを立てておく。
+            MSR => 0xb0,
+            SCR => self.scr,
+            _ => 0,
+        };
+
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), AccessFault> {
+        if size != 1 {
+            return Err(AccessFault);
+        }
+
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll = value,
+            RBR_THR_DLL => {
+                // ホスト側に書き込めなくてもゲストには影響させない。
+                let _ = self.output.write_all(&[value]);
+                let _ = self.output.flush();
+                self.thre_pending = true;
+            }
+            IER_DLM if dlab => self.dlm = value,
+            IER_DLM => {
+                // THRは常に空いているので、有効にした時点で割り込みが起こる。
+                if value & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & 0xf;
+            }
+            IIR_FCR => {
+                self.fifo_enabled = value & 0x1 != 0;
+                if value & 0x2 != 0 {
+                    self.rx.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1f,
+            SCR => self.scr = value,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.receive();
+    }
+
+    fn irq(&self) -> bool {
+        self.iir() & IIR_NO_INTERRUPT == 0
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("serial")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_u32("clock-frequency", CLOCK_FREQUENCY);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+use super::{
+    Rv64SGEmulator, Uart, VirtioBlock, UART_BASE, UART_IRQ, UART_SIZE, VIRTIO_BASE, VIRTIO_IRQ,
+    VIRTIO_SIZE,
+};
+
+pub const VIRT_RAM_BASE: u64 = 0x8000_0000;
+
+// RAMの末尾をデバイスツリーのために空けておく。
+const DTB_MAX_SIZE: u64 = 0x1_0000;
+
+const IMAGE_HEADER_SIZE: usize = 64;
+const IMAGE_MAGIC: &[u8; 8] = b"RISCV\0\0\0";
+const IMAGE_MAGIC2: &[u8; 4] = b"RSC\x05";
+
+// RISC-VのLinuxカーネルのImageのヘッダ
+struct ImageHeader {
+    // RAMの先頭から読み込む位置までのオフセット
+    text_offset: u64,
+    // .bssを含めた大きさ
+    image_size: u64,
+}
+
+fn invalid_data(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("virt: {}", what))
+}
+
+impl ImageHeader {
+    fn parse(buf: &[u8]) -> io::Result<Self> {
+        let header = buf
+            .get(..IMAGE_HEADER_SIZE)
+            .ok_or_else(|| invalid_data("kernel image is truncated"))?;
+        if &header[48..56] != IMAGE_MAGIC && &header[56..60] != IMAGE_MAGIC2 {
+            return Err(invalid_data("kernel image has no RISC-V Image header"));
+        }
+
+        let read_u64 = |offset: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&header[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        Ok(ImageHeader {
+            text_offset: read_u64(8),
+            // 古いカーネルでは0になっている。
+            image_size: read_u64(16).max(buf.len() as u64),
+        })
+    }
+}
+
+fn read_file(filename: &str) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    File::open(filename)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+// virtボードの構成
+pub struct VirtConfig {
+    pub memsz: usize,
+    pub kernel: String,
+    pub initrd: Option<String>,
+    pub bootargs: String,
+    pub uart: Uart,
+    pub disk: Option<VirtioBlock>,
+}
+
+impl Rv64SGEmulator {
+    // QEMUのvirtボードに似た構成でLinuxカーネルを起動できる状態にする関数
+    // カーネルはRAM_BASE + text_offset、DTBはRAMの末尾、initrdはその直前に置く。
+    // 組み込みのSBIを有効にしてS-modeのカーネルの先頭から実行し、a0にhartid、a1にDTBのアドレスを渡す。
+    pub fn load_virt(config: VirtConfig) -> io::Result<Self> {
+        let kernel = read_file(&config.kernel)?;
+        let header = ImageHeader::parse(&kernel)?;
+        let out_of_range = || invalid_data("kernel image header is out of range");
+        let entry = VIRT_RAM_BASE
+            .checked_add(header.text_offset)
+            .ok_or_else(out_of_range)?;
+        let kernel_end = entry
+            .checked_add(header.image_size)
+            .ok_or_else(out_of_range)?;
+
+        // カーネルの末尾がDTBより前にあればRAMに収まっている。
+        let dtb_addr = VIRT_RAM_BASE
+            .checked_add(config.memsz as u64)
+            .and_then(|ram_end| ram_end.checked_sub(DTB_MAX_SIZE))
+            .filter(|&dtb_addr| dtb_addr >= kernel_end)
+            .ok_or_else(|| invalid_data("memory is too small for the kernel"))?;
+
+        let initrd = match &config.initrd {
+            Some(filename) => {
+                let data = read_file(filename)?;
+                let start = dtb_addr
+                    .checked_sub(data.len() as u64)
+                    .map(|start| start & !0xfff)
+                    .filter(|&start| start >= kernel_end)
+                    .ok_or_else(|| invalid_data("memory is too small for the initrd"))?;
+                Some((start, data))
+            }
+            None => None,
+        };
+
+        let mut rv64sg_emulator = Rv64SGEmulator::new(entry, 0, VIRT_RAM_BASE, config.memsz);
+        let write_error = |_| invalid_data("image is outside of memory");
+        rv64sg_emulator
+            .bus
+            .write_bytes(entry, &kernel)
+            .map_err(write_error)?;
+        if let Some((start, data)) = &initrd {
+            rv64sg_emulator
+                .bus
+                .write_bytes(*start, data)
+                .map_err(write_error)?;
+        }
+
+        rv64sg_emulator.add_device(UART_BASE, UART_SIZE, Some(UART_IRQ), Box::new(config.uart));
+        if let Some(disk) = config.disk {
+            rv64sg_emulator.add_device(VIRTIO_BASE, VIRTIO_SIZE, Some(VIRTIO_IRQ), Box::new(disk));
+        }
+
+        rv64sg_emulator.enable_sbi();
+        rv64sg_emulator.registers[10] = 0;
+        rv64sg_emulator.load_device_tree(
+            dtb_addr,
+            &config.bootargs,
+            initrd
+                .as_ref()
+                .map(|(start, data)| (*start, start + data.len() as u64)),
+        )?;
+
+        Ok(rv64sg_emulator)
+    }
+}
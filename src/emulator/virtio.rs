@@ -0,0 +1,464 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use super::{
+    bus::{AccessFault, BusView, Device},
+    fdt::FdtWriter,
+};
+
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_SIZE: u64 = 0x1000;
+pub const VIRTIO_IRQ: u32 = 1;
+
+const SECTOR_SIZE: u64 = 512;
+const QUEUE_NUM_MAX: u32 = 256;
+
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX_REG: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiskMode {
+    ReadWrite,
+    // ゲストには読み込み専用のデバイスとして見せる。
+    ReadOnly,
+    // 書き込みはメモリ上に保持し、イメージファイルは変更しない。
+    CopyOnWrite,
+}
+
+// ホストのディスクイメージ
+struct Disk {
+    file: File,
+    mode: DiskMode,
+    sectors: u64,
+    overlay: HashMap<u64, Vec<u8>>,
+}
+
+impl Disk {
+    fn open(path: &str, mode: DiskMode) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)?;
+        let sectors = file.metadata()?.len() / SECTOR_SIZE;
+
+        Ok(Disk {
+            file,
+            mode,
+            sectors,
+            overlay: HashMap::new(),
+        })
+    }
+
+    fn check_sector(&self, sector: u64) -> io::Result<()> {
+        if sector >= self.sectors {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("sector {} is out of range", sector),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn read_sector(&mut self, sector: u64) -> io::Result<Vec<u8>> {
+        self.check_sector(sector)?;
+        if let Some(data) = self.overlay.get(&sector) {
+            return Ok(data.clone());
+        }
+
+        let mut data = vec![0; SECTOR_SIZE as usize];
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn write_sector(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.check_sector(sector)?;
+        match self.mode {
+            DiskMode::ReadWrite => {
+                self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+                self.file.write_all(data)
+            }
+            DiskMode::CopyOnWrite => {
+                self.overlay.insert(sector, data.to_vec());
+                Ok(())
+            }
+            DiskMode::ReadOnly => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "disk is read-only",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadWrite => self.file.sync_data(),
+            _ => Ok(()),
+        }
+    }
+}
+
+// split virtqueue
+struct Queue {
+    num: u32,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    last_avail_idx: u16,
+}
+
+impl Queue {
+    fn new() -> Self {
+        Queue {
+            num: 0,
+            ready: false,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            last_avail_idx: 0,
+        }
+    }
+}
+
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+// virtio-mmio(version 2)のブロックデバイス
+// QueueNotifyに書き込まれた要求はdmaで処理し、完了したら割り込みを上げる。
+pub struct VirtioBlock {
+    disk: Disk,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue: Queue,
+    notified: bool,
+    interrupt_status: u32,
+    status: u32,
+}
+
+impl VirtioBlock {
+    pub fn new(path: &str, mode: DiskMode) -> io::Result<Self> {
+        Ok(VirtioBlock {
+            disk: Disk::open(path, mode)?,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue: Queue::new(),
+            notified: false,
+            interrupt_status: 0,
+            status: 0,
+        })
+    }
+
+    fn device_features(&self) -> u64 {
+        let features = VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH;
+        if self.disk.mode == DiskMode::ReadOnly {
+            features | VIRTIO_BLK_F_RO
+        } else {
+            features
+        }
+    }
+
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue = Queue::new();
+        self.notified = false;
+        self.interrupt_status = 0;
+        self.status = 0;
+    }
+
+    fn read_descriptor(&self, bus: &mut BusView, index: u16) -> Result<Descriptor, AccessFault> {
+        if index as u32 >= self.queue.num {
+            return Err(AccessFault);
+        }
+
+        let address = self.queue.desc + 16 * index as u64;
+        Ok(Descriptor {
+            addr: bus.read(address, 8)?,
+            len: bus.read(address + 8, 4)? as u32,
+            flags: bus.read(address + 12, 2)? as u16,
+            next: bus.read(address + 14, 2)? as u16,
+        })
+    }
+
+    // 1つの要求を処理し、デバイスが書き込んだバイト数を返す。
+    // 要求が壊れている場合はErrを返し、used ringには何も書かない。
+    fn process_request(&mut self, bus: &mut BusView, head: u16) -> Result<u32, AccessFault> {
+        let mut chain = Vec::new();
+        let mut index = head;
+        loop {
+            let descriptor = self.read_descriptor(bus, index)?;
+            let next = descriptor.next;
+            let has_next = descriptor.flags & VIRTQ_DESC_F_NEXT != 0;
+            chain.push(descriptor);
+            if !has_next {
+                break;
+            }
+            // 循環している場合に止まらなくなるのを防ぐ。
+            if chain.len() > self.queue.num as usize {
+                return Err(AccessFault);
+            }
+            index = next;
+        }
+
+        if chain.len() < 2 {
+            return Err(AccessFault);
+        }
+        let header = &chain[0];
+        let request_type = bus.read(header.addr, 4)? as u32;
+        let mut sector = bus.read(header.addr + 8, 8)?;
+        // ステータスはデバイスが書き込む最後のディスクリプタに返す。
+        let status = chain.last().unwrap();
+        if status.flags & VIRTQ_DESC_F_WRITE == 0 {
+            return Err(AccessFault);
+        }
+        let status_addr = status.addr;
+        let data = &chain[1..chain.len() - 1];
+
+        let mut written = 0;
+        let result = match request_type {
+            VIRTIO_BLK_T_IN => {
+                let mut result = Ok(());
+                for descriptor in data {
+                    if descriptor.flags & VIRTQ_DESC_F_WRITE == 0 {
+                        return Err(AccessFault);
+                    }
+                    let mut buf = Vec::new();
+                    for i in 0..(descriptor.len as u64).div_ceil(SECTOR_SIZE) {
+                        match self.disk.read_sector(sector + i) {
+                            Ok(data) => buf.extend(data),
+                            Err(err) => result = Err(err),
+                        }
+                    }
+                    buf.truncate(descriptor.len as usize);
+                    bus.write_bytes(descriptor.addr, &buf)?;
+                    written += descriptor.len;
+                    sector += (descriptor.len as u64).div_ceil(SECTOR_SIZE);
+                }
+                result.map(|_| VIRTIO_BLK_S_OK)
+            }
+            VIRTIO_BLK_T_OUT => {
+                let mut result = Ok(());
+                for descriptor in data {
+                    let buf = bus.read_bytes(descriptor.addr, descriptor.len as usize)?;
+                    for (i, chunk) in buf.chunks(SECTOR_SIZE as usize).enumerate() {
+                        let mut chunk = chunk.to_vec();
+                        chunk.resize(SECTOR_SIZE as usize, 0);
+                        if let Err(err) = self.disk.write_sector(sector + i as u64, &chunk) {
+                            result = Err(err);
+                        }
+                    }
+                    sector += (descriptor.len as u64).div_ceil(SECTOR_SIZE);
+                }
+                result.map(|_| VIRTIO_BLK_S_OK)
+            }
+            VIRTIO_BLK_T_FLUSH => self.disk.flush().map(|_| VIRTIO_BLK_S_OK),
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = b"udy-cream".to_vec();
+                if let Some(descriptor) = data.first() {
+                    id.resize(descriptor.len.min(20) as usize, 0);
+                    bus.write_bytes(descriptor.addr, &id)?;
+                    written += id.len() as u32;
+                }
+                Ok(VIRTIO_BLK_S_OK)
+            }
+            _ => Ok(VIRTIO_BLK_S_UNSUPP),
+        };
+
+        let status = result.unwrap_or(VIRTIO_BLK_S_IOERR);
+        bus.write(status_addr, 1, status as u64)?;
+        Ok(written + 1)
+    }
+
+    // avail ringに積まれている要求を全て処理する。
+    fn process_queue(&mut self, bus: &mut BusView) -> Result<(), AccessFault> {
+        let num = self.queue.num as u64;
+        let avail_idx = bus.read(self.queue.driver + 2, 2)? as u16;
+        while self.queue.last_avail_idx != avail_idx {
+            let ring = self.queue.driver + 4 + 2 * (self.queue.last_avail_idx as u64 % num);
+            let head = bus.read(ring, 2)? as u16;
+            let written = self.process_request(bus, head)?;
+
+            let used_idx = bus.read(self.queue.device + 2, 2)? as u16;
+            let elem = self.queue.device + 4 + 8 * (used_idx as u64 % num);
+            bus.write(elem, 4, head as u64)?;
+            bus.write(elem + 4, 4, written as u64)?;
+            bus.write(self.queue.device + 2, 2, used_idx.wrapping_add(1) as u64)?;
+
+            self.queue.last_avail_idx = self.queue.last_avail_idx.wrapping_add(1);
+            self.interrupt_status |= 1;
+        }
+
+        Ok(())
+    }
+
+    fn config(&self) -> [u8; 8] {
+        self.disk.sectors.to_le_bytes()
+    }
+}
+
+impl Device for VirtioBlock {
+    fn read(&mut self, offset: u64, size: usize) -> Result<u64, AccessFault> {
+        if offset >= CONFIG {
+            let config = self.config();
+            let start = (offset - CONFIG) as usize;
+            let mut value = [0; 8];
+            for (i, byte) in value.iter_mut().take(size).enumerate() {
+                *byte = config.get(start + i).copied().unwrap_or(0);
+            }
+            return Ok(u64::from_le_bytes(value));
+        }
+
+        if size != 4 {
+            return Err(AccessFault);
+        }
+
+        let value = match offset {
+            MAGIC_VALUE => 0x7472_6976,
+            VERSION => 2,
+            DEVICE_ID => 2,
+            VENDOR_ID => 0x554d_4551,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() & 0xffff_ffff,
+                1 => self.device_features() >> 32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX_REG => QUEUE_NUM_MAX as u64,
+            QUEUE_READY => self.queue.ready as u64,
+            INTERRUPT_STATUS => self.interrupt_status as u64,
+            STATUS => self.status as u64,
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Result<(), AccessFault> {
+        // コンフィグ空間は全て読み込み専用
+        if offset >= CONFIG {
+            return Ok(());
+        }
+        if size != 4 {
+            return Err(AccessFault);
+        }
+
+        let value = value as u32;
+        let low = |old: u64| (old & !0xffff_ffff) | value as u64;
+        let high = |old: u64| (old & 0xffff_ffff) | ((value as u64) << 32);
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = low(self.driver_features),
+                1 => self.driver_features = high(self.driver_features),
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            // ブロックデバイスのキューは0番のみ
+            QUEUE_SEL => {}
+            QUEUE_NUM => self.queue.num = value.min(QUEUE_NUM_MAX),
+            QUEUE_READY => self.queue.ready = value & 1 == 1,
+            QUEUE_NOTIFY => self.notified = value == 0,
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    self.status = value;
+                }
+            }
+            QUEUE_DESC_LOW => self.queue.desc = low(self.queue.desc),
+            QUEUE_DESC_HIGH => self.queue.desc = high(self.queue.desc),
+            QUEUE_DRIVER_LOW => self.queue.driver = low(self.queue.driver),
+            QUEUE_DRIVER_HIGH => self.queue.driver = high(self.queue.driver),
+            QUEUE_DEVICE_LOW => self.queue.device = low(self.queue.device),
+            QUEUE_DEVICE_HIGH => self.queue.device = high(self.queue.device),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn dma(&mut self, bus: &mut BusView) {
+        if !self.notified || !self.queue.ready || self.queue.num == 0 {
+            return;
+        }
+        self.notified = false;
+
+        // ゲストが不正なアドレスを渡した場合はDEVICE_NEEDS_RESETを立て、
+        // コンフィグ変更の割り込みで知らせる。
+        if self.process_queue(bus).is_err() {
+            self.status |= 0x40;
+            self.interrupt_status |= 2;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn fdt_name(&self) -> Option<&'static str> {
+        Some("virtio_mmio")
+    }
+
+    fn fdt_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "virtio,mmio");
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
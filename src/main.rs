@@ -1,14 +1,284 @@
-use emulator::Rv64SGEmulator;
+use std::{
+    env,
+    fs::File,
+    io::{self, Read, Write},
+    process,
+};
 
+use cli::{Command, ImageFormat, Machine, Options, USAGE};
+use emulator::{
+    fuzz, install_interrupt_handler, objdump, DiffOutcome, ExitReason, FuzzConfig, Rv64SGEmulator,
+    Uart, VirtConfig, VirtioBlock, UART_BASE, UART_IRQ, UART_SIZE, VIRTIO_BASE, VIRTIO_IRQ,
+    VIRTIO_SIZE,
+};
+
+mod cli;
+mod cli_tests;
 mod emulator;
 
-fn main() {
-    let mut rv64sg_emulator = Rv64SGEmulator::load_from_filename(
-        0,
-        4096,
-        1024 * 1024 * 4,
-        "rv64-tests/share/riscv-tests/isa/rv64uc-p-rvc.bin",
+const RAM_BASE: u64 = 0x8000_0000;
+const RAM_SIZE: usize = 1024 * 1024 * 4;
+const VIRT_RAM_SIZE: usize = 1024 * 1024 * 128;
+const USER_RAM_SIZE: usize = 1024 * 1024 * 256;
+const DTB_SIZE: u64 = 0x1_0000;
+const VIRT_BOOTARGS: &str = "console=ttyS0 earlycon=sbi";
+const FUZZ_PROGRAMS: u64 = 10000;
+const FUZZ_LENGTH: usize = 64;
+const FUZZ_STEPS: usize = 1000;
+const SIGNATURE_GRANULARITY: usize = 4;
+
+// 終了コード。ゲストが終了した場合はexit_codeで決める。
+const EXIT_FAILURE: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+const EXIT_INSTRUCTION_LIMIT: i32 = 124;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+// ゲストの終了理由を終了コードにする。
+// 失敗した場合はゲストの終了コードの下位8ビットにし、それが0になる場合は1にする。
+fn exit_code(reason: &ExitReason) -> i32 {
+    match reason {
+        ExitReason::Pass | ExitReason::Reboot => 0,
+        ExitReason::Fail(code) => match *code as u8 {
+            0 => EXIT_FAILURE,
+            code => code as i32,
+        },
+        ExitReason::InstructionLimit => EXIT_INSTRUCTION_LIMIT,
+    }
+}
+
+fn is_elf(filename: &str) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    let mut file = File::open(filename)?;
+    Ok(file.read_exact(&mut magic).is_ok() && magic == ELF_MAGIC)
+}
+
+fn uart(options: &Options) -> io::Result<Uart> {
+    match &options.uart {
+        Some(path) => Uart::file(path),
+        None => Ok(Uart::stdio()),
+    }
+}
+
+fn disk(options: &Options) -> io::Result<Option<VirtioBlock>> {
+    options
+        .disk
+        .as_ref()
+        .map(|path| VirtioBlock::new(path, options.disk_mode))
+        .transpose()
+}
+
+// QEMUのvirtボードに似た構成でLinuxカーネルのImageを起動する。
+fn load_virt(options: &Options) -> io::Result<Rv64SGEmulator> {
+    Rv64SGEmulator::load_virt(VirtConfig {
+        memsz: options.memory_size.unwrap_or(VIRT_RAM_SIZE),
+        kernel: options.image.clone(),
+        initrd: options.initrd.clone(),
+        bootargs: options
+            .bootargs
+            .clone()
+            .unwrap_or_else(|| VIRT_BOOTARGS.to_string()),
+        uart: uart(options)?,
+        disk: disk(options)?,
+    })
+}
+
+// Linuxの実行ファイルをイメージと残りの引数、ホストの環境変数とともにU-modeで実行する。
+fn load_user(options: &Options) -> io::Result<Rv64SGEmulator> {
+    let args: Vec<String> = [options.image.clone()]
+        .into_iter()
+        .chain(options.args.iter().cloned())
+        .collect();
+    let envs: Vec<String> = env::vars()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    Rv64SGEmulator::load_linux_user(
+        options.memory_size.unwrap_or(USER_RAM_SIZE),
+        &options.image,
+        &args,
+        &envs,
     )
-    .unwrap();
-    rv64sg_emulator.exec_program(0x4c);
+}
+
+// ELFか生のバイナリをRAMに読み込み、スタックはRAMの末尾に置く。
+// 生のバイナリはRAMの先頭に置いてそこから実行する。
+fn load_bare(options: &Options) -> io::Result<Rv64SGEmulator> {
+    let memsz = options.memory_size.unwrap_or(RAM_SIZE);
+    let ram_base = options.memory_base.unwrap_or(RAM_BASE);
+    let ram_end = ram_base.checked_add(memsz as u64).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--memory-base {:#x} with --memory-size {:#x} exceeds the address space",
+                ram_base, memsz
+            ),
+        )
+    })?;
+    let elf = match options.format {
+        ImageFormat::Auto => is_elf(&options.image)?,
+        ImageFormat::Elf => true,
+        ImageFormat::Binary => false,
+    };
+
+    let mut rv64sg_emulator = if elf {
+        Rv64SGEmulator::load_from_elf_file(ram_base, ram_end, memsz, &options.image)?
+    } else {
+        Rv64SGEmulator::load_from_filename(ram_base, ram_end, memsz, &options.image)?
+    };
+    for (filename, address) in options.loads.iter() {
+        rv64sg_emulator.load_image(*address, filename)?;
+    }
+    if let Some(tohost) = options.tohost {
+        rv64sg_emulator.set_htif(tohost, options.fromhost);
+    }
+
+    if options.sbi {
+        rv64sg_emulator.enable_sbi();
+    }
+    if options.pk {
+        rv64sg_emulator.enable_proxy_kernel();
+    }
+    if options.semihosting {
+        let cmdline: Vec<&str> = [options.image.as_str()]
+            .into_iter()
+            .chain(options.args.iter().map(|arg| arg.as_str()))
+            .collect();
+        rv64sg_emulator.enable_semihosting(&cmdline.join(" "));
+    }
+
+    rv64sg_emulator.add_device(
+        UART_BASE,
+        UART_SIZE,
+        Some(UART_IRQ),
+        Box::new(uart(options)?),
+    );
+    if let Some(disk) = disk(options)? {
+        rv64sg_emulator.add_device(VIRTIO_BASE, VIRTIO_SIZE, Some(VIRTIO_IRQ), Box::new(disk));
+    }
+
+    if options.dtb {
+        let bootargs = options.bootargs.as_deref().unwrap_or_default();
+        let address = ram_end
+            .checked_sub(DTB_SIZE)
+            .filter(|&address| address >= ram_base)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "memory is too small for the device tree",
+                )
+            })?;
+        rv64sg_emulator.load_device_tree(address, bootargs, None)?;
+    }
+
+    Ok(rv64sg_emulator)
+}
+
+// 仮想記憶を使った場合のみTLBのヒット率を表示する。
+fn print_tlb_stats(rv64sg_emulator: &Rv64SGEmulator) {
+    let (fetch, data) = rv64sg_emulator.tlb_stats();
+    for (name, stats) in [("fetch", fetch), ("data", data)] {
+        if stats.hits + stats.misses != 0 {
+            eprintln!(
+                "{} tlb: {} hits, {} misses ({:.2}%)",
+                name,
+                stats.hits,
+                stats.misses,
+                stats.hit_rate() * 100.0
+            );
+        }
+    }
+}
+
+// オプションに従ってゲストを実行し、終了コードを返す。
+// UARTが端末の設定を戻せるように、エミュレータはここで破棄してからプロセスを終了する。
+fn run(options: Options) -> io::Result<i32> {
+    let mut rv64sg_emulator = match options.machine {
+        Machine::Bare => load_bare(&options)?,
+        Machine::Virt => load_virt(&options)?,
+        Machine::User => load_user(&options)?,
+    };
+    if let Some(entry) = options.entry {
+        rv64sg_emulator.set_entry(entry);
+    }
+    if let Some(limit) = options.max_instructions {
+        rv64sg_emulator.set_instruction_limit(limit);
+    }
+    if let Some(path) = &options.dump_dtb {
+        rv64sg_emulator.dump_device_tree(path)?;
+    }
+    if let Some(path) = &options.trace {
+        rv64sg_emulator.enable_trace(path)?;
+    }
+
+    // 参照トレースと乖離した場合は終了コードを1にする。
+    if let Some(path) = &options.reference {
+        let mut reference = io::BufReader::new(File::open(path)?);
+        let outcome = rv64sg_emulator.exec_with_reference(&mut reference, &mut io::stdout())?;
+        println!("{:?}", outcome);
+        return Ok(match outcome {
+            DiffOutcome::Matched(_) => 0,
+            DiffOutcome::Exited(reason, _) => exit_code(&reason),
+            DiffOutcome::Diverged(_) => EXIT_FAILURE,
+        });
+    }
+
+    // モニタではCtrl-Cで実行中のゲストを止める。
+    let exit_status = match &options.gdb {
+        Some(address) => rv64sg_emulator.exec_with_gdb(address)?,
+        None if options.monitor => {
+            install_interrupt_handler();
+            rv64sg_emulator.exec_with_monitor(&mut io::stdin().lock(), &mut io::stdout())?
+        }
+        None => rv64sg_emulator.exec_program(),
+    };
+    // userではゲストの出力だけを表示する。
+    if options.machine != Machine::User {
+        io::stdout().write_all(&exit_status.console)?;
+        println!("{:?}", exit_status.reason);
+    }
+
+    if let Some(path) = &options.signature {
+        let granularity = options
+            .signature_granularity
+            .unwrap_or(SIGNATURE_GRANULARITY);
+        rv64sg_emulator.dump_signature(path, granularity)?;
+    }
+    print_tlb_stats(&rv64sg_emulator);
+
+    Ok(exit_code(&exit_status.reason))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match cli::parse_args(&args) {
+        Ok(Command::Help) => {
+            print!("{}", USAGE);
+            Ok(0)
+        }
+        Ok(Command::Objdump(filename)) => objdump(&filename, &mut io::stdout().lock()).map(|_| 0),
+        // 失敗したプログラムが見つかった場合は終了コードを1にする。
+        Ok(Command::Fuzz { seed, programs }) => {
+            let config = FuzzConfig {
+                seed,
+                programs: programs.unwrap_or(FUZZ_PROGRAMS),
+                length: FUZZ_LENGTH,
+                steps: FUZZ_STEPS,
+            };
+            fuzz(&config, &mut io::stdout().lock())
+                .map(|failure| failure.map_or(0, |_| EXIT_FAILURE))
+        }
+        Ok(Command::Run(options)) => run(*options),
+        Err(message) => {
+            eprintln!("udy-cream: {}", message);
+            eprintln!("Try 'udy-cream --help' for more information.");
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    let code = result.unwrap_or_else(|err| {
+        eprintln!("udy-cream: {}", err);
+        EXIT_ERROR
+    });
+    io::stdout().flush().unwrap();
+    process::exit(code);
 }